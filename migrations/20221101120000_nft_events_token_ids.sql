@@ -0,0 +1,6 @@
+-- NFT events are still stored as one row per token.
+-- token_ids keeps all the tokens mentioned in the same event entry, so one mint of N tokens
+-- produces N rows, and each of them has the same token_ids array.
+-- Rows collected before this migration have NULL here.
+ALTER TABLE nft_events
+    ADD COLUMN token_ids text[];
@@ -0,0 +1,7 @@
+-- The block is added here only after all its events are stored.
+-- Used to find the gaps in the indexed history
+CREATE TABLE processed_blocks
+(
+    block_height    numeric(20, 0) PRIMARY KEY,
+    block_timestamp numeric(20, 0) NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Original EVENT_JSON log, filled only if the indexer runs with --store-raw-events.
+-- Always NULL for legacy events, they are not based on the event logs
+ALTER TABLE coin_events
+    ADD COLUMN raw_event text;
+
+ALTER TABLE nft_events
+    ADD COLUMN raw_event text;
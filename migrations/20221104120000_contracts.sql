@@ -0,0 +1,11 @@
+-- The registry of the contracts which produced the events.
+-- Could be rebuilt from coin_events/nft_events with `rebuild-contracts` command
+CREATE TABLE IF NOT EXISTS contracts
+(
+    contract_account_id                 text           PRIMARY KEY,
+    standard                            text           NOT NULL,
+    first_event_at_timestamp            numeric(20, 0) NOT NULL,
+    first_event_at_block_height         numeric(20, 0) NOT NULL,
+    inconsistency_found_at_timestamp    numeric(20, 0),
+    inconsistency_found_at_block_height numeric(20, 0)
+);
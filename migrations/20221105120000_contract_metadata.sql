@@ -0,0 +1,11 @@
+-- NEP-148 metadata of FT contracts, fetched once when the contract produces the first event.
+-- All the metadata columns are NULL if the contract does not implement `ft_metadata`
+CREATE TABLE contract_metadata
+(
+    contract_account_id text           PRIMARY KEY,
+    spec                text,
+    name                text,
+    symbol              text,
+    decimals            integer,
+    fetched_at_block_height numeric(20, 0) NOT NULL
+);
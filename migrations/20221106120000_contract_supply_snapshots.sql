@@ -0,0 +1,11 @@
+-- Net supply (all successful mints minus burns) of the contract at the end of the block.
+-- The row is added only for the blocks where the supply changed.
+-- Could be rebuilt from coin_events with `rebuild-supply-snapshots` command
+CREATE TABLE contract_supply_snapshots
+(
+    contract_account_id text           NOT NULL,
+    block_height        numeric(20, 0) NOT NULL,
+    block_timestamp     numeric(20, 0) NOT NULL,
+    supply              numeric(40, 0) NOT NULL,
+    PRIMARY KEY (contract_account_id, block_height)
+);
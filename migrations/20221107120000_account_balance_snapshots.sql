@@ -0,0 +1,11 @@
+-- Balance (sum of all successful deltas) of the account in the contract at the end of the block.
+-- The row is added only for the blocks where the balance changed
+CREATE TABLE account_balance_snapshots
+(
+    contract_account_id text           NOT NULL,
+    account_id          text           NOT NULL,
+    block_height        numeric(20, 0) NOT NULL,
+    block_timestamp     numeric(20, 0) NOT NULL,
+    balance             numeric(40, 0) NOT NULL,
+    PRIMARY KEY (contract_account_id, account_id, block_height)
+);
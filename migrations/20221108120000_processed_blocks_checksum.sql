@@ -0,0 +1,4 @@
+-- SHA-256 of the event rows of the block, filled only with --store-block-checksums.
+-- The same value for the same block means two deployments have written the same rows
+ALTER TABLE processed_blocks
+    ADD COLUMN block_checksum text;
@@ -0,0 +1,4 @@
+-- How the events of the contract are found: `events` for EVENT_JSON logs,
+-- `legacy_<family>` for the method parsing of the legacy contracts (e.g. `legacy_tkn_near`)
+ALTER TABLE contracts
+    ADD COLUMN detection_method text;
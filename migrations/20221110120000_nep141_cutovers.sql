@@ -0,0 +1,8 @@
+-- The block where the legacy tkn.near token emitted the first NEP-141 event.
+-- Since this block the token is handled only by the NEP-141 collector, the earlier blocks stay legacy ones
+CREATE TABLE nep141_cutovers
+(
+    contract_account_id text           PRIMARY KEY,
+    block_height        numeric(20, 0) NOT NULL,
+    block_timestamp     numeric(20, 0) NOT NULL
+);
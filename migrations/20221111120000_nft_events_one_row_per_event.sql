@@ -0,0 +1,5 @@
+-- NFT events are stored as one row per event entry, token_ids keeps all the tokens of the entry.
+-- token_id is filled only if the entry mentions exactly one token.
+-- Rows collected before this migration stay one row per token
+ALTER TABLE nft_events
+    ALTER COLUMN token_id DROP NOT NULL;
@@ -0,0 +1,12 @@
+-- Back to one row per token id, token_id is always set again. token_ids keeps all the tokens of the event entry.
+-- The multi-token rows written as one row per event entry can't be split here: their event indexes
+-- are already taken by the next entries of the same shard. Such rows are deleted together with the
+-- processed_blocks marks of their blocks, run `reindex` over these blocks to restore them.
+DELETE FROM processed_blocks
+WHERE block_height IN (SELECT block_height FROM nft_events WHERE token_id IS NULL);
+
+DELETE FROM nft_events
+WHERE token_id IS NULL;
+
+ALTER TABLE nft_events
+    ALTER COLUMN token_id SET NOT NULL;
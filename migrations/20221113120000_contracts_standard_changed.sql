@@ -0,0 +1,6 @@
+-- The block where the contract started to produce the events of another token kind (e.g. FT after NFT).
+-- The change of the standard is not a balance inconsistency, so it has its own columns.
+-- The old marks stay in `inconsistency_found_at_*`, `rebuild-contracts` fills the new columns
+ALTER TABLE contracts
+    ADD COLUMN standard_changed_at_timestamp    numeric(20, 0),
+    ADD COLUMN standard_changed_at_block_height numeric(20, 0);
@@ -0,0 +1,444 @@
+use crate::models::coin_events::CoinEvent;
+use crate::settings::IndexerSettings;
+use bigdecimal::BigDecimal;
+use futures::try_join;
+use near_lake_framework::near_indexer_primitives;
+use near_primitives::types::AccountId;
+use num_traits::Zero;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// Adds the snapshot for each account which balance changed in the block.
+// The new balance is based on the previous snapshot, so the blocks should go in order
+pub(crate) async fn store_balance_snapshots(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    settings: &IndexerSettings,
+    events: &[CoinEvent],
+    block_header: &near_indexer_primitives::views::BlockHeaderView,
+) -> anyhow::Result<()> {
+    if !settings.store_balance_snapshots {
+        return Ok(());
+    }
+    let mut balance_deltas: HashMap<(&str, &str), BigDecimal> = HashMap::new();
+    for event in events {
+        if event.status == "SUCCESS" {
+            *balance_deltas
+                .entry((
+                    event.contract_account_id.as_str(),
+                    event.affected_account_id.as_str(),
+                ))
+                .or_insert_with(BigDecimal::zero) += &event.delta_amount;
+        }
+    }
+    if balance_deltas.is_empty() {
+        return Ok(());
+    }
+
+    let mut contract_ids = Vec::with_capacity(balance_deltas.len());
+    let mut account_ids = Vec::with_capacity(balance_deltas.len());
+    let mut deltas = Vec::with_capacity(balance_deltas.len());
+    for ((contract_id, account_id), delta) in balance_deltas {
+        contract_ids.push(contract_id.to_string());
+        account_ids.push(account_id.to_string());
+        deltas.push(delta);
+    }
+    // ON CONFLICT keeps the block idempotent if it's processed again
+    sqlx::query(
+        "INSERT INTO account_balance_snapshots
+         SELECT changes.contract_account_id,
+                changes.account_id,
+                $1,
+                $2,
+                COALESCE((SELECT balance
+                          FROM account_balance_snapshots
+                          WHERE contract_account_id = changes.contract_account_id
+                            AND account_id = changes.account_id
+                            AND block_height < $1
+                          ORDER BY block_height DESC
+                          LIMIT 1), 0) + changes.delta
+         FROM UNNEST($3::text[], $4::text[], $5::numeric[]) AS changes(contract_account_id, account_id, delta)
+         ON CONFLICT (contract_account_id, account_id, block_height) DO NOTHING",
+    )
+    .bind(BigDecimal::from(block_header.height))
+    .bind(BigDecimal::from(block_header.timestamp))
+    .bind(contract_ids)
+    .bind(account_ids)
+    .bind(deltas)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Recalculates the balance snapshots since the given block (all of them for 0) from the stored events
+pub async fn rebuild_balance_snapshots(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    from_block_height: u64,
+) -> anyhow::Result<u64> {
+    // The balance is the sum of all the previous deltas, so the earlier events are read anyway
+    let result = sqlx::query(
+        "INSERT INTO account_balance_snapshots
+         SELECT * FROM (SELECT contract_account_id,
+                               affected_account_id,
+                               block_height,
+                               block_timestamp,
+                               SUM(delta) OVER (PARTITION BY contract_account_id, affected_account_id
+                                                ORDER BY block_height)
+                        FROM (SELECT contract_account_id,
+                                     affected_account_id,
+                                     block_height,
+                                     MAX(block_timestamp) AS block_timestamp,
+                                     SUM(delta_amount)    AS delta
+                              FROM coin_events
+                              WHERE status = 'SUCCESS'
+                              GROUP BY contract_account_id, affected_account_id, block_height) AS balance_deltas
+                       ) AS snapshots
+         WHERE block_height >= $1
+         ON CONFLICT (contract_account_id, account_id, block_height) DO UPDATE SET balance = excluded.balance",
+    )
+    .bind(BigDecimal::from(from_block_height))
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Sums up all the successful deltas of the account in the given contract up to the block (inclusive)
+pub async fn get_indexed_balance(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    contract_id: &AccountId,
+    account_id: &AccountId,
+    block_height: u64,
+) -> anyhow::Result<BigDecimal> {
+    let (balance,): (BigDecimal,) = sqlx::query_as(
+        "SELECT COALESCE(SUM(delta_amount), 0)
+         FROM coin_events
+         WHERE contract_account_id = $1
+           AND affected_account_id = $2
+           AND block_height <= $3
+           AND status = 'SUCCESS'",
+    )
+    .bind(contract_id.to_string())
+    .bind(account_id.to_string())
+    .bind(BigDecimal::from(block_height))
+    .fetch_one(pool)
+    .await?;
+    Ok(balance)
+}
+
+/// Balances of the account in all the contracts it ever had events in, up to the block (inclusive).
+/// The contracts where the balance is zero now are also included
+pub async fn account_portfolio(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    account_id: &AccountId,
+    block_height: u64,
+) -> anyhow::Result<HashMap<AccountId, BigDecimal>> {
+    let balances: Vec<(String, BigDecimal)> = sqlx::query_as(
+        "SELECT contract_account_id, COALESCE(SUM(delta_amount), 0)
+         FROM coin_events
+         WHERE affected_account_id = $1
+           AND block_height <= $2
+           AND status = 'SUCCESS'
+         GROUP BY contract_account_id",
+    )
+    .bind(account_id.to_string())
+    .bind(BigDecimal::from(block_height))
+    .fetch_all(pool)
+    .await?;
+    balances
+        .into_iter()
+        .map(|(contract_id, balance)| Ok((AccountId::from_str(&contract_id)?, balance)))
+        .collect()
+}
+
+/// Compares the indexed balances of the random accounts with `ft_balance_of` at the last processed block.
+/// The accounts are picked from `sample_percent` percent of `coin_events` pages, at most `sample_size` of them.
+/// The contracts with the diverged balances are marked as inconsistent.
+/// Returns the number of the found mismatches
+pub async fn verify_sampled_balances(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    rpc_client: &near_jsonrpc_client::JsonRpcClient,
+    sample_percent: f64,
+    sample_size: i64,
+) -> anyhow::Result<usize> {
+    let processed_range: (Option<BigDecimal>, Option<BigDecimal>) =
+        sqlx::query_as("SELECT MIN(block_height), MAX(block_height) FROM processed_blocks")
+            .fetch_one(pool)
+            .await?;
+    let (first_block_height, last_block_height) = match processed_range {
+        (Some(first), Some(last)) => (u64::from_str(&first.to_string())?, last),
+        _ => return Ok(0),
+    };
+    let (block_timestamp,): (BigDecimal,) =
+        sqlx::query_as("SELECT block_timestamp FROM processed_blocks WHERE block_height = $1")
+            .bind(&last_block_height)
+            .fetch_one(pool)
+            .await?;
+    let block_height_u64 = u64::from_str(&last_block_height.to_string())?;
+
+    // TABLESAMPLE reads only a part of the table, ORDER BY random() would scan all of it
+    let sample: Vec<(String, String)> = sqlx::query_as(
+        "SELECT DISTINCT contract_account_id, affected_account_id
+         FROM coin_events TABLESAMPLE SYSTEM ($1)
+         WHERE block_height <= $2
+         LIMIT $3",
+    )
+    .bind(sample_percent)
+    .bind(&last_block_height)
+    .bind(sample_size)
+    .fetch_all(pool)
+    .await?;
+
+    let mut mismatches = 0;
+    for (contract_id, account_id) in sample {
+        let contract_id = AccountId::from_str(&contract_id)?;
+        let account_id = AccountId::from_str(&account_id)?;
+        let chain_balances = try_join!(
+            crate::rpc_helpers::balance_at_block_boundary(
+                rpc_client,
+                &contract_id,
+                &account_id,
+                first_block_height,
+            ),
+            crate::rpc_helpers::balance_at_block_boundary(
+                rpc_client,
+                &contract_id,
+                &account_id,
+                block_height_u64,
+            )
+        );
+        let (chain_balance_at_start, chain_balance) = match chain_balances {
+            Ok(chain_balances) => chain_balances,
+            Err(err) => {
+                // The contract could be deleted or not implement `ft_balance_of`, it's not our inconsistency
+                tracing::warn!(
+                    target: crate::LOGGING_PREFIX,
+                    contract_id = %contract_id,
+                    account_id = %account_id,
+                    error = %err,
+                    "Failed to verify the balance",
+                );
+                continue;
+            }
+        };
+        // Only the history since the first processed block is complete: the indexer could start
+        // in the middle of the chain, or the earlier blocks could be indexed only for some contracts.
+        // The account which had the tokens before it can't be verified by the sum of its deltas
+        let indexed_before_start = match first_block_height.checked_sub(1) {
+            Some(block_height) => {
+                get_indexed_balance(pool, &contract_id, &account_id, block_height).await?
+            }
+            None => BigDecimal::zero(),
+        };
+        let indexed_at_start =
+            get_indexed_balance(pool, &contract_id, &account_id, first_block_height).await?;
+        let balance_before_start =
+            chain_balance_at_start - (&indexed_at_start - &indexed_before_start);
+        if !balance_before_start.is_zero() {
+            tracing::debug!(
+                target: crate::LOGGING_PREFIX,
+                contract_id = %contract_id,
+                account_id = %account_id,
+                first_block_height,
+                %balance_before_start,
+                "Skipping the balance verification, the history of the account is not fully indexed",
+            );
+            continue;
+        }
+        let indexed_balance =
+            get_indexed_balance(pool, &contract_id, &account_id, block_height_u64).await?
+                - indexed_before_start;
+        if chain_balance != indexed_balance {
+            mismatches += 1;
+            crate::metrics::BALANCE_MISMATCHES_TOTAL.inc();
+            tracing::warn!(
+                target: crate::LOGGING_PREFIX,
+                contract_id = %contract_id,
+                account_id = %account_id,
+                block_height = block_height_u64,
+                %indexed_balance,
+                %chain_balance,
+                "Indexed balance diverged from the chain",
+            );
+            crate::db_adapters::contracts::mark_inconsistent(
+                pool,
+                contract_id.as_str(),
+                &last_block_height,
+                &block_timestamp,
+            )
+            .await?;
+        }
+    }
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn diverged_balance_marks_contract_inconsistent() {
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        for block_height in 845_001..=845_005 {
+            crate::db_adapters::processed_blocks::store_processed_block(
+                &pool,
+                &test_utils::block_header(block_height),
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        let events = [
+            test_utils::coin_event(1, 845_002, "token.near", "alice.near", 100),
+            // Deliberately wrong, the chain says 70
+            test_utils::coin_event(2, 845_003, "token.near", "bob.near", 50),
+            // The account had 30 tokens before the first processed block, its history is not complete
+            test_utils::coin_event(3, 845_003, "partial.near", "carol.near", 10),
+        ];
+        crate::models::chunked_insert(&pool, &events).await.unwrap();
+        let rpc_url = test_utils::mock_ft_balances_rpc(
+            [
+                (("token.near", "alice.near", 845_005), 100),
+                (("token.near", "bob.near", 845_005), 70),
+                (("partial.near", "carol.near", 845_001), 30),
+                (("partial.near", "carol.near", 845_005), 40),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let rpc_client = near_jsonrpc_client::JsonRpcClient::connect(&rpc_url);
+
+        let mismatches = verify_sampled_balances(&pool, &rpc_client, 100.0, 100)
+            .await
+            .unwrap();
+
+        assert_eq!(mismatches, 1);
+        let marks: Vec<(String, Option<BigDecimal>)> = sqlx::query_as(
+            "SELECT contract_account_id, inconsistency_found_at_block_height FROM contracts",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            marks,
+            vec![("token.near".to_string(), Some(BigDecimal::from(845_005)))]
+        );
+    }
+
+    #[tokio::test]
+    async fn balance_snapshots_match_the_sum_of_deltas() {
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let mut failed_transfer = test_utils::coin_event(4, 842_002, "token.near", "bob.near", 7);
+        failed_transfer.status = "FAILURE".to_string();
+        let blocks = [
+            (
+                842_001,
+                vec![
+                    test_utils::coin_event(1, 842_001, "token.near", "alice.near", 100),
+                    test_utils::coin_event(2, 842_001, "token.near", "alice.near", -10),
+                    test_utils::coin_event(3, 842_001, "token.near", "bob.near", 10),
+                ],
+            ),
+            (842_002, vec![failed_transfer]),
+            (
+                842_003,
+                vec![test_utils::coin_event(
+                    5,
+                    842_003,
+                    "token.near",
+                    "alice.near",
+                    -40,
+                )],
+            ),
+        ];
+
+        for (block_height, events) in &blocks {
+            crate::models::chunked_insert(&pool, events).await.unwrap();
+            let block_header = test_utils::block_header(*block_height);
+            // Without the flag nothing is written
+            store_balance_snapshots(&pool, &IndexerSettings::default(), events, &block_header)
+                .await
+                .unwrap();
+            assert_eq!(
+                test_utils::count_rows(&pool, "account_balance_snapshots").await,
+                0
+            );
+        }
+        let settings = IndexerSettings {
+            store_balance_snapshots: true,
+            ..IndexerSettings::default()
+        };
+        for (block_height, events) in &blocks {
+            let block_header = test_utils::block_header(*block_height);
+            store_balance_snapshots(&pool, &settings, events, &block_header)
+                .await
+                .unwrap();
+        }
+
+        let snapshots: Vec<(String, BigDecimal, BigDecimal)> = sqlx::query_as(
+            "SELECT account_id, block_height, balance FROM account_balance_snapshots
+             WHERE contract_account_id = 'token.near' ORDER BY account_id, block_height",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        // The failed transfer doesn't change the balance, so there's no snapshot for it
+        assert_eq!(
+            snapshots,
+            vec![
+                ("alice.near".to_string(), 842_001.into(), 90.into()),
+                ("alice.near".to_string(), 842_003.into(), 50.into()),
+                ("bob.near".to_string(), 842_001.into(), 10.into()),
+            ]
+        );
+        let contract_id = AccountId::from_str("token.near").unwrap();
+        for (account_id, block_height, balance) in snapshots {
+            let indexed_balance = get_indexed_balance(
+                &pool,
+                &contract_id,
+                &AccountId::from_str(&account_id).unwrap(),
+                block_height.to_string().parse().unwrap(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(indexed_balance, balance);
+        }
+    }
+
+    #[tokio::test]
+    async fn portfolio_has_balances_in_all_contracts() {
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let mut failed_mint = test_utils::coin_event(5, 853_002, "usdt.near", "alice.near", 7);
+        failed_mint.status = "FAILURE".to_string();
+        let events = vec![
+            test_utils::coin_event(1, 853_001, "token.near", "alice.near", 100),
+            test_utils::coin_event(2, 853_002, "token.near", "alice.near", -30),
+            test_utils::coin_event(3, 853_001, "wrap.near", "alice.near", 5),
+            test_utils::coin_event(4, 853_002, "wrap.near", "alice.near", -5),
+            failed_mint,
+            test_utils::coin_event(6, 853_003, "usdt.near", "alice.near", 1),
+            test_utils::coin_event(7, 853_001, "token.near", "bob.near", 10),
+        ];
+        crate::models::chunked_insert(&pool, &events).await.unwrap();
+        let alice = AccountId::from_str("alice.near").unwrap();
+        let portfolio = |block_height| account_portfolio(&pool, &alice, block_height);
+
+        assert_eq!(
+            portfolio(853_002).await.unwrap(),
+            HashMap::from([
+                (AccountId::from_str("token.near").unwrap(), 70.into()),
+                (AccountId::from_str("wrap.near").unwrap(), 0.into()),
+            ])
+        );
+        assert_eq!(portfolio(853_003).await.unwrap().len(), 3);
+        assert!(portfolio(853_000).await.unwrap().is_empty());
+    }
+}
@@ -1,7 +1,9 @@
 use crate::db_adapters;
 use crate::db_adapters::coin;
+use crate::db_adapters::coin::legacy;
 use crate::db_adapters::{numeric_types, Event};
 use crate::models::coin_events::CoinEvent;
+use crate::settings::IndexerSettings;
 use bigdecimal::BigDecimal;
 use near_lake_framework::near_indexer_primitives;
 use near_primitives::borsh;
@@ -31,7 +33,6 @@ pub struct WithdrawCallArgs {
 
 /// Base Eth Address type
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
-#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Address(primitive_types::H160);
 
 impl BorshSerialize for Address {
@@ -56,6 +57,7 @@ impl BorshDeserialize for Address {
 }
 
 pub(crate) async fn collect_aurora(
+    settings: &IndexerSettings,
     shard_id: &near_indexer_primitives::types::ShardId,
     receipt_execution_outcomes: &[near_indexer_primitives::IndexerExecutionOutcomeWithReceipt],
     block_header: &near_indexer_primitives::views::BlockHeaderView,
@@ -63,13 +65,15 @@ pub(crate) async fn collect_aurora(
     let mut events: Vec<CoinEvent> = vec![];
     for outcome in receipt_execution_outcomes {
         if outcome.receipt.receiver_id != AccountId::from_str("aurora")?
-            || !db_adapters::events::extract_events(outcome).is_empty()
+            || !db_adapters::events::extract_events(settings, outcome).is_empty()
         {
             continue;
         }
         if let ReceiptEnumView::Action { actions, .. } = &outcome.receipt.receipt {
             for action in actions {
-                events.extend(process_aurora_functions(block_header, action, outcome).await?);
+                events.extend(
+                    process_aurora_functions(settings, block_header, action, outcome).await?,
+                );
             }
         }
     }
@@ -83,6 +87,7 @@ pub(crate) async fn collect_aurora(
 }
 
 async fn process_aurora_functions(
+    settings: &IndexerSettings,
     block_header: &near_indexer_primitives::views::BlockHeaderView,
     action: &ActionView,
     outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
@@ -100,7 +105,7 @@ async fn process_aurora_functions(
     // Note: aurora (now always, but) usually has binary args
     let decoded_args = base64::decode(args)?;
 
-    if vec![
+    if [
         "new",
         "call",
         "new_eth_connector",
@@ -148,10 +153,10 @@ async fn process_aurora_functions(
                     affected_id: AccountId::from_str(account_id)?,
                     involved_id: None,
                     delta,
-                    cause: "MINT".to_string(),
+                    cause: coin::CoinEventCause::Mint,
                     memo: None,
                 };
-                events.push(coin::build_event(base, custom).await?);
+                events.push(coin::build_event(settings, base, custom));
             };
         }
 
@@ -172,7 +177,8 @@ async fn process_aurora_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -183,14 +189,14 @@ async fn process_aurora_functions(
         let memo = ft_transfer_args
             .memo
             .as_ref()
-            .map(|s| s.escape_default().to_string());
+            .map(|s| db_adapters::events::prepare_memo(settings, s));
 
         let base_from = db_adapters::get_base(Event::Aurora, outcome, block_header)?;
         let custom_from = coin::FtEvent {
             affected_id: outcome.receipt.predecessor_id.clone(),
             involved_id: Some(ft_transfer_args.receiver_id.clone()),
             delta: negative_delta,
-            cause: "TRANSFER".to_string(),
+            cause: coin::CoinEventCause::Transfer,
             memo: memo.clone(),
         };
 
@@ -199,12 +205,12 @@ async fn process_aurora_functions(
             affected_id: ft_transfer_args.receiver_id,
             involved_id: Some(outcome.receipt.predecessor_id.clone()),
             delta,
-            cause: "TRANSFER".to_string(),
+            cause: coin::CoinEventCause::Transfer,
             memo,
         };
         return Ok(vec![
-            coin::build_event(base_from, custom_from).await?,
-            coin::build_event(base_to, custom_to).await?,
+            coin::build_event(settings, base_from, custom_from),
+            coin::build_event(settings, base_to, custom_to),
         ]);
     }
 
@@ -241,20 +247,20 @@ async fn process_aurora_functions(
                     affected_id: AccountId::from_str(from_account_id)?,
                     involved_id: Some(AccountId::from_str(to_account_id)?),
                     delta: negative_delta,
-                    cause: "TRANSFER".to_string(),
+                    cause: coin::CoinEventCause::Transfer,
                     memo: None,
                 };
-                events.push(coin::build_event(base_from, custom_from).await?);
+                events.push(coin::build_event(settings, base_from, custom_from));
 
                 let base_to = db_adapters::get_base(Event::Aurora, outcome, block_header)?;
                 let custom_to = coin::FtEvent {
                     affected_id: AccountId::from_str(to_account_id)?,
                     involved_id: Some(AccountId::from_str(from_account_id)?),
                     delta,
-                    cause: "TRANSFER".to_string(),
+                    cause: coin::CoinEventCause::Transfer,
                     memo: None,
                 };
-                events.push(coin::build_event(base_to, custom_to).await?);
+                events.push(coin::build_event(settings, base_to, custom_to));
             };
         }
         return Ok(events);
@@ -271,7 +277,8 @@ async fn process_aurora_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -284,19 +291,21 @@ async fn process_aurora_functions(
             affected_id: outcome.receipt.predecessor_id.clone(),
             involved_id: None,
             delta: negative_delta,
-            cause: "BURN".to_string(),
+            cause: coin::CoinEventCause::Burn,
             memo: None,
         };
 
-        return Ok(vec![coin::build_event(base, custom).await?]);
+        return Ok(vec![coin::build_event(settings, base, custom)]);
     }
 
     tracing::error!(
         target: crate::LOGGING_PREFIX,
-        "AURORA {} new method found: {}, receipt {}",
-        block_header.height,
-        method_name,
-        outcome.receipt.receipt_id
+        block_height = block_header.height,
+        receipt_id = %outcome.receipt.receipt_id,
+        contract_id = %outcome.receipt.receiver_id,
+        method_name = %db_adapters::sanitize_for_log(method_name),
+        "AURORA new method found",
     );
+    legacy::on_unknown_method(outcome, method_name);
     Ok(vec![])
 }
@@ -1,6 +1,16 @@
+use crate::db_adapters::error::IndexerError;
 use crate::models::coin_events::CoinEvent;
+use crate::settings::IndexerSettings;
+use bigdecimal::BigDecimal;
+use cached::{Cached, SizedCache};
 use futures::try_join;
 use near_lake_framework::near_indexer_primitives;
+use near_primitives::borsh::BorshDeserialize;
+use near_primitives::types::AccountId;
+use num_traits::{Signed, Zero};
+use serde::de::DeserializeOwned;
+use std::str::FromStr;
+use std::sync::Mutex;
 
 mod aurora;
 mod rainbow_bridge;
@@ -9,29 +19,56 @@ mod tkn_near;
 mod wentokensir;
 mod wrap_near;
 
+/// Collects the events of all the legacy contracts.
+/// Without `pool` the state of the legacy handlers (e.g. NEP-141 cutovers of tkn.near tokens) lives only in memory
 pub(crate) async fn collect_legacy(
+    pool: Option<&sqlx::Pool<sqlx::Postgres>>,
+    settings: &IndexerSettings,
     shard_id: &near_indexer_primitives::types::ShardId,
     receipt_execution_outcomes: &[near_indexer_primitives::IndexerExecutionOutcomeWithReceipt],
     block_header: &near_indexer_primitives::views::BlockHeaderView,
     chain_id: &str,
 ) -> anyhow::Result<Vec<CoinEvent>> {
-    // We don't need to store legacy events for testnet
+    // We don't need to store legacy events for testnet, except the tokens of the factory which lives on both chains
     if chain_id == "testnet" {
-        return Ok(vec![]);
+        return tkn_near::collect_tkn_near(
+            pool,
+            settings,
+            shard_id,
+            receipt_execution_outcomes,
+            block_header,
+            chain_id,
+        )
+        .await;
     }
     let mut events: Vec<CoinEvent> = vec![];
 
-    let aurora_future = aurora::collect_aurora(shard_id, receipt_execution_outcomes, block_header);
-    let rainbow_bridge_future =
-        rainbow_bridge::collect_rainbow_bridge(shard_id, receipt_execution_outcomes, block_header);
+    let aurora_future =
+        aurora::collect_aurora(settings, shard_id, receipt_execution_outcomes, block_header);
+    let rainbow_bridge_future = rainbow_bridge::collect_rainbow_bridge(
+        settings,
+        shard_id,
+        receipt_execution_outcomes,
+        block_header,
+    );
     let skyward_future =
-        skyward::collect_skyward(shard_id, receipt_execution_outcomes, block_header);
-    let tkn_near_future =
-        tkn_near::collect_tkn_near(shard_id, receipt_execution_outcomes, block_header);
-    let wentokensir_future =
-        wentokensir::collect_wentokensir(shard_id, receipt_execution_outcomes, block_header);
+        skyward::collect_skyward(settings, shard_id, receipt_execution_outcomes, block_header);
+    let tkn_near_future = tkn_near::collect_tkn_near(
+        pool,
+        settings,
+        shard_id,
+        receipt_execution_outcomes,
+        block_header,
+        chain_id,
+    );
+    let wentokensir_future = wentokensir::collect_wentokensir(
+        settings,
+        shard_id,
+        receipt_execution_outcomes,
+        block_header,
+    );
     let wrap_near_future =
-        wrap_near::collect_wrap_near(shard_id, receipt_execution_outcomes, block_header);
+        wrap_near::collect_wrap_near(settings, shard_id, receipt_execution_outcomes, block_header);
 
     let (
         aurora_events,
@@ -57,3 +94,531 @@ pub(crate) async fn collect_legacy(
     events.extend(wrap_near_events);
     Ok(events)
 }
+
+/// Recognizes the contracts of one legacy token family by the account id: `<prefix><suffix>`.
+/// Each family decides which prefixes are valid, so the families don't weaken each other's checks
+pub(crate) struct ContractMatcher {
+    suffix: String,
+    is_valid_prefix: fn(&str) -> bool,
+}
+
+impl ContractMatcher {
+    pub(crate) fn new(suffix: impl Into<String>, is_valid_prefix: fn(&str) -> bool) -> Self {
+        Self {
+            suffix: suffix.into(),
+            is_valid_prefix,
+        }
+    }
+
+    pub(crate) fn matches(&self, contract_id: &str) -> bool {
+        contract_id
+            .strip_suffix(&self.suffix)
+            .map_or(false, self.is_valid_prefix)
+    }
+}
+
+// Token names chosen by the users of the token factories, e.g. `abc` in `abc.tkn.near`
+pub(crate) fn is_token_name(prefix: &str) -> bool {
+    lazy_static! {
+        static ref RE: regex::Regex = regex::Regex::new(r"^[a-z0-9\-]+$").unwrap();
+    }
+    RE.is_match(prefix)
+}
+
+// Bridged tokens are named after the address of the original Ethereum contract, without `0x`
+pub(crate) fn is_eth_address(prefix: &str) -> bool {
+    lazy_static! {
+        static ref RE: regex::Regex = regex::Regex::new("^[a-f0-9]{40}$").unwrap();
+    }
+    RE.is_match(prefix)
+}
+
+/// Encoding of the function call args used by the legacy contract
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ArgsEncoding {
+    Json,
+    Borsh,
+}
+
+pub(crate) fn args_encoding(settings: &IndexerSettings, contract_id: &AccountId) -> ArgsEncoding {
+    if settings.borsh_args_contracts.contains(contract_id) {
+        ArgsEncoding::Borsh
+    } else {
+        ArgsEncoding::Json
+    }
+}
+
+pub(crate) fn decode_args<T: DeserializeOwned + BorshDeserialize>(
+    args: &[u8],
+    encoding: ArgsEncoding,
+) -> Result<T, IndexerError> {
+    Ok(match encoding {
+        ArgsEncoding::Json => serde_json::from_slice::<T>(args)?,
+        ArgsEncoding::Borsh => T::try_from_slice(args)?,
+    })
+}
+
+// The legacy handlers call it for the methods they don't know how to handle
+pub(crate) fn on_unknown_method(
+    outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
+    method_name: &str,
+) {
+    crate::metrics::LEGACY_UNKNOWN_METHOD_TOTAL
+        .with_label_values(&[&unknown_method_label(method_name)])
+        .inc();
+    crate::db_adapters::validation::record_unknown_method(
+        outcome.receipt.receiver_id.as_str(),
+        method_name,
+    );
+}
+
+// Method name comes from the user, so we keep the number and the size of the labels bounded
+const MAX_UNKNOWN_METHOD_LABELS: usize = 100;
+const MAX_UNKNOWN_METHOD_LABEL_LENGTH: usize = 64;
+
+fn unknown_method_label(method_name: &str) -> String {
+    lazy_static! {
+        static ref LABELS: crate::metrics::BoundedLabels =
+            crate::metrics::BoundedLabels::new(MAX_UNKNOWN_METHOD_LABELS);
+    }
+    let label: String = method_name
+        .chars()
+        .take(MAX_UNKNOWN_METHOD_LABEL_LENGTH)
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    LABELS.get(label)
+}
+
+// The args of the successful receipt should always be parseable, so the error means we have a bug.
+// By default we stop indexing, in tolerant mode we log the error and skip the receipt
+pub(crate) fn on_args_parse_error(
+    settings: &IndexerSettings,
+    err: IndexerError,
+    outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
+    method_name: &str,
+) -> Result<(), IndexerError> {
+    skip_if_tolerant(settings, err, outcome, method_name, "unparseable args")
+}
+
+// The logs break our assumptions about the contract. Same as for the args, we stop or skip the receipt
+pub(crate) fn on_unexpected_logs(
+    settings: &IndexerSettings,
+    err: IndexerError,
+    outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
+    method_name: &str,
+) -> Result<(), IndexerError> {
+    skip_if_tolerant(settings, err, outcome, method_name, "unexpected logs")
+}
+
+fn skip_if_tolerant(
+    settings: &IndexerSettings,
+    err: IndexerError,
+    outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
+    method_name: &str,
+    reason: &str,
+) -> Result<(), IndexerError> {
+    if !settings.tolerant_parsing {
+        return Err(err);
+    }
+    crate::metrics::PARSE_ERRORS_TOTAL.inc();
+    tracing::error!(
+        target: crate::LOGGING_PREFIX,
+        receipt_id = %outcome.receipt.receipt_id,
+        contract_id = %outcome.receipt.receiver_id,
+        method_name = %crate::db_adapters::sanitize_for_log(method_name),
+        error = %err,
+        "Skipping receipt with {}",
+        reason,
+    );
+    Ok(())
+}
+
+// `ft_resolve_transfer` writes either the refund or the deleted sender log.
+// Other log lines may appear around it, so we look through all of them
+// and require exactly one refund signal instead of relying on the order
+pub(crate) fn find_refund_log(
+    outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
+) -> Result<Option<&str>, IndexerError> {
+    let refund_logs: Vec<&str> = outcome
+        .execution_outcome
+        .outcome
+        .logs
+        .iter()
+        .map(String::as_str)
+        .filter(|log| *log == SENDER_DELETED_LOG || log.starts_with("Refund "))
+        .collect();
+    match refund_logs.as_slice() {
+        [] => Ok(None),
+        [log] => Ok(Some(log)),
+        _ => Err(IndexerError::Inconsistency(format!(
+            "Expected one refund log in receipt {}, found {}: {:?}",
+            outcome.receipt.receipt_id,
+            refund_logs.len(),
+            refund_logs
+        ))),
+    }
+}
+
+pub(crate) const SENDER_DELETED_LOG: &str = "The account of the sender was deleted";
+
+// The transfer made by `ft_transfer_call`, `ft_resolve_transfer` reverts the part of it
+#[derive(Debug, Clone)]
+struct TransferCall {
+    sender_id: AccountId,
+    receiver_id: AccountId,
+    amount: BigDecimal,
+}
+
+// `ft_resolve_transfer` usually goes a couple of blocks after `ft_transfer_call`
+const TRANSFER_CALLS_CACHE_SIZE: usize = 100_000;
+
+lazy_static! {
+    // The transfers by the ids of the receipts produced by `ft_transfer_call`, one of them is `ft_resolve_transfer`
+    static ref TRANSFER_CALLS: Mutex<SizedCache<near_indexer_primitives::CryptoHash, TransferCall>> =
+        Mutex::new(SizedCache::with_size(TRANSFER_CALLS_CACHE_SIZE));
+}
+
+// Remembers the transfer of `ft_transfer_call` to cross-check its `ft_resolve_transfer` later
+pub(crate) fn remember_transfer_call(
+    outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
+    method_name: &str,
+    receiver_id: &AccountId,
+    amount: &BigDecimal,
+) {
+    if method_name != "ft_transfer_call" {
+        return;
+    }
+    let transfer_call = TransferCall {
+        sender_id: outcome.receipt.predecessor_id.clone(),
+        receiver_id: receiver_id.clone(),
+        amount: amount.clone(),
+    };
+    let mut transfer_calls = TRANSFER_CALLS
+        .lock()
+        .expect("Transfer calls cache lock is poisoned");
+    for receipt_id in &outcome.execution_outcome.outcome.receipt_ids {
+        transfer_calls.cache_set(*receipt_id, transfer_call.clone());
+    }
+}
+
+// The refund rows should revert the transfer of the originating `ft_transfer_call`:
+// the same accounts, and not more than was transferred, so the four rows never go below zero in total.
+// Returns false if they don't. The originating transfer may be unknown (e.g. it was before the start), then nothing is checked
+pub(crate) fn check_refund_matches_transfer_call(
+    outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
+    refund_amount: &BigDecimal,
+    receiver_id: &AccountId,
+    sender_id: &AccountId,
+) -> bool {
+    let transfer_call = match TRANSFER_CALLS
+        .lock()
+        .expect("Transfer calls cache lock is poisoned")
+        .cache_remove(&outcome.receipt.receipt_id)
+    {
+        Some(transfer_call) => transfer_call,
+        None => {
+            tracing::debug!(
+                target: crate::LOGGING_PREFIX,
+                receipt_id = %outcome.receipt.receipt_id,
+                contract_id = %outcome.receipt.receiver_id,
+                "The originating ft_transfer_call is unknown, the refund is not cross-checked",
+            );
+            return true;
+        }
+    };
+    let matches = &transfer_call.sender_id == sender_id
+        && &transfer_call.receiver_id == receiver_id
+        && !refund_amount.is_negative()
+        && refund_amount <= &transfer_call.amount;
+    if !matches {
+        tracing::warn!(
+            target: crate::LOGGING_PREFIX,
+            receipt_id = %outcome.receipt.receipt_id,
+            contract_id = %outcome.receipt.receiver_id,
+            %refund_amount,
+            %receiver_id,
+            %sender_id,
+            transferred_amount = %transfer_call.amount,
+            transfer_receiver_id = %transfer_call.receiver_id,
+            transfer_sender_id = %transfer_call.sender_id,
+            "Refund does not net out with the originating ft_transfer_call",
+        );
+    }
+    matches
+}
+
+// near-sdk writes `Refund <amount> from <receiver_id> to <sender_id>` in `ft_resolve_transfer`.
+// We build the refund rows from the args and the result of `ft_on_transfer`,
+// so the log is used to cross-check that the rows revert the original transfer exactly
+pub(crate) fn check_refund_log(
+    outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
+    log: &str,
+    refund_amount: &BigDecimal,
+    receiver_id: &AccountId,
+    sender_id: &AccountId,
+) {
+    lazy_static! {
+        static ref RE: regex::Regex = regex::Regex::new(
+            r"^Refund (?P<amount>(0|[1-9][0-9]*)) from (?P<from_account_id>[a-z0-9_\.\-]+) to (?P<to_account_id>[a-z0-9_\.\-]+)$"
+        )
+        .unwrap();
+    }
+    let matches = RE.captures(log).map_or(false, |cap| {
+        BigDecimal::from_str(&cap["amount"]).ok().as_ref() == Some(refund_amount)
+            && &cap["from_account_id"] == receiver_id.as_str()
+            && &cap["to_account_id"] == sender_id.as_str()
+    });
+    if !matches {
+        tracing::warn!(
+            target: crate::LOGGING_PREFIX,
+            receipt_id = %outcome.receipt.receipt_id,
+            contract_id = %outcome.receipt.receiver_id,
+            %refund_amount,
+            %receiver_id,
+            %sender_id,
+            log = %crate::db_adapters::sanitize_for_log(log),
+            "Refund log does not match the reverted transfer",
+        );
+    }
+}
+
+// Something should be returned to the sender, but the contract didn't log the refund,
+// so the original transfer stays unreverted in our data
+pub(crate) fn check_missing_refund(
+    outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
+    refund_amount: &BigDecimal,
+) {
+    if !refund_amount.is_zero() {
+        tracing::warn!(
+            target: crate::LOGGING_PREFIX,
+            receipt_id = %outcome.receipt.receipt_id,
+            contract_id = %outcome.receipt.receiver_id,
+            %refund_amount,
+            logs = ?outcome.execution_outcome.outcome.logs,
+            "Expected refund is not found in the logs of ft_resolve_transfer",
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    const ETH_ADDRESS: &str = "6b175474e89094c44da98b954eedeac495271d0f";
+
+    #[test]
+    fn token_names() {
+        for (prefix, expected) in [
+            ("abc", true),
+            ("abc-123", true),
+            ("0", true),
+            ("", false),
+            ("Abc", false),
+            ("abc_def", false),
+            ("abc.def", false),
+            ("abc ", false),
+        ] {
+            assert_eq!(is_token_name(prefix), expected, "`{}`", prefix);
+        }
+    }
+
+    #[test]
+    fn eth_addresses() {
+        for (prefix, expected) in [
+            (ETH_ADDRESS.to_string(), true),
+            (ETH_ADDRESS.to_uppercase(), false),
+            (format!("0x{}", ETH_ADDRESS), false),
+            (ETH_ADDRESS[1..].to_string(), false),
+            (format!("{}0", ETH_ADDRESS), false),
+            (ETH_ADDRESS.replace('f', "g"), false),
+        ] {
+            assert_eq!(is_eth_address(&prefix), expected, "`{}`", prefix);
+        }
+    }
+
+    #[test]
+    fn contract_matcher_needs_both_suffix_and_prefix() {
+        let bridge = ContractMatcher::new(".factory.bridge.near", is_eth_address);
+        let wentokensir = ContractMatcher::new(".wentokensir.near", is_token_name);
+        for (matcher, contract_id, expected) in [
+            (
+                &bridge,
+                format!("{}.factory.bridge.near", ETH_ADDRESS),
+                true,
+            ),
+            (&bridge, "abc.factory.bridge.near".to_string(), false),
+            (&bridge, format!("{}.bridge.near", ETH_ADDRESS), false),
+            (&bridge, ".factory.bridge.near".to_string(), false),
+            (&bridge, "factory.bridge.near".to_string(), false),
+            (&wentokensir, "abc.wentokensir.near".to_string(), true),
+            (&wentokensir, "abc.wentokensir.nearx".to_string(), false),
+            (&wentokensir, "a.b.wentokensir.near".to_string(), false),
+            (&wentokensir, "wentokensir.near".to_string(), false),
+        ] {
+            assert_eq!(matcher.matches(&contract_id), expected, "{}", contract_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn broken_args_stop_indexing_unless_tolerant() {
+        let _globals = test_utils::lock_globals().await;
+        let broken_transfer = test_utils::function_call_outcome(
+            "broken",
+            "alice.near",
+            "abc.tkn.near",
+            "ft_transfer",
+            br#"{"receiver_id":"bob.near""#,
+            &[],
+        );
+        let collect = |settings: IndexerSettings| {
+            let outcomes = [broken_transfer.clone()];
+            async move {
+                collect_legacy(
+                    None,
+                    &settings,
+                    &0,
+                    &outcomes,
+                    &test_utils::block_header(805_001),
+                    "mainnet",
+                )
+                .await
+            }
+        };
+
+        assert!(collect(IndexerSettings::default()).await.is_err());
+
+        let parse_errors_before = crate::metrics::PARSE_ERRORS_TOTAL.get();
+        let tolerant_settings = IndexerSettings {
+            tolerant_parsing: true,
+            ..IndexerSettings::default()
+        };
+        assert!(collect(tolerant_settings).await.unwrap().is_empty());
+        assert_eq!(
+            crate::metrics::PARSE_ERRORS_TOTAL.get() - parse_errors_before,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_method_is_counted_with_sanitized_label() {
+        let _globals = test_utils::lock_globals().await;
+        let counter =
+            crate::metrics::LEGACY_UNKNOWN_METHOD_TOTAL.with_label_values(&["new_method_v2_"]);
+        let calls_before = counter.get();
+        let unknown_call = test_utils::function_call_outcome(
+            "unknown method",
+            "alice.near",
+            "wrap.near",
+            "new-method.v2!",
+            b"{}",
+            &[],
+        );
+
+        let events = collect_legacy(
+            None,
+            &IndexerSettings::default(),
+            &0,
+            &[unknown_call],
+            &test_utils::block_header(826_001),
+            "mainnet",
+        )
+        .await
+        .unwrap();
+        assert!(events.is_empty());
+        assert_eq!(counter.get() - calls_before, 1);
+    }
+
+    #[tokio::test]
+    async fn method_name_cannot_forge_log_lines() {
+        let _globals = test_utils::lock_globals().await;
+        let forging_call = test_utils::function_call_outcome(
+            "forging method",
+            "alice.near",
+            "wrap.near",
+            "x\n2022-11-01T00:00:00Z  INFO indexer_events: \u{1b}[31mforged",
+            b"{}",
+            &[],
+        );
+
+        let (logs, _guard) = test_utils::capture_logs();
+        collect_legacy(
+            None,
+            &IndexerSettings::default(),
+            &0,
+            &[forging_call],
+            &test_utils::block_header(854_001),
+            "mainnet",
+        )
+        .await
+        .unwrap();
+
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        assert_eq!(logs.lines().count(), 1, "{}", logs);
+        assert!(
+            logs.contains(
+                r"method_name=x\n2022-11-01T00:00:00Z  INFO indexer_events: \u{1b}[31mforged"
+            ),
+            "{}",
+            logs
+        );
+    }
+
+    #[tokio::test]
+    async fn tkn_near_mint_accepts_both_recipient_names() {
+        let mints = [
+            test_utils::function_call_outcome(
+                "mint to account_id",
+                "abc.tkn.near",
+                "abc.tkn.near",
+                "ft_mint",
+                br#"{"account_id":"alice.near","amount":"10","memo":"drop"}"#,
+                &[],
+            ),
+            test_utils::function_call_outcome(
+                "mint to owner_id",
+                "abc.tkn.near",
+                "abc.tkn.near",
+                "ft_mint",
+                br#"{"owner_id":"bob.near","amount":"5"}"#,
+                &[],
+            ),
+        ];
+
+        let events = collect_legacy(
+            None,
+            &IndexerSettings::default(),
+            &0,
+            &mints,
+            &test_utils::block_header(872_001),
+            "mainnet",
+        )
+        .await
+        .unwrap();
+        let minted: Vec<_> = events
+            .iter()
+            .map(|event| {
+                assert_eq!(event.cause, "MINT");
+                assert_eq!(event.involved_account_id, None);
+                (
+                    event.affected_account_id.as_str(),
+                    event.delta_amount.to_string(),
+                    event.event_memo.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            minted,
+            vec![
+                ("alice.near", "10".to_string(), Some("drop")),
+                ("bob.near", "5".to_string(), None),
+            ]
+        );
+    }
+}
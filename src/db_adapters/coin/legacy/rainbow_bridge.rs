@@ -1,6 +1,8 @@
 use crate::db_adapters;
+use crate::db_adapters::coin::legacy;
 use crate::db_adapters::{coin, numeric_types, Event};
 use crate::models::coin_events::CoinEvent;
+use crate::settings::IndexerSettings;
 use bigdecimal::BigDecimal;
 use near_lake_framework::near_indexer_primitives;
 use near_primitives::types::AccountId;
@@ -38,6 +40,7 @@ struct Withdraw {
 }
 
 pub(crate) async fn collect_rainbow_bridge(
+    settings: &IndexerSettings,
     shard_id: &near_indexer_primitives::types::ShardId,
     receipt_execution_outcomes: &[near_indexer_primitives::IndexerExecutionOutcomeWithReceipt],
     block_header: &near_indexer_primitives::views::BlockHeaderView,
@@ -46,14 +49,16 @@ pub(crate) async fn collect_rainbow_bridge(
 
     for outcome in receipt_execution_outcomes {
         if !is_rainbow_bridge_contract(outcome.receipt.receiver_id.as_str())
-            || !db_adapters::events::extract_events(outcome).is_empty()
+            || !db_adapters::events::extract_events(settings, outcome).is_empty()
         {
             continue;
         }
         if let ReceiptEnumView::Action { actions, .. } = &outcome.receipt.receipt {
             for action in actions {
-                events
-                    .extend(process_rainbow_bridge_functions(block_header, action, outcome).await?);
+                events.extend(
+                    process_rainbow_bridge_functions(settings, block_header, action, outcome)
+                        .await?,
+                );
             }
         }
     }
@@ -69,17 +74,15 @@ pub(crate) async fn collect_rainbow_bridge(
 }
 
 fn is_rainbow_bridge_contract(contract_id: &str) -> bool {
-    if let Some(contract_prefix) = contract_id.strip_suffix(".factory.bridge.near") {
-        lazy_static::lazy_static! {
-            static ref RE: regex::Regex = regex::Regex::new("^[a-f0-9]+$").unwrap();
-        }
-        contract_prefix.len() == 40 && RE.is_match(contract_prefix)
-    } else {
-        false
+    lazy_static::lazy_static! {
+        static ref MATCHER: legacy::ContractMatcher =
+            legacy::ContractMatcher::new(".factory.bridge.near", legacy::is_eth_address);
     }
+    MATCHER.matches(contract_id)
 }
 
 async fn process_rainbow_bridge_functions(
+    settings: &IndexerSettings,
     block_header: &near_indexer_primitives::views::BlockHeaderView,
     action: &ActionView,
     outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
@@ -96,7 +99,7 @@ async fn process_rainbow_bridge_functions(
 
     let decoded_args = base64::decode(args)?;
 
-    if vec![
+    if [
         "storage_deposit",
         "finish_deposit",
         "verify_log_entry",
@@ -122,7 +125,8 @@ async fn process_rainbow_bridge_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -134,10 +138,10 @@ async fn process_rainbow_bridge_functions(
             affected_id: mint_args.account_id.clone(),
             involved_id: None,
             delta,
-            cause: "MINT".to_string(),
+            cause: coin::CoinEventCause::Mint,
             memo: None,
         };
-        return Ok(vec![coin::build_event(base, custom).await?]);
+        return Ok(vec![coin::build_event(settings, base, custom)]);
     }
 
     // TRANSFER produces 2 events
@@ -154,7 +158,8 @@ async fn process_rainbow_bridge_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -165,14 +170,16 @@ async fn process_rainbow_bridge_functions(
         let memo = ft_transfer_args
             .memo
             .as_ref()
-            .map(|s| s.escape_default().to_string());
+            .map(|s| db_adapters::events::prepare_memo(settings, s));
+
+        legacy::remember_transfer_call(outcome, method_name, &ft_transfer_args.receiver_id, &delta);
 
         let base_from = db_adapters::get_base(Event::RainbowBridge, outcome, block_header)?;
         let custom_from = coin::FtEvent {
             affected_id: outcome.receipt.predecessor_id.clone(),
             involved_id: Some(ft_transfer_args.receiver_id.clone()),
             delta: negative_delta,
-            cause: "TRANSFER".to_string(),
+            cause: coin::CoinEventCause::Transfer,
             memo: memo.clone(),
         };
 
@@ -181,12 +188,12 @@ async fn process_rainbow_bridge_functions(
             affected_id: ft_transfer_args.receiver_id,
             involved_id: Some(outcome.receipt.predecessor_id.clone()),
             delta,
-            cause: "TRANSFER".to_string(),
+            cause: coin::CoinEventCause::Transfer,
             memo,
         };
         return Ok(vec![
-            coin::build_event(base_from, custom_from).await?,
-            coin::build_event(base_to, custom_to).await?,
+            coin::build_event(settings, base_from, custom_from),
+            coin::build_event(settings, base_to, custom_to),
         ]);
     }
 
@@ -206,7 +213,8 @@ async fn process_rainbow_bridge_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -225,55 +233,77 @@ async fn process_rainbow_bridge_functions(
         let memo = ft_refund_args
             .memo
             .as_ref()
-            .map(|s| s.escape_default().to_string());
+            .map(|s| db_adapters::events::prepare_memo(settings, s));
 
-        for log in &outcome.execution_outcome.outcome.logs {
-            if log == "The account of the sender was deleted" {
-                // I never met this case so it's better to re-check it manually when we find it
-                tracing::error!(
-                    target: crate::LOGGING_PREFIX,
-                    "The account of the sender was deleted {}",
-                    block_header.height
-                );
-
-                // we should revert ft_transfer_call, but there's no receiver_id. We should burn tokens
-                let base = db_adapters::get_base(Event::RainbowBridge, outcome, block_header)?;
-                let custom = coin::FtEvent {
-                    affected_id: ft_refund_args.receiver_id,
-                    involved_id: None,
-                    delta: negative_delta,
-                    cause: "BURN".to_string(),
-                    memo,
-                };
-                return Ok(vec![coin::build_event(base, custom).await?]);
+        let refund_log = match legacy::find_refund_log(outcome) {
+            Ok(Some(log)) => log,
+            Ok(None) => {
+                legacy::check_missing_refund(outcome, &delta);
+                return Ok(vec![]);
+            }
+            Err(err) => {
+                legacy::on_unexpected_logs(settings, err, outcome, method_name)?;
+                return Ok(vec![]);
             }
-            if log.starts_with("Refund ") {
-                // we should revert ft_transfer_call
-                let base_from = db_adapters::get_base(Event::RainbowBridge, outcome, block_header)?;
-                let custom_from = coin::FtEvent {
-                    affected_id: ft_refund_args.receiver_id.clone(),
-                    involved_id: Some(ft_refund_args.sender_id.clone()),
-                    delta: negative_delta,
-                    cause: "TRANSFER".to_string(),
-                    memo: memo.clone(),
-                };
+        };
 
-                let base_to = db_adapters::get_base(Event::RainbowBridge, outcome, block_header)?;
-                let custom_to = coin::FtEvent {
-                    affected_id: ft_refund_args.sender_id,
-                    involved_id: Some(ft_refund_args.receiver_id),
-                    delta,
-                    cause: "TRANSFER".to_string(),
-                    memo,
-                };
+        if refund_log == legacy::SENDER_DELETED_LOG {
+            // I never met this case so it's better to re-check it manually when we find it
+            tracing::error!(
+                target: crate::LOGGING_PREFIX,
+                block_height = block_header.height,
+                receipt_id = %outcome.receipt.receipt_id,
+                "The account of the sender was deleted",
+            );
 
-                return Ok(vec![
-                    coin::build_event(base_from, custom_from).await?,
-                    coin::build_event(base_to, custom_to).await?,
-                ]);
-            }
+            // we should revert ft_transfer_call, but there's no receiver_id. We should burn tokens
+            let base = db_adapters::get_base(Event::RainbowBridge, outcome, block_header)?;
+            let custom = coin::FtEvent {
+                affected_id: ft_refund_args.receiver_id,
+                involved_id: None,
+                delta: negative_delta,
+                cause: coin::CoinEventCause::Burn,
+                memo,
+            };
+            return Ok(vec![coin::build_event(settings, base, custom)]);
         }
-        return Ok(vec![]);
+
+        legacy::check_refund_log(
+            outcome,
+            refund_log,
+            &delta,
+            &ft_refund_args.receiver_id,
+            &ft_refund_args.sender_id,
+        );
+        legacy::check_refund_matches_transfer_call(
+            outcome,
+            &delta,
+            &ft_refund_args.receiver_id,
+            &ft_refund_args.sender_id,
+        );
+        // we should revert ft_transfer_call
+        let base_from = db_adapters::get_base(Event::RainbowBridge, outcome, block_header)?;
+        let custom_from = coin::FtEvent {
+            affected_id: ft_refund_args.receiver_id.clone(),
+            involved_id: Some(ft_refund_args.sender_id.clone()),
+            delta: negative_delta,
+            cause: coin::CoinEventCause::Transfer,
+            memo: memo.clone(),
+        };
+
+        let base_to = db_adapters::get_base(Event::RainbowBridge, outcome, block_header)?;
+        let custom_to = coin::FtEvent {
+            affected_id: ft_refund_args.sender_id,
+            involved_id: Some(ft_refund_args.receiver_id),
+            delta,
+            cause: coin::CoinEventCause::Transfer,
+            memo,
+        };
+
+        return Ok(vec![
+            coin::build_event(settings, base_from, custom_from),
+            coin::build_event(settings, base_to, custom_to),
+        ]);
     }
 
     // BURN produces 1 event, where involved_account_id is NULL
@@ -288,7 +318,8 @@ async fn process_rainbow_bridge_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -301,18 +332,20 @@ async fn process_rainbow_bridge_functions(
             affected_id: outcome.receipt.predecessor_id.clone(),
             involved_id: None,
             delta: negative_delta,
-            cause: "BURN".to_string(),
+            cause: coin::CoinEventCause::Burn,
             memo: None,
         };
-        return Ok(vec![coin::build_event(base, custom).await?]);
+        return Ok(vec![coin::build_event(settings, base, custom)]);
     }
 
     tracing::error!(
         target: crate::LOGGING_PREFIX,
-        "RAINBOW {} new method found: {}, receipt {}",
-        block_header.height,
-        method_name,
-        outcome.receipt.receipt_id
+        block_height = block_header.height,
+        receipt_id = %outcome.receipt.receipt_id,
+        contract_id = %outcome.receipt.receiver_id,
+        method_name = %db_adapters::sanitize_for_log(method_name),
+        "RAINBOW new method found",
     );
+    legacy::on_unknown_method(outcome, method_name);
     Ok(vec![])
 }
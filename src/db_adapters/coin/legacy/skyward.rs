@@ -1,6 +1,8 @@
 use crate::db_adapters;
+use crate::db_adapters::coin::legacy;
 use crate::db_adapters::{coin, numeric_types, Event};
 use crate::models::coin_events::CoinEvent;
+use crate::settings::IndexerSettings;
 use bigdecimal::BigDecimal;
 use near_lake_framework::near_indexer_primitives;
 use near_primitives::types::AccountId;
@@ -32,6 +34,7 @@ struct FtRefund {
 }
 
 pub(crate) async fn collect_skyward(
+    settings: &IndexerSettings,
     shard_id: &near_indexer_primitives::types::ShardId,
     receipt_execution_outcomes: &[near_indexer_primitives::IndexerExecutionOutcomeWithReceipt],
     block_header: &near_indexer_primitives::views::BlockHeaderView,
@@ -40,13 +43,15 @@ pub(crate) async fn collect_skyward(
 
     for outcome in receipt_execution_outcomes {
         if outcome.receipt.receiver_id != AccountId::from_str("token.skyward.near")?
-            || !db_adapters::events::extract_events(outcome).is_empty()
+            || !db_adapters::events::extract_events(settings, outcome).is_empty()
         {
             continue;
         }
         if let ReceiptEnumView::Action { actions, .. } = &outcome.receipt.receipt {
             for action in actions {
-                events.extend(process_skyward_functions(block_header, action, outcome).await?);
+                events.extend(
+                    process_skyward_functions(settings, block_header, action, outcome).await?,
+                );
             }
         }
     }
@@ -61,6 +66,7 @@ pub(crate) async fn collect_skyward(
 }
 
 async fn process_skyward_functions(
+    settings: &IndexerSettings,
     block_header: &near_indexer_primitives::views::BlockHeaderView,
     action: &ActionView,
     outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
@@ -74,7 +80,7 @@ async fn process_skyward_functions(
 
     let decoded_args = base64::decode(args)?;
 
-    if vec![
+    if [
         "storage_deposit",
         "ft_balance_of",
         "ft_metadata",
@@ -97,7 +103,8 @@ async fn process_skyward_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -109,10 +116,10 @@ async fn process_skyward_functions(
             affected_id: args.owner_id,
             involved_id: None,
             delta,
-            cause: "MINT".to_string(),
+            cause: coin::CoinEventCause::Mint,
             memo: None,
         };
-        return Ok(vec![coin::build_event(base, custom).await?]);
+        return Ok(vec![coin::build_event(settings, base, custom)]);
     }
 
     // no examples of MINT calls except `new`
@@ -131,7 +138,8 @@ async fn process_skyward_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -142,14 +150,16 @@ async fn process_skyward_functions(
         let memo = ft_transfer_args
             .memo
             .as_ref()
-            .map(|s| s.escape_default().to_string());
+            .map(|s| db_adapters::events::prepare_memo(settings, s));
+
+        legacy::remember_transfer_call(outcome, method_name, &ft_transfer_args.receiver_id, &delta);
 
         let base_from = db_adapters::get_base(Event::Skyward, outcome, block_header)?;
         let custom_from = coin::FtEvent {
             affected_id: outcome.receipt.predecessor_id.clone(),
             involved_id: Some(ft_transfer_args.receiver_id.clone()),
             delta: negative_delta,
-            cause: "TRANSFER".to_string(),
+            cause: coin::CoinEventCause::Transfer,
             memo: memo.clone(),
         };
 
@@ -158,12 +168,12 @@ async fn process_skyward_functions(
             affected_id: ft_transfer_args.receiver_id,
             involved_id: Some(outcome.receipt.predecessor_id.clone()),
             delta,
-            cause: "TRANSFER".to_string(),
+            cause: coin::CoinEventCause::Transfer,
             memo,
         };
         return Ok(vec![
-            coin::build_event(base_from, custom_from).await?,
-            coin::build_event(base_to, custom_to).await?,
+            coin::build_event(settings, base_from, custom_from),
+            coin::build_event(settings, base_to, custom_to),
         ]);
     }
 
@@ -183,7 +193,8 @@ async fn process_skyward_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -202,64 +213,88 @@ async fn process_skyward_functions(
         let memo = ft_refund_args
             .memo
             .as_ref()
-            .map(|s| s.escape_default().to_string());
-
-        for log in &outcome.execution_outcome.outcome.logs {
-            if log == "The account of the sender was deleted" {
-                // I never met this case so it's better to re-check it manually when we find it
-                tracing::error!(
-                    target: crate::LOGGING_PREFIX,
-                    "The account of the sender was deleted {}",
-                    block_header.height
-                );
+            .map(|s| db_adapters::events::prepare_memo(settings, s));
 
-                // we should revert ft_transfer_call, but there's no receiver_id. We should burn tokens
-                let base = db_adapters::get_base(Event::Skyward, outcome, block_header)?;
-                let custom = coin::FtEvent {
-                    affected_id: ft_refund_args.receiver_id,
-                    involved_id: None,
-                    delta: negative_delta,
-                    cause: "BURN".to_string(),
-                    memo,
-                };
-                return Ok(vec![coin::build_event(base, custom).await?]);
+        let refund_log = match legacy::find_refund_log(outcome) {
+            Ok(Some(log)) => log,
+            Ok(None) => {
+                legacy::check_missing_refund(outcome, &delta);
+                return Ok(vec![]);
             }
-            if log.starts_with("Refund ") {
-                // we should revert ft_transfer_call
-                let base_from = db_adapters::get_base(Event::Skyward, outcome, block_header)?;
-                let custom_from = coin::FtEvent {
-                    affected_id: ft_refund_args.receiver_id.clone(),
-                    involved_id: Some(ft_refund_args.sender_id.clone()),
-                    delta: negative_delta,
-                    cause: "TRANSFER".to_string(),
-                    memo: memo.clone(),
-                };
-
-                let base_to = db_adapters::get_base(Event::Skyward, outcome, block_header)?;
-                let custom_to = coin::FtEvent {
-                    affected_id: ft_refund_args.sender_id,
-                    involved_id: Some(ft_refund_args.receiver_id),
-                    delta,
-                    cause: "TRANSFER".to_string(),
-                    memo,
-                };
-                return Ok(vec![
-                    coin::build_event(base_from, custom_from).await?,
-                    coin::build_event(base_to, custom_to).await?,
-                ]);
+            Err(err) => {
+                legacy::on_unexpected_logs(settings, err, outcome, method_name)?;
+                return Ok(vec![]);
             }
+        };
+
+        if refund_log == legacy::SENDER_DELETED_LOG {
+            // I never met this case so it's better to re-check it manually when we find it
+            tracing::error!(
+                target: crate::LOGGING_PREFIX,
+                block_height = block_header.height,
+                receipt_id = %outcome.receipt.receipt_id,
+                "The account of the sender was deleted",
+            );
+
+            // we should revert ft_transfer_call, but there's no receiver_id. We should burn tokens
+            let base = db_adapters::get_base(Event::Skyward, outcome, block_header)?;
+            let custom = coin::FtEvent {
+                affected_id: ft_refund_args.receiver_id,
+                involved_id: None,
+                delta: negative_delta,
+                cause: coin::CoinEventCause::Burn,
+                memo,
+            };
+            return Ok(vec![coin::build_event(settings, base, custom)]);
         }
-        return Ok(vec![]);
+
+        legacy::check_refund_log(
+            outcome,
+            refund_log,
+            &delta,
+            &ft_refund_args.receiver_id,
+            &ft_refund_args.sender_id,
+        );
+        legacy::check_refund_matches_transfer_call(
+            outcome,
+            &delta,
+            &ft_refund_args.receiver_id,
+            &ft_refund_args.sender_id,
+        );
+        // we should revert ft_transfer_call
+        let base_from = db_adapters::get_base(Event::Skyward, outcome, block_header)?;
+        let custom_from = coin::FtEvent {
+            affected_id: ft_refund_args.receiver_id.clone(),
+            involved_id: Some(ft_refund_args.sender_id.clone()),
+            delta: negative_delta,
+            cause: coin::CoinEventCause::Transfer,
+            memo: memo.clone(),
+        };
+
+        let base_to = db_adapters::get_base(Event::Skyward, outcome, block_header)?;
+        let custom_to = coin::FtEvent {
+            affected_id: ft_refund_args.sender_id,
+            involved_id: Some(ft_refund_args.receiver_id),
+            delta,
+            cause: coin::CoinEventCause::Transfer,
+            memo,
+        };
+        return Ok(vec![
+            coin::build_event(settings, base_from, custom_from),
+            coin::build_event(settings, base_to, custom_to),
+        ]);
     }
 
     // no examples of BURN calls
 
     tracing::error!(
         target: crate::LOGGING_PREFIX,
-        "SKYWARD {} new method found: {}, receipt {}",
-        block_header.height,
-        method_name,
-        outcome.receipt.receipt_id
+        block_height = block_header.height,
+        receipt_id = %outcome.receipt.receipt_id,
+        contract_id = %outcome.receipt.receiver_id,
+        method_name = %db_adapters::sanitize_for_log(method_name),
+        "SKYWARD new method found",
     );
+    legacy::on_unknown_method(outcome, method_name);
     Ok(vec![])
 }
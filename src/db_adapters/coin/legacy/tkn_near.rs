@@ -1,29 +1,46 @@
 use crate::db_adapters;
-use crate::db_adapters::{coin, numeric_types, Event};
+use crate::db_adapters::coin::legacy;
+use crate::db_adapters::error::IndexerError;
+use crate::db_adapters::{coin, event_types, numeric_types, Event};
 use crate::models::coin_events::CoinEvent;
+use crate::settings::IndexerSettings;
+use anyhow::Context;
 use bigdecimal::BigDecimal;
 use near_lake_framework::near_indexer_primitives;
+use near_primitives::borsh;
+use near_primitives::borsh::BorshDeserialize;
 use near_primitives::types::AccountId;
 use near_primitives::views::{ActionView, ExecutionStatusView, ReceiptEnumView};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::ops::{Mul, Sub};
 use std::str::FromStr;
+use std::sync::RwLock;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, BorshDeserialize, Debug, Clone)]
 struct FtNew {
     // pub metadata: ...,
     pub owner_id: AccountId,
     pub total_supply: numeric_types::U128,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, BorshDeserialize, Debug, Clone)]
+struct FtMint {
+    // Some tokens call the receiver `owner_id`
+    #[serde(alias = "owner_id")]
+    pub account_id: AccountId,
+    pub amount: numeric_types::U128,
+    pub memo: Option<String>,
+}
+
+#[derive(Deserialize, BorshDeserialize, Debug, Clone)]
 struct FtTransfer {
     pub receiver_id: AccountId,
     pub amount: numeric_types::U128,
     pub memo: Option<String>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, BorshDeserialize, Debug, Clone)]
 struct FtRefund {
     pub receiver_id: AccountId,
     pub sender_id: AccountId,
@@ -31,27 +48,47 @@ struct FtRefund {
     pub memo: Option<String>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, BorshDeserialize, Debug, Clone)]
 struct NearWithdraw {
     pub amount: numeric_types::U128,
 }
 
 pub(crate) async fn collect_tkn_near(
+    pool: Option<&sqlx::Pool<sqlx::Postgres>>,
+    settings: &IndexerSettings,
     shard_id: &near_indexer_primitives::types::ShardId,
     receipt_execution_outcomes: &[near_indexer_primitives::IndexerExecutionOutcomeWithReceipt],
     block_header: &near_indexer_primitives::views::BlockHeaderView,
+    chain_id: &str,
 ) -> anyhow::Result<Vec<CoinEvent>> {
     let mut events: Vec<CoinEvent> = vec![];
+    let contract_matcher =
+        legacy::ContractMatcher::new(tkn_contract_suffix(chain_id), legacy::is_token_name);
 
-    for outcome in receipt_execution_outcomes {
-        if !is_tkn_near_contract(outcome.receipt.receiver_id.as_str())
-            || !db_adapters::events::extract_events(outcome).is_empty()
+    let tkn_outcomes: Vec<_> = receipt_execution_outcomes
+        .iter()
+        .filter(|outcome| contract_matcher.matches(outcome.receipt.receiver_id.as_str()))
+        .collect();
+    // The cutover should be known before we process any outcome of the block
+    for outcome in &tkn_outcomes {
+        if has_nep141_events(settings, outcome) {
+            record_nep141_cutover(pool, &outcome.receipt.receiver_id, block_header).await?;
+        }
+    }
+
+    for outcome in tkn_outcomes {
+        if is_cut_over_to_nep141(pool, &outcome.receipt.receiver_id, block_header.height).await?
+            || !db_adapters::events::extract_events(settings, outcome).is_empty()
         {
             continue;
         }
         if let ReceiptEnumView::Action { actions, .. } = &outcome.receipt.receipt {
             for action in actions {
-                events.extend(process_tkn_near_functions(block_header, action, outcome).await?);
+                events.extend(
+                    process_tkn_near_functions(settings, block_header, action, outcome)
+                        .await
+                        .with_context(|| format!("receipt {}", outcome.receipt.receipt_id))?,
+                );
             }
         }
     }
@@ -65,22 +102,129 @@ pub(crate) async fn collect_tkn_near(
     Ok(events)
 }
 
-fn is_tkn_near_contract(contract_id: &str) -> bool {
-    if let Some(contract_prefix) = contract_id.strip_suffix(".tkn.near") {
-        lazy_static::lazy_static! {
-            static ref RE: regex::Regex = regex::Regex::new(r"^[a-z0-9\-]+$").unwrap();
+lazy_static! {
+    // The tokens could be upgraded to emit NEP-141 events. Since the first such event,
+    // the contract is handled only by NEP-141 collector, otherwise the methods which don't emit
+    // the events (yet) would be counted by both collectors or by none of them.
+    // The cutovers are stored in `nep141_cutovers` table, this is the cache of it:
+    // contract id -> the height of the block with the first NEP-141 event, None if there's no such block.
+    // The contract is missing here if the table is not checked yet
+    static ref NEP141_CUTOVERS: RwLock<HashMap<AccountId, Option<u64>>> =
+        RwLock::new(HashMap::new());
+}
+
+fn has_nep141_events(
+    settings: &IndexerSettings,
+    outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
+) -> bool {
+    db_adapters::events::extract_events(settings, outcome)
+        .iter()
+        .any(|extracted| matches!(extracted.event, event_types::NearEvent::Nep141(_)))
+}
+
+async fn nep141_cutover_height(
+    pool: Option<&sqlx::Pool<sqlx::Postgres>>,
+    contract_id: &AccountId,
+) -> anyhow::Result<Option<u64>> {
+    if let Some(cutover_height) = NEP141_CUTOVERS
+        .read()
+        .expect("NEP-141 cutovers lock is poisoned")
+        .get(contract_id)
+    {
+        return Ok(*cutover_height);
+    }
+    let stored_height: Option<BigDecimal> = match pool {
+        Some(pool) => {
+            sqlx::query_scalar(
+                "SELECT block_height FROM nep141_cutovers WHERE contract_account_id = $1",
+            )
+            .bind(contract_id.as_str())
+            .fetch_optional(pool)
+            .await?
         }
-        RE.is_match(contract_prefix)
-    } else {
-        false
+        None => None,
+    };
+    let stored_height = stored_height
+        .map(|height| u64::from_str(&height.to_string()))
+        .transpose()?;
+    // The cutover could be recorded while we were reading the table
+    let mut cutovers = NEP141_CUTOVERS
+        .write()
+        .expect("NEP-141 cutovers lock is poisoned");
+    let cutover_height = cutovers.entry(contract_id.clone()).or_insert(None);
+    *cutover_height = earliest(*cutover_height, stored_height);
+    Ok(*cutover_height)
+}
+
+fn earliest(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// Reprocessing the earlier blocks moves the cutover back
+async fn record_nep141_cutover(
+    pool: Option<&sqlx::Pool<sqlx::Postgres>>,
+    contract_id: &AccountId,
+    block_header: &near_indexer_primitives::views::BlockHeaderView,
+) -> anyhow::Result<()> {
+    let known_height = nep141_cutover_height(pool, contract_id).await?;
+    if known_height.map_or(false, |height| height <= block_header.height) {
+        return Ok(());
+    }
+    if let Some(pool) = pool {
+        sqlx::query(
+            "INSERT INTO nep141_cutovers VALUES ($1, $2, $3)
+             ON CONFLICT (contract_account_id) DO UPDATE
+             SET block_height = excluded.block_height, block_timestamp = excluded.block_timestamp
+             WHERE excluded.block_height < nep141_cutovers.block_height",
+        )
+        .bind(contract_id.as_str())
+        .bind(BigDecimal::from(block_header.height))
+        .bind(BigDecimal::from(block_header.timestamp))
+        .execute(pool)
+        .await?;
+    }
+    let mut cutovers = NEP141_CUTOVERS
+        .write()
+        .expect("NEP-141 cutovers lock is poisoned");
+    let cutover_height = cutovers.entry(contract_id.clone()).or_insert(None);
+    *cutover_height = earliest(*cutover_height, Some(block_header.height));
+    tracing::info!(
+        target: crate::LOGGING_PREFIX,
+        contract_id = %contract_id,
+        block_height = block_header.height,
+        "Contract switched from legacy methods to NEP-141 events, legacy handling is stopped",
+    );
+    Ok(())
+}
+
+// Blocks could be reprocessed, so the blocks before the cutover are still handled as legacy ones
+async fn is_cut_over_to_nep141(
+    pool: Option<&sqlx::Pool<sqlx::Postgres>>,
+    contract_id: &AccountId,
+    block_height: u64,
+) -> anyhow::Result<bool> {
+    Ok(nep141_cutover_height(pool, contract_id)
+        .await?
+        .map_or(false, |cutover_height| cutover_height <= block_height))
+}
+
+// The same token factory is deployed on mainnet as `tkn.near` and on testnet as `tkn.testnet`
+fn tkn_contract_suffix(chain_id: &str) -> String {
+    match chain_id {
+        "mainnet" => ".tkn.near".to_string(),
+        _ => format!(".tkn.{}", chain_id),
     }
 }
 
 async fn process_tkn_near_functions(
+    settings: &IndexerSettings,
     block_header: &near_indexer_primitives::views::BlockHeaderView,
     action: &ActionView,
     outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
-) -> anyhow::Result<Vec<CoinEvent>> {
+) -> Result<Vec<CoinEvent>, IndexerError> {
     let (method_name, args, deposit) = match action {
         ActionView::FunctionCall {
             method_name,
@@ -92,8 +236,9 @@ async fn process_tkn_near_functions(
     };
 
     let decoded_args = base64::decode(args)?;
+    let args_encoding = legacy::args_encoding(settings, &outcome.receipt.receiver_id);
 
-    if vec![
+    if [
         "storage_deposit",
         "ft_balance_of",
         "ft_metadata",
@@ -106,7 +251,7 @@ async fn process_tkn_near_functions(
 
     // may mint the tokens
     if method_name == "new" {
-        let args = match serde_json::from_slice::<FtNew>(&decoded_args) {
+        let args = match legacy::decode_args::<FtNew>(&decoded_args, args_encoding) {
             Ok(x) => x,
             Err(err) => {
                 match outcome.execution_outcome.outcome.status {
@@ -116,7 +261,8 @@ async fn process_tkn_near_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err, outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -128,10 +274,10 @@ async fn process_tkn_near_functions(
             affected_id: args.owner_id,
             involved_id: None,
             delta,
-            cause: "MINT".to_string(),
+            cause: coin::CoinEventCause::Mint,
             memo: None,
         };
-        return Ok(vec![coin::build_event(base, custom).await?]);
+        return Ok(vec![coin::build_event(settings, base, custom)]);
     }
 
     // MINT produces 1 event, where involved_account_id is NULL.
@@ -142,17 +288,51 @@ async fn process_tkn_near_functions(
             affected_id: outcome.receipt.predecessor_id.clone(),
             involved_id: None,
             delta,
-            cause: "MINT".to_string(),
+            cause: coin::CoinEventCause::Mint,
             memo: None,
         };
-        return Ok(vec![coin::build_event(base, custom).await?]);
+        return Ok(vec![coin::build_event(settings, base, custom)]);
+    }
+
+    // Privileged method of some tokens, mints `amount` to the given account
+    if method_name == "ft_mint" {
+        let ft_mint_args = match legacy::decode_args::<FtMint>(&decoded_args, args_encoding) {
+            Ok(x) => x,
+            Err(err) => {
+                match outcome.execution_outcome.outcome.status {
+                    // We couldn't parse args for failed receipt. Let's just ignore it, we can't save it properly
+                    ExecutionStatusView::Unknown | ExecutionStatusView::Failure(_) => {
+                        return Ok(vec![])
+                    }
+                    ExecutionStatusView::SuccessValue(_)
+                    | ExecutionStatusView::SuccessReceiptId(_) => {
+                        legacy::on_args_parse_error(settings, err, outcome, method_name)?;
+                        return Ok(vec![]);
+                    }
+                }
+            }
+        };
+        let delta = BigDecimal::from_str(&ft_mint_args.amount.0.to_string())?;
+        let base = db_adapters::get_base(Event::TknNear, outcome, block_header)?;
+        let custom = coin::FtEvent {
+            affected_id: ft_mint_args.account_id,
+            involved_id: None,
+            delta,
+            cause: coin::CoinEventCause::Mint,
+            memo: ft_mint_args
+                .memo
+                .as_ref()
+                .map(|s| db_adapters::events::prepare_memo(settings, s)),
+        };
+        return Ok(vec![coin::build_event(settings, base, custom)]);
     }
 
     // TRANSFER produces 2 events
     // 1. affected_account_id is sender, delta is negative, absolute_amount decreased
     // 2. affected_account_id is receiver, delta is positive, absolute_amount increased
     if method_name == "ft_transfer" || method_name == "ft_transfer_call" {
-        let ft_transfer_args = match serde_json::from_slice::<FtTransfer>(&decoded_args) {
+        let ft_transfer_args = match legacy::decode_args::<FtTransfer>(&decoded_args, args_encoding)
+        {
             Ok(x) => x,
             Err(err) => {
                 match outcome.execution_outcome.outcome.status {
@@ -162,7 +342,8 @@ async fn process_tkn_near_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err, outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -173,14 +354,16 @@ async fn process_tkn_near_functions(
         let memo = ft_transfer_args
             .memo
             .as_ref()
-            .map(|s| s.escape_default().to_string());
+            .map(|s| db_adapters::events::prepare_memo(settings, s));
+
+        legacy::remember_transfer_call(outcome, method_name, &ft_transfer_args.receiver_id, &delta);
 
         let base_from = db_adapters::get_base(Event::TknNear, outcome, block_header)?;
         let custom_from = coin::FtEvent {
             affected_id: outcome.receipt.predecessor_id.clone(),
             involved_id: Some(ft_transfer_args.receiver_id.clone()),
             delta: negative_delta,
-            cause: "TRANSFER".to_string(),
+            cause: coin::CoinEventCause::Transfer,
             memo: memo.clone(),
         };
 
@@ -189,12 +372,12 @@ async fn process_tkn_near_functions(
             affected_id: ft_transfer_args.receiver_id,
             involved_id: Some(outcome.receipt.predecessor_id.clone()),
             delta,
-            cause: "TRANSFER".to_string(),
+            cause: coin::CoinEventCause::Transfer,
             memo,
         };
         return Ok(vec![
-            coin::build_event(base_from, custom_from).await?,
-            coin::build_event(base_to, custom_to).await?,
+            coin::build_event(settings, base_from, custom_from),
+            coin::build_event(settings, base_to, custom_to),
         ]);
     }
 
@@ -204,7 +387,7 @@ async fn process_tkn_near_functions(
             // ft_transfer_call was successful, there's nothing to return back
             return Ok(vec![]);
         }
-        let ft_refund_args = match serde_json::from_slice::<FtRefund>(&decoded_args) {
+        let ft_refund_args = match legacy::decode_args::<FtRefund>(&decoded_args, args_encoding) {
             Ok(x) => x,
             Err(err) => {
                 match outcome.execution_outcome.outcome.status {
@@ -214,7 +397,8 @@ async fn process_tkn_near_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err, outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -227,67 +411,92 @@ async fn process_tkn_near_functions(
         {
             let transferred_amount =
                 serde_json::from_slice::<String>(&base64::decode(transferred_amount_decoded)?)?;
-            delta = delta.sub(BigDecimal::from_str(&transferred_amount)?);
+            delta = delta.sub(numeric_types::parse_amount(
+                &transferred_amount,
+                &outcome.receipt.receipt_id,
+            )?);
         }
         let negative_delta = delta.clone().mul(BigDecimal::from(-1));
         let memo = ft_refund_args
             .memo
             .as_ref()
-            .map(|s| s.escape_default().to_string());
-
-        for log in &outcome.execution_outcome.outcome.logs {
-            if log == "The account of the sender was deleted" {
-                // I never met this case so it's better to re-check it manually when we find it
-                tracing::error!(
-                    target: crate::LOGGING_PREFIX,
-                    "The account of the sender was deleted {}",
-                    block_header.height
-                );
+            .map(|s| db_adapters::events::prepare_memo(settings, s));
 
-                // we should revert ft_transfer_call, but there's no receiver_id. We should burn tokens
-                let base = db_adapters::get_base(Event::TknNear, outcome, block_header)?;
-                let custom = coin::FtEvent {
-                    affected_id: ft_refund_args.receiver_id,
-                    involved_id: None,
-                    delta: negative_delta,
-                    cause: "BURN".to_string(),
-                    memo,
-                };
-                return Ok(vec![coin::build_event(base, custom).await?]);
+        let refund_log = match legacy::find_refund_log(outcome) {
+            Ok(Some(log)) => log,
+            Ok(None) => {
+                legacy::check_missing_refund(outcome, &delta);
+                return Ok(vec![]);
             }
-            if log.starts_with("Refund ") {
-                // we should revert ft_transfer_call
-                let base_from = db_adapters::get_base(Event::TknNear, outcome, block_header)?;
-                let custom_from = coin::FtEvent {
-                    affected_id: ft_refund_args.receiver_id.clone(),
-                    involved_id: Some(ft_refund_args.sender_id.clone()),
-                    delta: negative_delta,
-                    cause: "TRANSFER".to_string(),
-                    memo: memo.clone(),
-                };
-
-                let base_to = db_adapters::get_base(Event::TknNear, outcome, block_header)?;
-                let custom_to = coin::FtEvent {
-                    affected_id: ft_refund_args.sender_id,
-                    involved_id: Some(ft_refund_args.receiver_id),
-                    delta,
-                    cause: "TRANSFER".to_string(),
-                    memo,
-                };
-
-                return Ok(vec![
-                    coin::build_event(base_from, custom_from).await?,
-                    coin::build_event(base_to, custom_to).await?,
-                ]);
+            Err(err) => {
+                legacy::on_unexpected_logs(settings, err, outcome, method_name)?;
+                return Ok(vec![]);
             }
+        };
+
+        if refund_log == legacy::SENDER_DELETED_LOG {
+            // I never met this case so it's better to re-check it manually when we find it
+            tracing::error!(
+                target: crate::LOGGING_PREFIX,
+                block_height = block_header.height,
+                receipt_id = %outcome.receipt.receipt_id,
+                "The account of the sender was deleted",
+            );
+
+            // we should revert ft_transfer_call, but there's no receiver_id. We should burn tokens
+            let base = db_adapters::get_base(Event::TknNear, outcome, block_header)?;
+            let custom = coin::FtEvent {
+                affected_id: ft_refund_args.receiver_id,
+                involved_id: None,
+                delta: negative_delta,
+                cause: coin::CoinEventCause::Burn,
+                memo,
+            };
+            return Ok(vec![coin::build_event(settings, base, custom)]);
         }
-        return Ok(vec![]);
+
+        legacy::check_refund_log(
+            outcome,
+            refund_log,
+            &delta,
+            &ft_refund_args.receiver_id,
+            &ft_refund_args.sender_id,
+        );
+        legacy::check_refund_matches_transfer_call(
+            outcome,
+            &delta,
+            &ft_refund_args.receiver_id,
+            &ft_refund_args.sender_id,
+        );
+        // we should revert ft_transfer_call
+        let base_from = db_adapters::get_base(Event::TknNear, outcome, block_header)?;
+        let custom_from = coin::FtEvent {
+            affected_id: ft_refund_args.receiver_id.clone(),
+            involved_id: Some(ft_refund_args.sender_id.clone()),
+            delta: negative_delta,
+            cause: coin::CoinEventCause::Transfer,
+            memo: memo.clone(),
+        };
+
+        let base_to = db_adapters::get_base(Event::TknNear, outcome, block_header)?;
+        let custom_to = coin::FtEvent {
+            affected_id: ft_refund_args.sender_id,
+            involved_id: Some(ft_refund_args.receiver_id),
+            delta,
+            cause: coin::CoinEventCause::Transfer,
+            memo,
+        };
+
+        return Ok(vec![
+            coin::build_event(settings, base_from, custom_from),
+            coin::build_event(settings, base_to, custom_to),
+        ]);
     }
 
     // BURN produces 1 event, where involved_account_id is NULL
     // I've seen no burn events, but if someone calls it, it should be like this
     if method_name == "near_withdraw" {
-        let ft_burn_args = match serde_json::from_slice::<NearWithdraw>(&decoded_args) {
+        let ft_burn_args = match legacy::decode_args::<NearWithdraw>(&decoded_args, args_encoding) {
             Ok(x) => x,
             Err(err) => {
                 match outcome.execution_outcome.outcome.status {
@@ -297,7 +506,8 @@ async fn process_tkn_near_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err, outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -310,18 +520,418 @@ async fn process_tkn_near_functions(
             affected_id: outcome.receipt.predecessor_id.clone(),
             involved_id: None,
             delta: negative_delta,
-            cause: "BURN".to_string(),
+            cause: coin::CoinEventCause::Burn,
             memo: None,
         };
-        return Ok(vec![coin::build_event(base, custom).await?]);
+        return Ok(vec![coin::build_event(settings, base, custom)]);
     }
 
     tracing::error!(
         target: crate::LOGGING_PREFIX,
-        "TKN {} new method found: {}, receipt {}",
-        block_header.height,
-        method_name,
-        outcome.receipt.receipt_id
+        block_height = block_header.height,
+        receipt_id = %outcome.receipt.receipt_id,
+        contract_id = %outcome.receipt.receiver_id,
+        method_name = %db_adapters::sanitize_for_log(method_name),
+        "TKN new method found",
     );
+    legacy::on_unknown_method(outcome, method_name);
     Ok(vec![])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use num_traits::Zero;
+    use std::collections::HashMap;
+
+    const TOKEN: &str = "cutover.tkn.near";
+
+    fn ft_transfer(
+        seed: &str,
+        logs: &[&str],
+    ) -> near_indexer_primitives::IndexerExecutionOutcomeWithReceipt {
+        test_utils::function_call_outcome(
+            seed,
+            "alice.near",
+            TOKEN,
+            "ft_transfer",
+            br#"{"receiver_id":"bob.near","amount":"10"}"#,
+            logs,
+        )
+    }
+
+    async fn collect(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        block_height: u64,
+        outcome: near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
+    ) -> Vec<CoinEvent> {
+        collect_tkn_near(
+            Some(pool),
+            &IndexerSettings::default(),
+            &0,
+            &[outcome],
+            &test_utils::block_header(block_height),
+            "mainnet",
+        )
+        .await
+        .unwrap()
+    }
+
+    fn forget_cutovers() {
+        NEP141_CUTOVERS
+            .write()
+            .unwrap()
+            .remove(&TOKEN.parse::<AccountId>().unwrap());
+    }
+
+    async fn stored_cutover(pool: &sqlx::Pool<sqlx::Postgres>) -> Option<BigDecimal> {
+        sqlx::query_scalar(
+            "SELECT block_height FROM nep141_cutovers WHERE contract_account_id = $1",
+        )
+        .bind(TOKEN)
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn legacy_token_transitions_to_nep141_events() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        forget_cutovers();
+        let transfer_log = r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_transfer","data":[{"old_owner_id":"alice.near","new_owner_id":"bob.near","amount":"10"}]}"#;
+
+        // Legacy transfer, no events yet
+        assert_eq!(
+            collect(&pool, 860_010, ft_transfer("legacy", &[]))
+                .await
+                .len(),
+            2
+        );
+        // The first NEP-141 event, the NEP-141 collector takes it
+        assert!(
+            collect(&pool, 860_020, ft_transfer("nep141", &[transfer_log]))
+                .await
+                .is_empty()
+        );
+        assert_eq!(stored_cutover(&pool).await, Some(BigDecimal::from(860_020)));
+
+        // After the restart the cutover is read from the DB
+        forget_cutovers();
+        assert!(collect(&pool, 860_030, ft_transfer("no events", &[]))
+            .await
+            .is_empty());
+        // The blocks before the cutover are still legacy ones
+        assert_eq!(
+            collect(&pool, 860_010, ft_transfer("legacy", &[]))
+                .await
+                .len(),
+            2
+        );
+
+        // Reprocessing the earlier block with NEP-141 event moves the cutover back
+        forget_cutovers();
+        assert!(
+            collect(&pool, 860_005, ft_transfer("earlier", &[transfer_log]))
+                .await
+                .is_empty()
+        );
+        assert_eq!(stored_cutover(&pool).await, Some(BigDecimal::from(860_005)));
+        assert!(collect(&pool, 860_010, ft_transfer("legacy", &[]))
+            .await
+            .is_empty());
+        forget_cutovers();
+    }
+
+    #[derive(borsh::BorshSerialize)]
+    struct BorshFtTransfer {
+        receiver_id: AccountId,
+        amount: numeric_types::U128,
+        memo: Option<String>,
+    }
+
+    async fn collect_transfer(
+        settings: &IndexerSettings,
+        contract_id: &str,
+        args: &[u8],
+    ) -> anyhow::Result<Vec<CoinEvent>> {
+        let outcome = test_utils::function_call_outcome(
+            contract_id,
+            "alice.near",
+            contract_id,
+            "ft_transfer",
+            args,
+            &[],
+        );
+        collect_tkn_near(
+            None,
+            settings,
+            &0,
+            &[outcome],
+            &test_utils::block_header(802_001),
+            "mainnet",
+        )
+        .await
+    }
+
+    fn deltas(events: &[CoinEvent]) -> Vec<(String, BigDecimal)> {
+        events
+            .iter()
+            .map(|event| {
+                (
+                    event.affected_account_id.clone(),
+                    event.delta_amount.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn ft_transfer_args_are_decoded_with_contract_encoding() {
+        let settings = IndexerSettings {
+            borsh_args_contracts: ["borsh.tkn.near".parse().unwrap()].into_iter().collect(),
+            ..IndexerSettings::default()
+        };
+        let json_args = br#"{"receiver_id":"bob.near","amount":"10"}"#;
+        let borsh_args = borsh::BorshSerialize::try_to_vec(&BorshFtTransfer {
+            receiver_id: "bob.near".parse().unwrap(),
+            amount: numeric_types::U128(10),
+            memo: None,
+        })
+        .unwrap();
+        let expected = vec![
+            ("alice.near".to_string(), BigDecimal::from(-10)),
+            ("bob.near".to_string(), BigDecimal::from(10)),
+        ];
+
+        let json_events = collect_transfer(&settings, "json.tkn.near", json_args).await;
+        let borsh_events = collect_transfer(&settings, "borsh.tkn.near", &borsh_args).await;
+        // JSON args of the Borsh contract are not valid, the successful receipt can't be skipped silently
+        let mismatched_events = collect_transfer(&settings, "borsh.tkn.near", json_args).await;
+
+        assert_eq!(deltas(&json_events.unwrap()), expected);
+        assert_eq!(deltas(&borsh_events.unwrap()), expected);
+        assert!(mismatched_events.is_err());
+    }
+
+    #[tokio::test]
+    async fn fully_refunded_transfer_call_nets_to_zero() {
+        let contract_id = "refund.tkn.near";
+        let mut resolve = test_utils::function_call_outcome(
+            "resolve",
+            contract_id,
+            contract_id,
+            "ft_resolve_transfer",
+            br#"{"sender_id":"alice.near","receiver_id":"bob.near","amount":"10"}"#,
+            &["Refund 10 from bob.near to alice.near"],
+        );
+        // ft_on_transfer returned all the tokens back
+        resolve.execution_outcome.outcome.status =
+            ExecutionStatusView::SuccessValue(base64::encode(r#""0""#));
+        let mut transfer_call = test_utils::function_call_outcome(
+            "transfer call",
+            "alice.near",
+            contract_id,
+            "ft_transfer_call",
+            br#"{"receiver_id":"bob.near","amount":"10","msg":""}"#,
+            &[],
+        );
+        transfer_call.execution_outcome.outcome.receipt_ids = vec![resolve.receipt.receipt_id];
+
+        let mut events = vec![];
+        for (block_height, outcome) in [(846_001, transfer_call), (846_003, resolve.clone())] {
+            events.extend(
+                collect_tkn_near(
+                    None,
+                    &IndexerSettings::default(),
+                    &0,
+                    &[outcome],
+                    &test_utils::block_header(block_height),
+                    "mainnet",
+                )
+                .await
+                .unwrap(),
+            );
+        }
+
+        assert_eq!(events.len(), 4);
+        let mut net_deltas: HashMap<&str, BigDecimal> = HashMap::new();
+        for event in &events {
+            *net_deltas
+                .entry(event.affected_account_id.as_str())
+                .or_insert_with(BigDecimal::zero) += &event.delta_amount;
+        }
+        assert_eq!(net_deltas.len(), 2);
+        assert!(net_deltas.values().all(BigDecimal::is_zero));
+
+        // The refund can't exceed the originating transfer
+        let mut transfer_call = test_utils::function_call_outcome(
+            "transfer call",
+            "alice.near",
+            contract_id,
+            "ft_transfer_call",
+            br#"{"receiver_id":"bob.near","amount":"10","msg":""}"#,
+            &[],
+        );
+        transfer_call.execution_outcome.outcome.receipt_ids = vec![resolve.receipt.receipt_id];
+        legacy::remember_transfer_call(
+            &transfer_call,
+            "ft_transfer_call",
+            &"bob.near".parse().unwrap(),
+            &BigDecimal::from(10),
+        );
+        assert!(!legacy::check_refund_matches_transfer_call(
+            &resolve,
+            &BigDecimal::from(11),
+            &"bob.near".parse().unwrap(),
+            &"alice.near".parse().unwrap(),
+        ));
+    }
+
+    fn resolve_transfer(
+        logs: &[&str],
+    ) -> near_indexer_primitives::IndexerExecutionOutcomeWithReceipt {
+        let mut outcome = test_utils::function_call_outcome(
+            &logs.join("\n"),
+            "logs.tkn.near",
+            "logs.tkn.near",
+            "ft_resolve_transfer",
+            br#"{"sender_id":"alice.near","receiver_id":"bob.near","amount":"10"}"#,
+            logs,
+        );
+        outcome.execution_outcome.outcome.status =
+            ExecutionStatusView::SuccessValue(base64::encode(r#""4""#));
+        outcome
+    }
+
+    async fn collect_resolve(
+        settings: &IndexerSettings,
+        logs: &[&str],
+    ) -> anyhow::Result<Vec<CoinEvent>> {
+        collect_tkn_near(
+            None,
+            settings,
+            &0,
+            &[resolve_transfer(logs)],
+            &test_utils::block_header(800_001),
+            "mainnet",
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn refund_is_found_among_interleaved_logs() {
+        let expected = vec![
+            ("bob.near".to_string(), BigDecimal::from(-6)),
+            ("alice.near".to_string(), BigDecimal::from(6)),
+        ];
+
+        for logs in [
+            &["Refund 6 from bob.near to alice.near", "Some debug output"][..],
+            &["Some debug output", "Refund 6 from bob.near to alice.near"],
+            &[
+                "Some debug output",
+                "Refund 6 from bob.near to alice.near",
+                "More debug output",
+            ],
+        ] {
+            assert_eq!(
+                deltas(
+                    &collect_resolve(&IndexerSettings::default(), logs)
+                        .await
+                        .unwrap()
+                ),
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn ambiguous_refund_logs_follow_tolerant_parsing() {
+        let logs = [
+            "Refund 6 from bob.near to alice.near",
+            "Some debug output",
+            legacy::SENDER_DELETED_LOG,
+        ];
+
+        assert!(collect_resolve(&IndexerSettings::default(), &logs)
+            .await
+            .is_err());
+
+        let tolerant_settings = IndexerSettings {
+            tolerant_parsing: true,
+            ..IndexerSettings::default()
+        };
+        let tolerant_events = collect_resolve(&tolerant_settings, &logs).await;
+        assert!(tolerant_events.unwrap().is_empty());
+    }
+
+    #[test]
+    fn factory_suffix_follows_the_chain() {
+        let cases = [
+            ("mainnet", "abc.tkn.near", true),
+            ("mainnet", "abc.tkn.testnet", false),
+            ("testnet", "abc.tkn.testnet", true),
+            ("testnet", "abc.tkn.near", false),
+            ("mainnet", "foo.tkn.nearx", false),
+            ("testnet", "foo.tkn.testnetx", false),
+        ];
+        for (chain_id, contract_id, expected) in cases {
+            let matcher =
+                legacy::ContractMatcher::new(tkn_contract_suffix(chain_id), legacy::is_token_name);
+            assert_eq!(
+                matcher.matches(contract_id),
+                expected,
+                "{} on {}",
+                contract_id,
+                chain_id
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn near_deposit_without_attached_near_is_not_an_event() {
+        let near_deposit = |deposit| {
+            let mut outcome = test_utils::function_call_outcome(
+                "near_deposit",
+                "alice.near",
+                "wrapped.tkn.near",
+                "near_deposit",
+                b"{}",
+                &[],
+            );
+            if let ReceiptEnumView::Action { actions, .. } = &mut outcome.receipt.receipt {
+                if let ActionView::FunctionCall { deposit: d, .. } = &mut actions[0] {
+                    *d = deposit;
+                }
+            }
+            outcome
+        };
+
+        let mut deltas = vec![];
+        for deposit in [0, 5] {
+            let events = collect_tkn_near(
+                None,
+                &IndexerSettings::default(),
+                &0,
+                &[near_deposit(deposit)],
+                &test_utils::block_header(832_001),
+                "mainnet",
+            )
+            .await
+            .unwrap();
+            deltas.push(
+                events
+                    .iter()
+                    .map(|event| event.delta_amount.clone())
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        assert_eq!(deltas, vec![vec![], vec![BigDecimal::from(5)]]);
+    }
+}
@@ -1,6 +1,8 @@
 use crate::db_adapters;
+use crate::db_adapters::coin::legacy;
 use crate::db_adapters::{coin, numeric_types, Event};
 use crate::models::coin_events::CoinEvent;
+use crate::settings::IndexerSettings;
 use bigdecimal::BigDecimal;
 use near_lake_framework::near_indexer_primitives;
 use near_primitives::types::AccountId;
@@ -36,6 +38,7 @@ struct NearWithdraw {
 }
 
 pub(crate) async fn collect_wentokensir(
+    settings: &IndexerSettings,
     shard_id: &near_indexer_primitives::types::ShardId,
     receipt_execution_outcomes: &[near_indexer_primitives::IndexerExecutionOutcomeWithReceipt],
     block_header: &near_indexer_primitives::views::BlockHeaderView,
@@ -44,13 +47,15 @@ pub(crate) async fn collect_wentokensir(
 
     for outcome in receipt_execution_outcomes {
         if !is_wentokensir_contract(outcome.receipt.receiver_id.as_str())
-            || !db_adapters::events::extract_events(outcome).is_empty()
+            || !db_adapters::events::extract_events(settings, outcome).is_empty()
         {
             continue;
         }
         if let ReceiptEnumView::Action { actions, .. } = &outcome.receipt.receipt {
             for action in actions {
-                events.extend(process_wentokensir_functions(block_header, action, outcome).await?);
+                events.extend(
+                    process_wentokensir_functions(settings, block_header, action, outcome).await?,
+                );
             }
         }
     }
@@ -65,17 +70,15 @@ pub(crate) async fn collect_wentokensir(
 }
 
 fn is_wentokensir_contract(contract_id: &str) -> bool {
-    if let Some(contract_prefix) = contract_id.strip_suffix(".wentokensir.near") {
-        lazy_static::lazy_static! {
-            static ref RE: regex::Regex = regex::Regex::new(r"^[a-z0-9\-]+$").unwrap();
-        }
-        RE.is_match(contract_prefix)
-    } else {
-        false
+    lazy_static::lazy_static! {
+        static ref MATCHER: legacy::ContractMatcher =
+            legacy::ContractMatcher::new(".wentokensir.near", legacy::is_token_name);
     }
+    MATCHER.matches(contract_id)
 }
 
 async fn process_wentokensir_functions(
+    settings: &IndexerSettings,
     block_header: &near_indexer_primitives::views::BlockHeaderView,
     action: &ActionView,
     outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
@@ -92,7 +95,7 @@ async fn process_wentokensir_functions(
 
     let decoded_args = base64::decode(args)?;
 
-    if vec![
+    if [
         "storage_deposit",
         "new",
         "on_ft_metadata",
@@ -113,10 +116,10 @@ async fn process_wentokensir_functions(
             affected_id: outcome.receipt.predecessor_id.clone(),
             involved_id: None,
             delta,
-            cause: "MINT".to_string(),
+            cause: coin::CoinEventCause::Mint,
             memo: None,
         };
-        return Ok(vec![coin::build_event(base, custom).await?]);
+        return Ok(vec![coin::build_event(settings, base, custom)]);
     }
 
     // other way to make MINT
@@ -131,7 +134,8 @@ async fn process_wentokensir_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -142,10 +146,10 @@ async fn process_wentokensir_functions(
             affected_id: args.sender_id,
             involved_id: None,
             delta,
-            cause: "MINT".to_string(),
+            cause: coin::CoinEventCause::Mint,
             memo: None,
         };
-        return Ok(vec![coin::build_event(base, custom).await?]);
+        return Ok(vec![coin::build_event(settings, base, custom)]);
     }
 
     // TRANSFER produces 2 events
@@ -162,7 +166,8 @@ async fn process_wentokensir_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -173,14 +178,16 @@ async fn process_wentokensir_functions(
         let memo = ft_transfer_args
             .memo
             .as_ref()
-            .map(|s| s.escape_default().to_string());
+            .map(|s| db_adapters::events::prepare_memo(settings, s));
+
+        legacy::remember_transfer_call(outcome, method_name, &ft_transfer_args.receiver_id, &delta);
 
         let base_from = db_adapters::get_base(Event::Wentokensir, outcome, block_header)?;
         let custom_from = coin::FtEvent {
             affected_id: outcome.receipt.predecessor_id.clone(),
             involved_id: Some(ft_transfer_args.receiver_id.clone()),
             delta: negative_delta,
-            cause: "TRANSFER".to_string(),
+            cause: coin::CoinEventCause::Transfer,
             memo: memo.clone(),
         };
 
@@ -189,12 +196,12 @@ async fn process_wentokensir_functions(
             affected_id: ft_transfer_args.receiver_id,
             involved_id: Some(outcome.receipt.predecessor_id.clone()),
             delta,
-            cause: "TRANSFER".to_string(),
+            cause: coin::CoinEventCause::Transfer,
             memo,
         };
         return Ok(vec![
-            coin::build_event(base_from, custom_from).await?,
-            coin::build_event(base_to, custom_to).await?,
+            coin::build_event(settings, base_from, custom_from),
+            coin::build_event(settings, base_to, custom_to),
         ]);
     }
 
@@ -214,7 +221,8 @@ async fn process_wentokensir_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -233,55 +241,77 @@ async fn process_wentokensir_functions(
         let memo = ft_refund_args
             .memo
             .as_ref()
-            .map(|s| s.escape_default().to_string());
+            .map(|s| db_adapters::events::prepare_memo(settings, s));
 
-        for log in &outcome.execution_outcome.outcome.logs {
-            if log == "The account of the sender was deleted" {
-                // I never met this case so it's better to re-check it manually when we find it
-                tracing::error!(
-                    target: crate::LOGGING_PREFIX,
-                    "The account of the sender was deleted {}",
-                    block_header.height
-                );
-
-                // we should revert ft_transfer_call, but there's no receiver_id. We should burn tokens
-                let base = db_adapters::get_base(Event::Wentokensir, outcome, block_header)?;
-                let custom = coin::FtEvent {
-                    affected_id: ft_refund_args.receiver_id,
-                    involved_id: None,
-                    delta: negative_delta,
-                    cause: "BURN".to_string(),
-                    memo,
-                };
-                return Ok(vec![coin::build_event(base, custom).await?]);
+        let refund_log = match legacy::find_refund_log(outcome) {
+            Ok(Some(log)) => log,
+            Ok(None) => {
+                legacy::check_missing_refund(outcome, &delta);
+                return Ok(vec![]);
+            }
+            Err(err) => {
+                legacy::on_unexpected_logs(settings, err, outcome, method_name)?;
+                return Ok(vec![]);
             }
-            if log.starts_with("Refund ") {
-                // we should revert ft_transfer_call
-                let base_from = db_adapters::get_base(Event::Wentokensir, outcome, block_header)?;
-                let custom_from = coin::FtEvent {
-                    affected_id: ft_refund_args.receiver_id.clone(),
-                    involved_id: Some(ft_refund_args.sender_id.clone()),
-                    delta: negative_delta,
-                    cause: "TRANSFER".to_string(),
-                    memo: memo.clone(),
-                };
+        };
 
-                let base_to = db_adapters::get_base(Event::Wentokensir, outcome, block_header)?;
-                let custom_to = coin::FtEvent {
-                    affected_id: ft_refund_args.sender_id,
-                    involved_id: Some(ft_refund_args.receiver_id),
-                    delta,
-                    cause: "TRANSFER".to_string(),
-                    memo,
-                };
+        if refund_log == legacy::SENDER_DELETED_LOG {
+            // I never met this case so it's better to re-check it manually when we find it
+            tracing::error!(
+                target: crate::LOGGING_PREFIX,
+                block_height = block_header.height,
+                receipt_id = %outcome.receipt.receipt_id,
+                "The account of the sender was deleted",
+            );
 
-                return Ok(vec![
-                    coin::build_event(base_from, custom_from).await?,
-                    coin::build_event(base_to, custom_to).await?,
-                ]);
-            }
+            // we should revert ft_transfer_call, but there's no receiver_id. We should burn tokens
+            let base = db_adapters::get_base(Event::Wentokensir, outcome, block_header)?;
+            let custom = coin::FtEvent {
+                affected_id: ft_refund_args.receiver_id,
+                involved_id: None,
+                delta: negative_delta,
+                cause: coin::CoinEventCause::Burn,
+                memo,
+            };
+            return Ok(vec![coin::build_event(settings, base, custom)]);
         }
-        return Ok(vec![]);
+
+        legacy::check_refund_log(
+            outcome,
+            refund_log,
+            &delta,
+            &ft_refund_args.receiver_id,
+            &ft_refund_args.sender_id,
+        );
+        legacy::check_refund_matches_transfer_call(
+            outcome,
+            &delta,
+            &ft_refund_args.receiver_id,
+            &ft_refund_args.sender_id,
+        );
+        // we should revert ft_transfer_call
+        let base_from = db_adapters::get_base(Event::Wentokensir, outcome, block_header)?;
+        let custom_from = coin::FtEvent {
+            affected_id: ft_refund_args.receiver_id.clone(),
+            involved_id: Some(ft_refund_args.sender_id.clone()),
+            delta: negative_delta,
+            cause: coin::CoinEventCause::Transfer,
+            memo: memo.clone(),
+        };
+
+        let base_to = db_adapters::get_base(Event::Wentokensir, outcome, block_header)?;
+        let custom_to = coin::FtEvent {
+            affected_id: ft_refund_args.sender_id,
+            involved_id: Some(ft_refund_args.receiver_id),
+            delta,
+            cause: coin::CoinEventCause::Transfer,
+            memo,
+        };
+
+        return Ok(vec![
+            coin::build_event(settings, base_from, custom_from),
+            coin::build_event(settings, base_to, custom_to),
+        ]);
     }
 
     // BURN produces 1 event, where involved_account_id is NULL
@@ -297,7 +327,8 @@ async fn process_wentokensir_functions(
                     }
                     ExecutionStatusView::SuccessValue(_)
                     | ExecutionStatusView::SuccessReceiptId(_) => {
-                        anyhow::bail!(err)
+                        legacy::on_args_parse_error(settings, err.into(), outcome, method_name)?;
+                        return Ok(vec![]);
                     }
                 }
             }
@@ -310,18 +341,20 @@ async fn process_wentokensir_functions(
             affected_id: outcome.receipt.predecessor_id.clone(),
             involved_id: None,
             delta: negative_delta,
-            cause: "BURN".to_string(),
+            cause: coin::CoinEventCause::Burn,
             memo: None,
         };
-        return Ok(vec![coin::build_event(base, custom).await?]);
+        return Ok(vec![coin::build_event(settings, base, custom)]);
     }
 
     tracing::error!(
         target: crate::LOGGING_PREFIX,
-        "WENTOKENSIR {} new method found: {}, receipt {}",
-        block_header.height,
-        method_name,
-        outcome.receipt.receipt_id
+        block_height = block_header.height,
+        receipt_id = %outcome.receipt.receipt_id,
+        contract_id = %outcome.receipt.receiver_id,
+        method_name = %db_adapters::sanitize_for_log(method_name),
+        "WENTOKENSIR new method found",
     );
+    legacy::on_unknown_method(outcome, method_name);
     Ok(vec![])
 }
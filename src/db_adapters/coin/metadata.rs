@@ -0,0 +1,215 @@
+use crate::models;
+use crate::models::coin_events::CoinEvent;
+use crate::models::contract_metadata::ContractMetadata;
+use crate::rpc_helpers;
+use crate::settings::IndexerSettings;
+use bigdecimal::BigDecimal;
+use near_jsonrpc_client::JsonRpcClient;
+use near_primitives::types::AccountId;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Fetches `ft_metadata` once per contract and stores it to `contract_metadata` table
+pub struct ContractMetadataCache {
+    rpc_client: JsonRpcClient,
+    // The contracts which already have the row in the DB
+    known_contracts: Mutex<HashSet<String>>,
+}
+
+impl ContractMetadataCache {
+    pub fn new(rpc_url: &str) -> Self {
+        Self {
+            rpc_client: JsonRpcClient::connect(rpc_url),
+            known_contracts: Mutex::new(HashSet::new()),
+        }
+    }
+
+    async fn ensure_fetched(
+        &self,
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        contract_id: &str,
+        block_height: u64,
+        rpc_budget: &mut rpc_helpers::RpcBudget,
+    ) -> anyhow::Result<()> {
+        if self.is_known(contract_id) {
+            return Ok(());
+        }
+        // The cache is empty after the restart, so we check the DB before going to RPC
+        let stored: Option<(String,)> = sqlx::query_as(
+            "SELECT contract_account_id FROM contract_metadata WHERE contract_account_id = $1",
+        )
+        .bind(contract_id)
+        .fetch_optional(pool)
+        .await?;
+
+        if stored.is_none() {
+            // The contract stays unknown, so the metadata is fetched when the contract appears again
+            if !rpc_budget.try_spend() {
+                return Ok(());
+            }
+            let metadata = rpc_helpers::get_ft_metadata(
+                &self.rpc_client,
+                &AccountId::from_str(contract_id)?,
+                block_height,
+            )
+            .await?;
+            let row = ContractMetadata {
+                contract_account_id: contract_id.to_string(),
+                spec: metadata.as_ref().map(|m| m.spec.clone()),
+                name: metadata.as_ref().map(|m| m.name.clone()),
+                symbol: metadata.as_ref().map(|m| m.symbol.clone()),
+                decimals: metadata.as_ref().map(|m| i32::from(m.decimals)),
+                fetched_at_block_height: BigDecimal::from(block_height),
+            };
+            models::chunked_insert(pool, &[row]).await?;
+        }
+        self.known_contracts
+            .lock()
+            .expect("Known contracts lock is poisoned")
+            .insert(contract_id.to_string());
+        Ok(())
+    }
+
+    fn is_known(&self, contract_id: &str) -> bool {
+        self.known_contracts
+            .lock()
+            .expect("Known contracts lock is poisoned")
+            .contains(contract_id)
+    }
+}
+
+// Makes sure we have the metadata of all the contracts mentioned in the events
+pub(crate) async fn fetch_new_contracts_metadata(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    settings: &IndexerSettings,
+    events: &[CoinEvent],
+    block_height: u64,
+) -> anyhow::Result<()> {
+    let cache = match &settings.contract_metadata_cache {
+        Some(cache) => cache,
+        None => return Ok(()),
+    };
+    let contracts: HashSet<&str> = events
+        .iter()
+        .map(|event| event.contract_account_id.as_str())
+        .collect();
+    let mut rpc_budget =
+        rpc_helpers::RpcBudget::for_block(block_height, settings.max_rpc_per_block);
+    for contract_id in contracts {
+        cache
+            .ensure_fetched(pool, contract_id, block_height, &mut rpc_budget)
+            .await?;
+    }
+    rpc_budget.warn_if_exceeded();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn metadata_is_fetched_once_per_contract() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let rpc_calls = Arc::new(AtomicUsize::new(0));
+        let rpc_url = {
+            let rpc_calls = rpc_calls.clone();
+            test_utils::mock_view_rpc(move |_, method_name, _, _| {
+                assert_eq!(method_name, "ft_metadata");
+                rpc_calls.fetch_add(1, Ordering::SeqCst);
+                serde_json::json!({"spec": "ft-1.0.0", "name": "Token", "symbol": "TKN", "decimals": 18})
+            })
+        };
+        let events = [
+            test_utils::coin_event(1, 810_001, "token.near", "alice.near", 100),
+            test_utils::coin_event(2, 810_001, "token.near", "bob.near", 100),
+        ];
+        let settings_with_new_cache = || IndexerSettings {
+            contract_metadata_cache: Some(Arc::new(ContractMetadataCache::new(&rpc_url))),
+            ..IndexerSettings::default()
+        };
+
+        let settings = settings_with_new_cache();
+        fetch_new_contracts_metadata(&pool, &settings, &events, 810_001)
+            .await
+            .unwrap();
+        fetch_new_contracts_metadata(&pool, &settings, &events, 810_002)
+            .await
+            .unwrap();
+        // After the restart the cache is empty, the stored row is enough
+        fetch_new_contracts_metadata(&pool, &settings_with_new_cache(), &events, 810_003)
+            .await
+            .unwrap();
+
+        assert_eq!(rpc_calls.load(Ordering::SeqCst), 1);
+        let stored: (String, Option<String>, Option<i32>, BigDecimal) = sqlx::query_as(
+            "SELECT contract_account_id, symbol, decimals, fetched_at_block_height FROM contract_metadata",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            stored,
+            (
+                "token.near".to_string(),
+                Some("TKN".to_string()),
+                Some(18),
+                BigDecimal::from(810_001)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_calls_are_capped_per_block() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let rpc_calls = Arc::new(AtomicUsize::new(0));
+        let rpc_url = {
+            let rpc_calls = rpc_calls.clone();
+            test_utils::mock_view_rpc(move |_, _, _, _| {
+                rpc_calls.fetch_add(1, Ordering::SeqCst);
+                serde_json::json!({"spec": "ft-1.0.0", "name": "Token", "symbol": "TKN", "decimals": 18})
+            })
+        };
+        let events: Vec<CoinEvent> = (1..=5)
+            .map(|i| {
+                test_utils::coin_event(i, 868_001, &format!("token{}.near", i), "alice.near", 1)
+            })
+            .collect();
+        let settings = IndexerSettings {
+            contract_metadata_cache: Some(Arc::new(ContractMetadataCache::new(&rpc_url))),
+            max_rpc_per_block: Some(2),
+            ..IndexerSettings::default()
+        };
+        let exceeded_before = crate::metrics::RPC_BUDGET_EXCEEDED_TOTAL.get();
+
+        fetch_new_contracts_metadata(&pool, &settings, &events, 868_001)
+            .await
+            .unwrap();
+        assert_eq!(rpc_calls.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            crate::metrics::RPC_BUDGET_EXCEEDED_TOTAL.get() - exceeded_before,
+            3
+        );
+
+        // The deferred contracts are fetched when they appear in the next blocks
+        for block_height in [868_002, 868_003] {
+            fetch_new_contracts_metadata(&pool, &settings, &events, block_height)
+                .await
+                .unwrap();
+        }
+        assert_eq!(rpc_calls.load(Ordering::SeqCst), 5);
+        assert_eq!(test_utils::count_rows(&pool, "contract_metadata").await, 5);
+    }
+}
@@ -1,42 +1,139 @@
+use crate::db_adapters::contracts_filter::ContractsFilter;
 use crate::db_adapters::Event;
 use crate::models;
 use crate::models::coin_events::CoinEvent;
+use crate::settings::IndexerSettings;
+use crate::sinks::EventSink;
 use bigdecimal::BigDecimal;
-use futures::future::try_join_all;
 use futures::try_join;
 use near_lake_framework::near_indexer_primitives;
 use near_primitives::types::AccountId;
-use num_traits::Zero;
+use num_traits::{Signed, Zero};
+use tracing::Instrument;
 
-mod legacy;
+pub mod balances;
+pub(crate) mod legacy;
+pub mod metadata;
 mod nep141_events;
+pub mod supply;
+
+pub use balances::account_portfolio;
 
 pub const FT: &str = "FT_NEP141";
 pub const FT_LEGACY: &str = "FT_LEGACY";
 
+/// The reason of the balance change, stored to `coin_events.cause`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoinEventCause {
+    Mint,
+    Transfer,
+    // Transfer where the receiver is the token contract itself, see `IndexerSettings::classify_transfers_to_contract`
+    TransferToContract,
+    Burn,
+}
+
+impl CoinEventCause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CoinEventCause::Mint => "MINT",
+            CoinEventCause::Transfer => "TRANSFER",
+            CoinEventCause::TransferToContract => "TRANSFER_TO_CONTRACT",
+            CoinEventCause::Burn => "BURN",
+        }
+    }
+}
+
+impl std::fmt::Display for CoinEventCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Both rows of the transfer are marked: the one of the contract receiving the tokens
+// and the one of the sender, where the contract is the involved account
+fn classify_cause(
+    settings: &IndexerSettings,
+    cause: CoinEventCause,
+    contract_id: &AccountId,
+    custom: &FtEvent,
+) -> CoinEventCause {
+    if cause != CoinEventCause::Transfer || !settings.classify_transfers_to_contract {
+        return cause;
+    }
+    let is_received_by_contract = &custom.affected_id == contract_id && custom.delta.is_positive();
+    let is_sent_to_contract =
+        custom.involved_id.as_ref() == Some(contract_id) && custom.delta.is_negative();
+    if is_received_by_contract || is_sent_to_contract {
+        CoinEventCause::TransferToContract
+    } else {
+        cause
+    }
+}
+
 struct FtEvent {
     pub affected_id: AccountId,
     pub involved_id: Option<AccountId>,
     pub delta: BigDecimal,
-    pub cause: String,
+    pub cause: CoinEventCause,
     pub memo: Option<String>,
 }
 
 pub(crate) async fn store_ft(
     pool: &sqlx::Pool<sqlx::Postgres>,
+    settings: &IndexerSettings,
     streamer_message: &near_indexer_primitives::StreamerMessage,
     chain_id: &str,
-) -> anyhow::Result<()> {
+    contracts_filter: &ContractsFilter,
+    sink: &dyn EventSink,
+) -> anyhow::Result<Vec<CoinEvent>> {
     let mut events: Vec<CoinEvent> = vec![];
 
-    let events_futures = streamer_message
-        .shards
-        .iter()
-        .map(|shard| collect_ft_for_shard(streamer_message, shard, chain_id));
-    for events_by_shard in try_join_all(events_futures).await? {
+    let events_futures: Vec<_> = crate::db_adapters::ordered_shards(settings, streamer_message)
+        .into_iter()
+        .map(|shard| {
+            collect_ft_for_shard(
+                pool,
+                settings,
+                streamer_message,
+                shard,
+                chain_id,
+                contracts_filter,
+            )
+            .instrument(tracing::info_span!("shard", shard_id = shard.shard_id))
+        })
+        .collect();
+    for events_by_shard in
+        crate::db_adapters::events::try_join_all_ordered(settings, events_futures).await?
+    {
         events.extend(events_by_shard);
     }
-    models::chunked_insert(pool, &events).await
+    // The events stored by the previous attempt of the block are not inserted, counted and emitted again
+    let mut new_events = events.clone();
+    if settings.store_to_db {
+        crate::db_adapters::skip_stored_events(
+            pool,
+            &mut new_events,
+            streamer_message.block.header.height,
+            |event| &event.event_index,
+        )
+        .await?;
+        models::bulk_insert(pool, settings, &new_events).await?;
+        // The snapshots are based on all the events of the block
+        supply::store_supply_snapshots(pool, settings, &events, &streamer_message.block.header)
+            .await?;
+        balances::store_balance_snapshots(pool, settings, &events, &streamer_message.block.header)
+            .await?;
+        metadata::fetch_new_contracts_metadata(
+            pool,
+            settings,
+            &events,
+            streamer_message.block.header.height,
+        )
+        .await?;
+    }
+    crate::metrics::FT_EVENTS_TOTAL.inc_by(new_events.len() as u64);
+    sink.emit_coin_events(&new_events).await?;
+    Ok(events)
 }
 
 pub(crate) fn filter_zeros_and_enumerate_events(
@@ -54,35 +151,51 @@ pub(crate) fn filter_zeros_and_enumerate_events(
 }
 
 async fn collect_ft_for_shard(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    settings: &IndexerSettings,
     streamer_message: &near_indexer_primitives::StreamerMessage,
     shard: &near_indexer_primitives::IndexerShard,
     chain_id: &str,
+    contracts_filter: &ContractsFilter,
 ) -> anyhow::Result<Vec<CoinEvent>> {
     let mut events: Vec<CoinEvent> = vec![];
+    let receipt_execution_outcomes = crate::db_adapters::filter_outcomes_by_contracts(
+        &shard.receipt_execution_outcomes,
+        contracts_filter,
+    );
 
     let nep141_future = nep141_events::collect_nep141_events(
+        settings,
         &shard.shard_id,
-        &shard.receipt_execution_outcomes,
+        &receipt_execution_outcomes,
         &streamer_message.block.header,
     );
     let legacy_contracts_future = legacy::collect_legacy(
+        settings.store_to_db.then_some(pool),
+        settings,
         &shard.shard_id,
-        &shard.receipt_execution_outcomes,
+        &receipt_execution_outcomes,
         &streamer_message.block.header,
         chain_id,
     );
-    let (nep141_events, legacy_events) = try_join!(nep141_future, legacy_contracts_future)?;
+    let (nep141_events, legacy_events) = if settings.deterministic_ordering {
+        (nep141_future.await?, legacy_contracts_future.await?)
+    } else {
+        try_join!(nep141_future, legacy_contracts_future)?
+    };
 
     events.extend(nep141_events);
     events.extend(legacy_events);
     Ok(events)
 }
 
-async fn build_event(
+fn build_event(
+    settings: &IndexerSettings,
     base: crate::db_adapters::EventBase,
     custom: FtEvent,
-) -> anyhow::Result<CoinEvent> {
-    Ok(CoinEvent {
+) -> CoinEvent {
+    let cause = classify_cause(settings, custom.cause, &base.contract_account_id, &custom);
+    CoinEvent {
         event_index: BigDecimal::zero(), // initialized later
         standard: base.standard,
         receipt_id: base.receipt_id,
@@ -93,8 +206,113 @@ async fn build_event(
         involved_account_id: custom.involved_id.map(|id| id.to_string()),
         delta_amount: custom.delta,
         // coin_id: "".to_string(),
-        cause: custom.cause,
+        cause: cause.to_string(),
         status: crate::db_adapters::get_status(&base.status),
         event_memo: custom.memo,
-    })
+        raw_event: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn transfer_to_the_token_contract_is_marked_if_asked() {
+        let transfers = [
+            test_utils::function_call_outcome(
+                "to contract",
+                "alice.near",
+                "abc.tkn.near",
+                "ft_transfer",
+                br#"{"receiver_id":"abc.tkn.near","amount":"10"}"#,
+                &[],
+            ),
+            test_utils::function_call_outcome(
+                "to bob",
+                "alice.near",
+                "abc.tkn.near",
+                "ft_transfer",
+                br#"{"receiver_id":"bob.near","amount":"5"}"#,
+                &[],
+            ),
+        ];
+        let causes = |classify_transfers_to_contract| {
+            let transfers = &transfers;
+            async move {
+                let settings = IndexerSettings {
+                    classify_transfers_to_contract,
+                    ..IndexerSettings::default()
+                };
+                legacy::collect_legacy(
+                    None,
+                    &settings,
+                    &0,
+                    transfers,
+                    &test_utils::block_header(856_001),
+                    "mainnet",
+                )
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|event| (event.affected_account_id, event.delta_amount, event.cause))
+                .collect::<Vec<_>>()
+            }
+        };
+        let event = |account_id: &str, delta: i64, cause: CoinEventCause| {
+            (
+                account_id.to_string(),
+                BigDecimal::from(delta),
+                cause.to_string(),
+            )
+        };
+
+        assert_eq!(
+            causes(false).await,
+            vec![
+                event("alice.near", -10, CoinEventCause::Transfer),
+                event("abc.tkn.near", 10, CoinEventCause::Transfer),
+                event("alice.near", -5, CoinEventCause::Transfer),
+                event("bob.near", 5, CoinEventCause::Transfer),
+            ]
+        );
+        // Both rows of the transfer to the contract are marked, the usual transfer stays as is
+        assert_eq!(
+            causes(true).await,
+            vec![
+                event("alice.near", -10, CoinEventCause::TransferToContract),
+                event("abc.tkn.near", 10, CoinEventCause::TransferToContract),
+                event("alice.near", -5, CoinEventCause::Transfer),
+                event("bob.near", 5, CoinEventCause::Transfer),
+            ]
+        );
+    }
+
+    #[test]
+    fn causes_are_the_stored_strings() {
+        let causes = [
+            CoinEventCause::Mint,
+            CoinEventCause::Transfer,
+            CoinEventCause::TransferToContract,
+            CoinEventCause::Burn,
+        ];
+        // Fails to compile when a new cause is added, so that the list above is updated too
+        for cause in causes {
+            match cause {
+                CoinEventCause::Mint
+                | CoinEventCause::Transfer
+                | CoinEventCause::TransferToContract
+                | CoinEventCause::Burn => {}
+            }
+        }
+
+        assert_eq!(
+            causes.map(|cause| cause.as_str()),
+            ["MINT", "TRANSFER", "TRANSFER_TO_CONTRACT", "BURN"]
+        );
+        for cause in causes {
+            assert_eq!(cause.to_string(), cause.as_str());
+        }
+    }
 }
@@ -3,6 +3,7 @@ use crate::db_adapters::event_types::Nep141Event;
 use crate::db_adapters::Event;
 use crate::db_adapters::{coin, events, get_base};
 use crate::models::coin_events::CoinEvent;
+use crate::settings::IndexerSettings;
 use bigdecimal::BigDecimal;
 use near_lake_framework::near_indexer_primitives;
 use near_primitives::types::AccountId;
@@ -10,16 +11,34 @@ use std::ops::Mul;
 use std::str::FromStr;
 
 pub(crate) async fn collect_nep141_events(
+    settings: &IndexerSettings,
     shard_id: &near_indexer_primitives::types::ShardId,
     receipt_execution_outcomes: &[near_indexer_primitives::IndexerExecutionOutcomeWithReceipt],
     block_header: &near_indexer_primitives::views::BlockHeaderView,
 ) -> anyhow::Result<Vec<CoinEvent>> {
     let mut res = Vec::new();
     for outcome in receipt_execution_outcomes {
-        let events = events::extract_events(outcome);
-        for event in events {
-            if let event_types::NearEvent::Nep141(ft_events) = event {
-                res.extend(compose_db_events(&ft_events, outcome, block_header).await?);
+        for extracted_event in events::extract_events(settings, outcome) {
+            if let event_types::NearEvent::Nep141(ft_events) = &extracted_event.event {
+                if extracted_event.should_skip_as_duplicate(settings, outcome) {
+                    continue;
+                }
+                if !events::is_supported_nep141_version(settings, &ft_events.version) {
+                    tracing::warn!(
+                        target: crate::LOGGING_PREFIX,
+                        receipt_id = %outcome.receipt.receipt_id,
+                        version = %ft_events.version,
+                        "Skipping NEP-141 event of unsupported version",
+                    );
+                    continue;
+                }
+                let raw_event = events::raw_event_to_store(settings, &extracted_event.raw_log);
+                for mut event in
+                    compose_db_events(settings, ft_events, outcome, block_header).await?
+                {
+                    event.raw_event = raw_event.clone();
+                    res.push(event);
+                }
             }
         }
     }
@@ -34,6 +53,7 @@ pub(crate) async fn collect_nep141_events(
 }
 
 async fn compose_db_events(
+    settings: &IndexerSettings,
     events: &Nep141Event,
     outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
     block_header: &near_indexer_primitives::views::BlockHeaderView,
@@ -52,13 +72,13 @@ async fn compose_db_events(
                     affected_id: AccountId::from_str(&mint_event.owner_id)?,
                     involved_id: None,
                     delta: BigDecimal::from_str(&mint_event.amount)?,
-                    cause: "MINT".to_string(),
+                    cause: coin::CoinEventCause::Mint,
                     memo: mint_event
                         .memo
                         .as_ref()
-                        .map(|s| s.escape_default().to_string()),
+                        .map(|s| events::prepare_memo(settings, s)),
                 };
-                ft_events.push(coin::build_event(base, custom).await?);
+                ft_events.push(coin::build_event(settings, base, custom));
             }
         }
         event_types::Nep141EventKind::FtTransfer(transfer_events) => {
@@ -68,26 +88,26 @@ async fn compose_db_events(
                     affected_id: AccountId::from_str(&transfer_event.old_owner_id)?,
                     involved_id: Some(AccountId::from_str(&transfer_event.new_owner_id)?),
                     delta: BigDecimal::from_str(&transfer_event.amount)?.mul(BigDecimal::from(-1)),
-                    cause: "TRANSFER".to_string(),
+                    cause: coin::CoinEventCause::Transfer,
                     memo: transfer_event
                         .memo
                         .as_ref()
-                        .map(|s| s.escape_default().to_string()),
+                        .map(|s| events::prepare_memo(settings, s)),
                 };
-                ft_events.push(coin::build_event(base, custom).await?);
+                ft_events.push(coin::build_event(settings, base, custom));
 
                 let base = get_base(Event::Nep141, outcome, block_header)?;
                 let custom = coin::FtEvent {
                     affected_id: AccountId::from_str(&transfer_event.new_owner_id)?,
                     involved_id: Some(AccountId::from_str(&transfer_event.old_owner_id)?),
                     delta: BigDecimal::from_str(&transfer_event.amount)?,
-                    cause: "TRANSFER".to_string(),
+                    cause: coin::CoinEventCause::Transfer,
                     memo: transfer_event
                         .memo
                         .as_ref()
-                        .map(|s| s.escape_default().to_string()),
+                        .map(|s| events::prepare_memo(settings, s)),
                 };
-                ft_events.push(coin::build_event(base, custom).await?);
+                ft_events.push(coin::build_event(settings, base, custom));
             }
         }
         event_types::Nep141EventKind::FtBurn(burn_events) => {
@@ -97,16 +117,57 @@ async fn compose_db_events(
                     affected_id: AccountId::from_str(&burn_event.owner_id)?,
                     involved_id: None,
                     delta: BigDecimal::from_str(&burn_event.amount)?.mul(BigDecimal::from(-1)),
-                    cause: "BURN".to_string(),
+                    cause: coin::CoinEventCause::Burn,
                     memo: burn_event
                         .memo
                         .as_ref()
-                        .map(|s| s.escape_default().to_string()),
+                        .map(|s| events::prepare_memo(settings, s)),
                 };
-                ft_events.push(coin::build_event(base, custom).await?);
+                ft_events.push(coin::build_event(settings, base, custom));
             }
         }
     }
 
     Ok(ft_events)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn unsupported_versions_are_skipped() {
+        let v1_log = test_utils::ft_mint_log("alice.near", "100");
+        let v2_log = v1_log
+            .replace("1.0.0", "2.0.0")
+            .replace("\"100\"", "\"200\"");
+        let outcome = test_utils::logs_outcome("versions", "token.near", &[&v1_log, &v2_log]);
+        let collect = |settings: IndexerSettings| {
+            let outcome = outcome.clone();
+            async move {
+                collect_nep141_events(
+                    &settings,
+                    &0,
+                    &[outcome],
+                    &test_utils::block_header(794_001),
+                )
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|event| event.delta_amount)
+                .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(
+            collect(IndexerSettings::default()).await,
+            vec![BigDecimal::from(100), BigDecimal::from(200)]
+        );
+        let v1_only = IndexerSettings {
+            supported_nep141_versions: vec!["1.0.0".to_string()],
+            ..IndexerSettings::default()
+        };
+        assert_eq!(collect(v1_only).await, vec![BigDecimal::from(100)]);
+    }
+}
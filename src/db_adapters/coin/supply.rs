@@ -0,0 +1,149 @@
+use crate::db_adapters::coin::CoinEventCause;
+use crate::models::coin_events::CoinEvent;
+use crate::settings::IndexerSettings;
+use bigdecimal::BigDecimal;
+use near_lake_framework::near_indexer_primitives;
+use num_traits::Zero;
+use std::collections::HashMap;
+
+// Adds the snapshot for each contract which minted or burned something in the block.
+// The new supply is based on the previous snapshot, so the blocks should go in order
+pub(crate) async fn store_supply_snapshots(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    settings: &IndexerSettings,
+    events: &[CoinEvent],
+    block_header: &near_indexer_primitives::views::BlockHeaderView,
+) -> anyhow::Result<()> {
+    if !settings.store_supply_snapshots {
+        return Ok(());
+    }
+    let mut supply_deltas: HashMap<&str, BigDecimal> = HashMap::new();
+    for event in events {
+        if event.status == "SUCCESS"
+            && (event.cause == CoinEventCause::Mint.as_str()
+                || event.cause == CoinEventCause::Burn.as_str())
+        {
+            *supply_deltas
+                .entry(event.contract_account_id.as_str())
+                .or_insert_with(BigDecimal::zero) += &event.delta_amount;
+        }
+    }
+
+    for (contract_id, delta) in supply_deltas {
+        // ON CONFLICT keeps the block idempotent if it's processed again
+        sqlx::query(
+            "INSERT INTO contract_supply_snapshots
+             SELECT $1, $2, $3, COALESCE((SELECT supply
+                                          FROM contract_supply_snapshots
+                                          WHERE contract_account_id = $1 AND block_height < $2
+                                          ORDER BY block_height DESC
+                                          LIMIT 1), 0) + $4
+             ON CONFLICT (contract_account_id, block_height) DO NOTHING",
+        )
+        .bind(contract_id)
+        .bind(BigDecimal::from(block_header.height))
+        .bind(BigDecimal::from(block_header.timestamp))
+        .bind(delta)
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Recalculates the supply snapshots since the given block (all of them for 0) from the stored events
+pub async fn rebuild_supply_snapshots(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    from_block_height: u64,
+) -> anyhow::Result<u64> {
+    // The supply is the sum of all the previous deltas, so the earlier events are read anyway
+    let result = sqlx::query(
+        "INSERT INTO contract_supply_snapshots
+         SELECT * FROM (SELECT contract_account_id,
+                               block_height,
+                               block_timestamp,
+                               SUM(delta) OVER (PARTITION BY contract_account_id ORDER BY block_height)
+                        FROM (SELECT contract_account_id,
+                                     block_height,
+                                     MAX(block_timestamp) AS block_timestamp,
+                                     SUM(delta_amount)    AS delta
+                              FROM coin_events
+                              WHERE cause IN ('MINT', 'BURN') AND status = 'SUCCESS'
+                              GROUP BY contract_account_id, block_height) AS supply_deltas
+                       ) AS snapshots
+         WHERE block_height >= $1
+         ON CONFLICT (contract_account_id, block_height) DO UPDATE SET supply = excluded.supply",
+    )
+    .bind(BigDecimal::from(from_block_height))
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    async fn supply_snapshots(pool: &sqlx::Pool<sqlx::Postgres>) -> Vec<(BigDecimal, BigDecimal)> {
+        sqlx::query_as(
+            "SELECT block_height, supply FROM contract_supply_snapshots
+             WHERE contract_account_id = 'token.near' ORDER BY block_height",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn supply_follows_successful_mints_and_burns() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let settings = IndexerSettings {
+            store_supply_snapshots: true,
+            ..IndexerSettings::default()
+        };
+        let mut transfer = test_utils::coin_event(2, 814_001, "token.near", "bob.near", 5);
+        transfer.cause = CoinEventCause::Transfer.as_str().to_string();
+        let mut failed_mint = test_utils::coin_event(3, 814_002, "token.near", "bob.near", 7);
+        failed_mint.status = "FAILURE".to_string();
+        let blocks = [
+            (
+                814_001,
+                vec![
+                    test_utils::coin_event(1, 814_001, "token.near", "alice.near", 100),
+                    transfer,
+                ],
+            ),
+            (
+                814_002,
+                vec![
+                    failed_mint,
+                    test_utils::coin_event(4, 814_002, "token.near", "alice.near", -30),
+                ],
+            ),
+        ];
+
+        for (block_height, events) in &blocks {
+            crate::models::chunked_insert(&pool, events).await.unwrap();
+            let block_header = test_utils::block_header(*block_height);
+            store_supply_snapshots(&pool, &settings, events, &block_header)
+                .await
+                .unwrap();
+        }
+
+        let expected = vec![
+            (BigDecimal::from(814_001), BigDecimal::from(100)),
+            (BigDecimal::from(814_002), BigDecimal::from(70)),
+        ];
+        assert_eq!(supply_snapshots(&pool).await, expected);
+        sqlx::query("DELETE FROM contract_supply_snapshots")
+            .execute(&pool)
+            .await
+            .unwrap();
+        rebuild_supply_snapshots(&pool, 0).await.unwrap();
+        assert_eq!(supply_snapshots(&pool).await, expected);
+    }
+}
@@ -0,0 +1,443 @@
+use crate::models;
+use crate::models::coin_events::CoinEvent;
+use crate::models::contracts::Contract;
+use crate::models::nft_events::NftEvent;
+use bigdecimal::BigDecimal;
+use std::collections::{HashMap, HashSet};
+
+/// Fills `contracts` table from the stored events, returns the number of the found contracts.
+/// The existing rows are updated, inconsistency marks stay untouched.
+/// If the contract was redeployed with another token kind, the latest standard is stored
+/// together with the first event of the new standard
+pub async fn rebuild_contracts(pool: &sqlx::Pool<sqlx::Postgres>) -> anyhow::Result<usize> {
+    let contracts = contracts_from_events(pool, None).await?;
+    if !contracts.is_empty() {
+        models::chunked_insert(pool, &contracts).await?;
+    }
+    Ok(contracts.len())
+}
+
+/// Registers the contracts of the block while indexing.
+/// The change of the token kind is found against the standard stored by the previous blocks.
+/// The blocks indexed out of order (e.g. backfills) keep the stored standard, `rebuild_contracts` fixes it
+pub(crate) async fn register_contracts(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    coin_events: &[CoinEvent],
+    nft_events: &[NftEvent],
+) -> anyhow::Result<()> {
+    let mut events: Vec<FirstEvent> = coin_events
+        .iter()
+        .map(|event| {
+            (
+                event.contract_account_id.clone(),
+                event.standard.clone(),
+                event.block_timestamp.clone(),
+                event.block_height.clone(),
+                event.event_index.clone(),
+            )
+        })
+        .chain(nft_events.iter().map(|event| {
+            (
+                event.contract_account_id.clone(),
+                event.standard.clone(),
+                event.block_timestamp.clone(),
+                event.block_height.clone(),
+                event.event_index.clone(),
+            )
+        }))
+        .collect();
+    if events.is_empty() {
+        return Ok(());
+    }
+    events.sort_by(|a, b| (&a.0, &a.4).cmp(&(&b.0, &b.4)));
+    let mut seen_standards = HashSet::new();
+    events.retain(|event| seen_standards.insert((event.0.clone(), event.1.clone())));
+
+    let mut contract_ids: Vec<String> = events.iter().map(|event| event.0.clone()).collect();
+    contract_ids.dedup();
+    let stored_contracts: HashMap<String, (String, BigDecimal)> = sqlx::query_as(
+        "SELECT contract_account_id, standard, first_event_at_block_height
+         FROM contracts WHERE contract_account_id = ANY($1)",
+    )
+    .bind(&contract_ids)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|(contract_account_id, standard, block_height)| {
+        (contract_account_id, (standard, block_height))
+    })
+    .collect();
+
+    let contracts = fold_first_events(events, &stored_contracts);
+    models::chunked_insert(pool, &contracts).await
+}
+
+// The first event of one standard of the contract:
+// contract, standard, block timestamp, block height, event index
+type FirstEvent = (String, String, BigDecimal, BigDecimal, BigDecimal);
+
+// Builds the rows of all the contracts, or only of the given one, from the stored events
+async fn contracts_from_events(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    contract_id: Option<&str>,
+) -> anyhow::Result<Vec<Contract>> {
+    // The first event of each standard of the contract, ordered by time.
+    // The event type (so the detection method) is encoded in `event_index`
+    let first_events: Vec<FirstEvent> = sqlx::query_as(
+        "SELECT contract_account_id, standard, MIN(block_timestamp), MIN(block_height), MIN(event_index)
+         FROM (SELECT contract_account_id, standard, block_timestamp, block_height, event_index FROM coin_events
+               UNION ALL
+               SELECT contract_account_id, standard, block_timestamp, block_height, event_index FROM nft_events
+              ) AS events
+         WHERE $1::text IS NULL OR contract_account_id = $1
+         GROUP BY contract_account_id, standard
+         ORDER BY contract_account_id, MIN(block_timestamp)",
+    )
+    .bind(contract_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(fold_first_events(first_events, &HashMap::new()))
+}
+
+// Turns the first events, ordered by contract and time, into the rows.
+// `stored_contracts` are the standard and the first event height of the already registered contracts
+fn fold_first_events(
+    first_events: Vec<FirstEvent>,
+    stored_contracts: &HashMap<String, (String, BigDecimal)>,
+) -> Vec<Contract> {
+    let mut contracts: Vec<Contract> = vec![];
+    for (contract_account_id, standard, block_timestamp, block_height, event_index) in first_events
+    {
+        let detection_method =
+            crate::db_adapters::detection_method_from_db_index(&event_index).map(str::to_string);
+        let is_out_of_order = |contract_account_id: &str| {
+            stored_contracts
+                .get(contract_account_id)
+                .map_or(false, |(_, first_event_at)| *first_event_at >= block_height)
+        };
+        match contracts.last_mut() {
+            Some(contract) if contract.contract_account_id == contract_account_id => {
+                if !is_out_of_order(&contract_account_id) {
+                    switch_standard(
+                        contract,
+                        standard,
+                        detection_method,
+                        block_timestamp,
+                        block_height,
+                    );
+                }
+            }
+            _ => {
+                let stored_standard = stored_contracts
+                    .get(&contract_account_id)
+                    .map(|(standard, _)| standard.clone());
+                let mut contract = Contract {
+                    contract_account_id,
+                    standard: stored_standard.clone().unwrap_or_else(|| standard.clone()),
+                    first_event_at_timestamp: block_timestamp.clone(),
+                    first_event_at_block_height: block_height.clone(),
+                    // `least` ignores NULLs, so the existing values are preserved
+                    inconsistency_found_at_timestamp: None,
+                    inconsistency_found_at_block_height: None,
+                    // The stored method stays until the standard changes
+                    detection_method: match stored_standard {
+                        Some(_) => None,
+                        None => detection_method.clone(),
+                    },
+                    standard_changed_at_timestamp: None,
+                    standard_changed_at_block_height: None,
+                };
+                if stored_standard.is_some() && !is_out_of_order(&contract.contract_account_id) {
+                    switch_standard(
+                        &mut contract,
+                        standard,
+                        detection_method,
+                        block_timestamp,
+                        block_height,
+                    );
+                }
+                contracts.push(contract);
+            }
+        }
+    }
+    contracts
+}
+
+fn switch_standard(
+    contract: &mut Contract,
+    standard: String,
+    detection_method: Option<String>,
+    block_timestamp: BigDecimal,
+    block_height: BigDecimal,
+) {
+    // Legacy FT contract may start to emit NEP-141 events, it's the same token
+    if is_same_token_kind(&contract.standard, &standard) {
+        return;
+    }
+    tracing::warn!(
+        target: crate::LOGGING_PREFIX,
+        "Contract {} changed the standard from {} to {} at block {}",
+        contract.contract_account_id,
+        contract.standard,
+        standard,
+        block_height
+    );
+    contract.standard = standard;
+    contract.detection_method = detection_method;
+    if contract.standard_changed_at_block_height.is_none() {
+        contract.standard_changed_at_timestamp = Some(block_timestamp);
+        contract.standard_changed_at_block_height = Some(block_height);
+    }
+}
+
+/// Resets the first event of the contracts first seen since the given block to the stored events,
+/// the contracts without the events are removed. The inconsistency marks of the others stay untouched.
+/// Should be called once the blocks since `from_block_height` are indexed again, before `rebuild_contracts`:
+/// it keeps the earliest first event, so it can't move the first event forward by itself
+pub(crate) async fn refresh_first_events_since(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    from_block_height: u64,
+) -> anyhow::Result<()> {
+    let from_block_height = BigDecimal::from(from_block_height);
+    let mut transaction = pool.begin().await?;
+    sqlx::query(
+        "DELETE FROM contracts
+         WHERE first_event_at_block_height >= $1
+           AND NOT EXISTS (SELECT 1 FROM coin_events WHERE coin_events.contract_account_id = contracts.contract_account_id)
+           AND NOT EXISTS (SELECT 1 FROM nft_events WHERE nft_events.contract_account_id = contracts.contract_account_id)",
+    )
+    .bind(&from_block_height)
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query(
+        "UPDATE contracts
+         SET first_event_at_timestamp    = first_events.block_timestamp,
+             first_event_at_block_height = first_events.block_height
+         FROM (SELECT contract_account_id, MIN(block_timestamp) AS block_timestamp, MIN(block_height) AS block_height
+               FROM (SELECT contract_account_id, block_timestamp, block_height FROM coin_events
+                     UNION ALL
+                     SELECT contract_account_id, block_timestamp, block_height FROM nft_events
+                    ) AS events
+               WHERE contract_account_id IN (SELECT contract_account_id FROM contracts WHERE first_event_at_block_height >= $1)
+               GROUP BY contract_account_id
+              ) AS first_events
+         WHERE contracts.contract_account_id = first_events.contract_account_id",
+    )
+    .bind(&from_block_height)
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+// FT_NEP141 and FT_LEGACY are both FTs, NFT_NEP171 is not
+fn is_same_token_kind(standard: &str, other_standard: &str) -> bool {
+    let kind = |standard: &str| standard.split('_').next().map(str::to_string);
+    kind(standard) == kind(other_standard)
+}
+
+/// Marks the contract as inconsistent since the given block, the earlier mark stays untouched.
+/// The registry is filled by `rebuild_contracts`, so the row is built from the stored events if it's missing
+pub(crate) async fn mark_inconsistent(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    contract_id: &str,
+    block_height: &BigDecimal,
+    block_timestamp: &BigDecimal,
+) -> anyhow::Result<()> {
+    let mut contracts = contracts_from_events(pool, Some(contract_id)).await?;
+    let contract = match contracts.first_mut() {
+        Some(contract) => contract,
+        None => anyhow::bail!("Contract {} has no stored events", contract_id),
+    };
+    // `least` keeps the earlier mark
+    contract.inconsistency_found_at_timestamp = Some(block_timestamp.clone());
+    contract.inconsistency_found_at_block_height = Some(block_height.clone());
+    models::chunked_insert(pool, &contracts).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_adapters::contracts_filter::ContractsFilter;
+    use crate::settings::IndexerSettings;
+    use crate::sinks::NoopSink;
+    use crate::test_utils;
+
+    const CONTRACT: &str = "switch.near";
+
+    async fn stored_contract(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+    ) -> (String, Option<BigDecimal>, Option<BigDecimal>) {
+        sqlx::query_as(
+            "SELECT standard, standard_changed_at_block_height, inconsistency_found_at_block_height
+             FROM contracts WHERE contract_account_id = $1",
+        )
+        .bind(CONTRACT)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rebuild_takes_first_events_and_keeps_inconsistency_marks() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let events = [
+            test_utils::coin_event(1, 808_002, "token.near", "alice.near", 100),
+            test_utils::coin_event(2, 808_005, "token.near", "bob.near", 10),
+            test_utils::coin_event(3, 808_004, "other.near", "alice.near", 1),
+        ];
+        models::chunked_insert(&pool, &events).await.unwrap();
+        assert_eq!(rebuild_contracts(&pool).await.unwrap(), 2);
+        sqlx::query(
+            "UPDATE contracts SET inconsistency_found_at_timestamp = 1, inconsistency_found_at_block_height = 808005
+             WHERE contract_account_id = 'token.near'",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(rebuild_contracts(&pool).await.unwrap(), 2);
+
+        let contracts: Vec<(String, BigDecimal, Option<BigDecimal>)> = sqlx::query_as(
+            "SELECT contract_account_id, first_event_at_block_height, inconsistency_found_at_block_height
+             FROM contracts ORDER BY contract_account_id",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            contracts,
+            vec![
+                ("other.near".to_string(), BigDecimal::from(808_004), None),
+                (
+                    "token.near".to_string(),
+                    BigDecimal::from(808_002),
+                    Some(BigDecimal::from(808_005))
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn standard_change_is_found_while_indexing() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let ft_mint_log = test_utils::ft_mint_log("alice.near", "100");
+        let nft_mint_log = test_utils::nft_mint_log("alice.near", &["rabbit"]);
+        let blocks = [
+            test_utils::streamer_message(
+                829_001,
+                vec![test_utils::logs_outcome("ft", CONTRACT, &[&ft_mint_log])],
+            ),
+            test_utils::streamer_message(
+                829_002,
+                vec![test_utils::logs_outcome("nft", CONTRACT, &[&nft_mint_log])],
+            ),
+        ];
+
+        let mut registered = vec![];
+        for block in &blocks {
+            crate::db_adapters::events::store_events(
+                &pool,
+                &IndexerSettings::default(),
+                block,
+                "mainnet",
+                &ContractsFilter::default(),
+                &NoopSink,
+            )
+            .await
+            .unwrap();
+            registered.push(stored_contract(&pool).await);
+        }
+
+        assert_eq!(
+            registered,
+            vec![
+                ("FT_NEP141".to_string(), None, None),
+                (
+                    crate::db_adapters::nft::NFT.to_string(),
+                    Some(BigDecimal::from(829_002)),
+                    None
+                ),
+            ]
+        );
+        // The rebuild from the stored events comes to the same row
+        sqlx::query("DELETE FROM contracts")
+            .execute(&pool)
+            .await
+            .unwrap();
+        rebuild_contracts(&pool).await.unwrap();
+        assert_eq!(stored_contract(&pool).await, registered[1]);
+    }
+
+    #[tokio::test]
+    async fn detection_method_is_stored_while_indexing() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let ft_mint_log = test_utils::ft_mint_log("alice.near", "100");
+        let legacy_transfer = |seed, logs: &[&str]| {
+            test_utils::function_call_outcome(
+                seed,
+                "alice.near",
+                "legacy.tkn.near",
+                "ft_transfer",
+                br#"{"receiver_id":"bob.near","amount":"10"}"#,
+                logs,
+            )
+        };
+        let blocks = [
+            test_utils::streamer_message(
+                865_001,
+                vec![
+                    legacy_transfer("legacy", &[]),
+                    test_utils::logs_outcome("events", "events.near", &[&ft_mint_log]),
+                ],
+            ),
+            // The legacy contract switches to NEP-141 events, the token is the same
+            test_utils::streamer_message(
+                865_002,
+                vec![legacy_transfer("cutover", &[&ft_mint_log])],
+            ),
+        ];
+
+        for block in &blocks {
+            crate::db_adapters::events::store_events(
+                &pool,
+                &IndexerSettings::default(),
+                block,
+                "mainnet",
+                &ContractsFilter::default(),
+                &NoopSink,
+            )
+            .await
+            .unwrap();
+        }
+
+        let detection_methods: Vec<(String, Option<String>)> = sqlx::query_as(
+            "SELECT contract_account_id, detection_method FROM contracts ORDER BY contract_account_id",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            detection_methods,
+            vec![
+                ("events.near".to_string(), Some("events".to_string())),
+                (
+                    "legacy.tkn.near".to_string(),
+                    Some("legacy_tkn_near".to_string())
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use near_primitives::types::AccountId;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// Defines which contracts should be indexed.
+/// Deny list has the priority over allow list
+#[derive(Debug, Default, Clone)]
+pub struct ContractsFilter {
+    // None means all the contracts are allowed
+    allow_list: Option<HashSet<AccountId>>,
+    deny_list: HashSet<AccountId>,
+}
+
+impl ContractsFilter {
+    pub fn new(allow_list: Option<HashSet<AccountId>>, deny_list: HashSet<AccountId>) -> Self {
+        Self {
+            allow_list,
+            deny_list,
+        }
+    }
+
+    pub fn is_allowed(&self, contract_id: &AccountId) -> bool {
+        if self.deny_list.contains(contract_id) {
+            return false;
+        }
+        match &self.allow_list {
+            Some(allow_list) => allow_list.contains(contract_id),
+            None => true,
+        }
+    }
+
+    pub fn allows_everything(&self) -> bool {
+        self.allow_list.is_none() && self.deny_list.is_empty()
+    }
+}
+
+/// Reads the list of account ids, one per line. Empty lines and lines started with `#` are ignored
+pub fn read_contracts_file(path: &std::path::Path) -> anyhow::Result<HashSet<AccountId>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|err| anyhow::anyhow!("Failed to read {}: {}", path.display(), err))?;
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            AccountId::from_str(line).map_err(|err| {
+                anyhow::anyhow!(
+                    "Invalid account id `{}` in {}: {}",
+                    line,
+                    path.display(),
+                    err
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn contracts_file(content: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    fn account(account_id: &str) -> AccountId {
+        account_id.parse().unwrap()
+    }
+
+    #[test]
+    fn deny_list_wins_over_allow_list() {
+        let allow_list = read_contracts_file(
+            contracts_file("# tokens\ntoken.near\n\n  nft.near  \nspam.near\n").path(),
+        )
+        .unwrap();
+        let deny_list = read_contracts_file(contracts_file("spam.near\n").path()).unwrap();
+        let filter = ContractsFilter::new(Some(allow_list), deny_list);
+
+        assert!(filter.is_allowed(&account("token.near")));
+        assert!(filter.is_allowed(&account("nft.near")));
+        assert!(!filter.is_allowed(&account("spam.near")));
+        assert!(!filter.is_allowed(&account("other.near")));
+        assert!(!filter.allows_everything());
+        assert!(ContractsFilter::default().allows_everything());
+    }
+
+    #[test]
+    fn invalid_account_id_in_file_is_an_error() {
+        let file = contracts_file("token.near\nNot An Account\n");
+
+        let err = read_contracts_file(file.path()).unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("Invalid account id `Not An Account`"),
+            "{}",
+            err
+        );
+    }
+}
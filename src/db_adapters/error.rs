@@ -0,0 +1,89 @@
+/// Errors which may happen while collecting the events.
+/// They are converted to `anyhow::Error` at the public boundary (`store_events`)
+#[derive(Debug, thiserror::Error)]
+pub enum IndexerError {
+    #[error("Parse error: {0}")]
+    Parse(String),
+    #[error("RPC error: {0}")]
+    Rpc(String),
+    #[error("DB error: {0}")]
+    Db(#[from] sqlx::Error),
+    #[error("Inconsistency found: {0}")]
+    Inconsistency(String),
+}
+
+macro_rules! impl_from_parse_error {
+    ($($ty: ty),*) => {
+        $(
+            impl From<$ty> for IndexerError {
+                fn from(err: $ty) -> Self {
+                    Self::Parse(err.to_string())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_parse_error!(
+    serde_json::Error,
+    std::io::Error,
+    base64::DecodeError,
+    bigdecimal::ParseBigDecimalError,
+    near_primitives::account::id::ParseAccountError
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_adapters::coin::legacy::{decode_args, ArgsEncoding};
+
+    #[derive(serde::Deserialize, near_primitives::borsh::BorshDeserialize, Debug)]
+    struct Args {
+        #[allow(dead_code)]
+        amount: u64,
+    }
+
+    #[test]
+    fn broken_args_are_parse_errors() {
+        for (args, encoding) in [
+            (&b"{\"amount\":"[..], ArgsEncoding::Json),
+            (&b"\x01"[..], ArgsEncoding::Borsh),
+        ] {
+            let err = decode_args::<Args>(args, encoding).unwrap_err();
+            assert!(matches!(err, IndexerError::Parse(_)), "{err:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_node_is_rpc_error() {
+        // Nothing listens on the port 1
+        let rpc_client = near_jsonrpc_client::JsonRpcClient::connect("http://127.0.0.1:1");
+        let err = crate::rpc_helpers::balance_at_block_boundary(
+            &rpc_client,
+            &"token.near".parse().unwrap(),
+            &"alice.near".parse().unwrap(),
+            809_001,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(
+            matches!(err.downcast_ref(), Some(IndexerError::Rpc(_))),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_query_is_db_error() {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_timeout(std::time::Duration::from_millis(100))
+            .connect_lazy("postgres://postgres@127.0.0.1:1/db")
+            .unwrap();
+        let sqlx_err = sqlx::query("SELECT 1").execute(&pool).await.unwrap_err();
+
+        let err = IndexerError::from(sqlx_err);
+
+        assert!(matches!(err, IndexerError::Db(_)), "{err:?}");
+        assert!(err.to_string().starts_with("DB error: "), "{err}");
+    }
+}
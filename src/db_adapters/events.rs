@@ -1,43 +1,1011 @@
+use crate::db_adapters::contracts_filter::ContractsFilter;
 use crate::db_adapters::event_types;
 use crate::db_adapters::{coin, nft};
+use crate::models::coin_events::CoinEvent;
+use crate::models::nft_events::NftEvent;
+use crate::settings::IndexerSettings;
+use crate::sinks::EventSink;
 use futures::try_join;
 use near_lake_framework::near_indexer_primitives;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 
-pub(crate) async fn store_events(
+const EVENT_LOG_PREFIX: &str = "EVENT_JSON";
+
+/// Runs the futures concurrently, or one by one with the deterministic ordering.
+/// The results go in the order of the futures anyway
+pub(crate) async fn try_join_all_ordered<T, F>(
+    settings: &IndexerSettings,
+    futures: Vec<F>,
+) -> anyhow::Result<Vec<T>>
+where
+    F: std::future::Future<Output = anyhow::Result<T>>,
+{
+    if !settings.deterministic_ordering {
+        return futures::future::try_join_all(futures).await;
+    }
+    let mut results = vec![];
+    for future in futures {
+        results.push(future.await?);
+    }
+    Ok(results)
+}
+
+// Returns the value for `raw_event` column
+pub(crate) fn raw_event_to_store(settings: &IndexerSettings, raw_log: &str) -> Option<String> {
+    if settings.store_raw_events {
+        Some(raw_log.to_string())
+    } else {
+        None
+    }
+}
+
+const TRUNCATED_MEMO_MARKER: &str = "...[truncated]";
+
+// Truncates the memo to the max length (in chars). The memo is stored as is, escaping is up to the reader.
+// The only exception is NUL char: Postgres text can't contain it, so it becomes the replacement char
+pub(crate) fn prepare_memo(settings: &IndexerSettings, memo: &str) -> String {
+    let memo = match memo.char_indices().nth(settings.max_memo_length) {
+        Some((end, _)) => memo[..end].to_string() + TRUNCATED_MEMO_MARKER,
+        None => memo.to_string(),
+    };
+    memo.replace('\0', "\u{FFFD}")
+}
+
+// The custom `standard` of the event is mapped to the supported one (`nep141` or `nep171`)
+pub(crate) fn parse_event(
+    settings: &IndexerSettings,
+    event_json: &str,
+) -> serde_json::Result<event_types::NearEvent> {
+    let aliases = &settings.standard_aliases;
+    if aliases.is_empty() {
+        return serde_json::from_str::<event_types::NearEvent>(event_json);
+    }
+    let mut value = serde_json::from_str::<serde_json::Value>(event_json)?;
+    if let Some(standard) = value.get_mut("standard") {
+        if let Some(supported_standard) = standard.as_str().and_then(|s| aliases.get(s)) {
+            *standard = serde_json::Value::String(supported_standard.clone());
+        }
+    }
+    serde_json::from_value::<event_types::NearEvent>(value)
+}
+
+pub(crate) fn is_supported_nep141_version(settings: &IndexerSettings, version: &str) -> bool {
+    let versions = &settings.supported_nep141_versions;
+    versions.is_empty() || versions.iter().any(|supported| supported == version)
+}
+
+/// Collects FT and NFT events from all the shards of the block and stores them to the DB.
+/// Only the contracts allowed by `contracts_filter` are indexed.
+/// Returns the checksum of the block events if the checksums are enabled
+pub async fn store_events(
     pool: &sqlx::Pool<sqlx::Postgres>,
+    settings: &IndexerSettings,
     streamer_message: &near_indexer_primitives::StreamerMessage,
     chain_id: &str,
-) -> anyhow::Result<()> {
-    try_join!(
-        coin::store_ft(pool, streamer_message, chain_id),
-        nft::store_nft(pool, streamer_message),
-    )?;
-    Ok(())
+    contracts_filter: &ContractsFilter,
+    sink: &dyn EventSink,
+) -> anyhow::Result<Option<String>> {
+    report_parse_failures(settings, streamer_message, contracts_filter);
+    let ft_future = async {
+        if settings.index_ft {
+            coin::store_ft(
+                pool,
+                settings,
+                streamer_message,
+                chain_id,
+                contracts_filter,
+                sink,
+            )
+            .await
+        } else {
+            Ok(vec![])
+        }
+    };
+    let nft_future = async {
+        if settings.index_nft {
+            nft::store_nft(pool, settings, streamer_message, contracts_filter, sink).await
+        } else {
+            Ok(vec![])
+        }
+    };
+    let (coin_events, nft_events) = if settings.deterministic_ordering {
+        (ft_future.await?, nft_future.await?)
+    } else {
+        try_join!(ft_future, nft_future)?
+    };
+    if settings.store_to_db {
+        crate::db_adapters::contracts::register_contracts(pool, &coin_events, &nft_events).await?;
+    }
+    if !settings.store_block_checksums {
+        return Ok(None);
+    }
+    Ok(Some(block_checksum(&coin_events, &nft_events)?))
+}
+
+// SHA-256 over the rows of the block ordered by `event_index`, one JSON line per row.
+// The fields go in the order of the columns, so two runs writing the same rows get the same checksum.
+// Each block is hashed separately, so the ranges of two deployments could be compared from any block
+fn block_checksum(coin_events: &[CoinEvent], nft_events: &[NftEvent]) -> anyhow::Result<String> {
+    let mut coin_events: Vec<&CoinEvent> = coin_events.iter().collect();
+    coin_events.sort_by(|a, b| a.event_index.cmp(&b.event_index));
+    let mut nft_events: Vec<&NftEvent> = nft_events.iter().collect();
+    nft_events.sort_by(|a, b| a.event_index.cmp(&b.event_index));
+
+    let mut hasher = Sha256::new();
+    for event in coin_events {
+        hasher.update(b"coin_events ");
+        hasher.update(serde_json::to_vec(event)?);
+        hasher.update(b"\n");
+    }
+    for event in nft_events {
+        hasher.update(b"nft_events ");
+        hasher.update(serde_json::to_vec(event)?);
+        hasher.update(b"\n");
+    }
+    Ok(hex::encode(hasher.finalize()))
 }
 
+pub(crate) struct ExtractedEvent {
+    pub event: event_types::NearEvent,
+    // Trimmed log the event was parsed from
+    pub raw_log: String,
+    // The same log is already met in this receipt
+    pub is_duplicate: bool,
+}
+
+impl ExtractedEvent {
+    // The duplicates are counted anyway, it helps to decide whether the dedup is needed
+    pub(crate) fn should_skip_as_duplicate(
+        &self,
+        settings: &IndexerSettings,
+        outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
+    ) -> bool {
+        if !self.is_duplicate {
+            return false;
+        }
+        crate::metrics::DUPLICATE_EVENT_LOGS_TOTAL.inc();
+        if !settings.dedup_receipt_logs {
+            return false;
+        }
+        tracing::debug!(
+            target: crate::LOGGING_PREFIX,
+            receipt_id = %outcome.receipt.receipt_id,
+            log = %crate::db_adapters::sanitize_for_log(&self.raw_log),
+            "Skipping duplicate event log",
+        );
+        true
+    }
+}
+
+// Pure extraction: each collector (NEP-141, NEP-171, legacy) extracts the events of the outcome by itself.
+// The logs which are not parsed are skipped silently, `report_parse_failures` counts them once per block
 pub(crate) fn extract_events(
+    settings: &IndexerSettings,
     outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
-) -> Vec<event_types::NearEvent> {
-    let prefix = "EVENT_JSON:";
-    outcome.execution_outcome.outcome.logs.iter().filter_map(|untrimmed_log| {
-        let log = untrimmed_log.trim();
-        if !log.starts_with(prefix) {
-            return None;
-        }
-
-        match serde_json::from_str::<'_, event_types::NearEvent>(
-            log[prefix.len()..].trim(),
-        ) {
-            Ok(result) => Some(result),
-            Err(err) => {
-                tracing::info!(
-                    target: crate::LOGGING_PREFIX,
-                    "Provided event log does not correspond to any of formats defined in NEP. Will ignore this event. \n {:#?} \n{:#?}",
-                    err,
-                    untrimmed_log,
-                );
-                None
+) -> Vec<ExtractedEvent> {
+    let case_insensitive = settings.case_insensitive_event_prefix;
+    let mut seen_logs: HashSet<&str> = HashSet::new();
+    outcome
+        .execution_outcome
+        .outcome
+        .logs
+        .iter()
+        .filter_map(|untrimmed_log| {
+            let event_json = strip_event_prefix(untrimmed_log.trim(), case_insensitive)?;
+            let is_duplicate = !seen_logs.insert(untrimmed_log.trim());
+            Some(ExtractedEvent {
+                event: parse_event(settings, event_json.trim()).ok()?,
+                raw_log: untrimmed_log.trim().to_string(),
+                is_duplicate,
+            })
+        })
+        .collect()
+}
+
+const MAX_PARSE_FAILURE_CONTRACT_LABELS: usize = 100;
+
+lazy_static! {
+    static ref PARSE_FAILURE_CONTRACT_LABELS: crate::metrics::BoundedLabels =
+        crate::metrics::BoundedLabels::new(MAX_PARSE_FAILURE_CONTRACT_LABELS);
+}
+
+// Counts and logs the event logs which are not parsed, once for each outcome of the block indexed by this run.
+// Reprocessing the block counts them again, the same as the other per-block metrics
+fn report_parse_failures(
+    settings: &IndexerSettings,
+    streamer_message: &near_indexer_primitives::StreamerMessage,
+    contracts_filter: &ContractsFilter,
+) {
+    let case_insensitive = settings.case_insensitive_event_prefix;
+    let outcomes = streamer_message
+        .shards
+        .iter()
+        .flat_map(|shard| &shard.receipt_execution_outcomes)
+        .filter(|outcome| contracts_filter.is_allowed(&outcome.receipt.receiver_id));
+    for outcome in outcomes {
+        for untrimmed_log in &outcome.execution_outcome.outcome.logs {
+            let event_json = match strip_event_prefix(untrimmed_log.trim(), case_insensitive) {
+                Some(event_json) => event_json,
+                None => continue,
+            };
+            let err = match parse_event(settings, event_json.trim()) {
+                Ok(_) => continue,
+                Err(err) => err,
+            };
+            crate::metrics::EVENT_PARSE_FAILURES_TOTAL
+                .with_label_values(&[
+                    &PARSE_FAILURE_CONTRACT_LABELS.get(outcome.receipt.receiver_id.to_string())
+                ])
+                .inc();
+            // The log could be a valid JSON with unexpected structure, the version helps to understand what's changed
+            let version = serde_json::from_str::<serde_json::Value>(event_json.trim())
+                .ok()
+                .and_then(|value| value.get("version")?.as_str().map(str::to_string));
+            tracing::info!(
+                target: crate::LOGGING_PREFIX,
+                receipt_id = %outcome.receipt.receipt_id,
+                contract_id = %outcome.receipt.receiver_id,
+                version = ?version,
+                error = %err,
+                log = %crate::db_adapters::sanitize_for_log(untrimmed_log),
+                "Provided event log does not correspond to any of formats defined in NEP. Will ignore this event",
+            );
+        }
+    }
+}
+
+// Returns the part after `EVENT_JSON:`, the whitespaces around the colon are allowed
+pub(crate) fn strip_event_prefix(log: &str, case_insensitive: bool) -> Option<&str> {
+    let prefix = log.get(..EVENT_LOG_PREFIX.len())?;
+    let prefix_matches = if case_insensitive {
+        prefix.eq_ignore_ascii_case(EVENT_LOG_PREFIX)
+    } else {
+        prefix == EVENT_LOG_PREFIX
+    };
+    if !prefix_matches {
+        return None;
+    }
+    log[EVENT_LOG_PREFIX.len()..].trim_start().strip_prefix(':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::NoopSink;
+    use crate::test_utils;
+    use bigdecimal::BigDecimal;
+
+    #[test]
+    fn long_memo_is_truncated_by_chars() {
+        let settings = IndexerSettings {
+            max_memo_length: 4,
+            ..IndexerSettings::default()
+        };
+
+        assert_eq!(prepare_memo(&settings, "rent"), "rent");
+        assert_eq!(prepare_memo(&settings, "rent!"), "rent...[truncated]");
+        // Multibyte chars are not cut in the middle
+        assert_eq!(
+            prepare_memo(&settings, "ёжик в тумане"),
+            "ёжик...[truncated]"
+        );
+        assert_eq!(prepare_memo(&settings, ""), "");
+    }
+
+    #[test]
+    fn aliased_standard_is_parsed_as_the_target_one() {
+        let forked_mint =
+            test_utils::ft_mint_log("alice.near", "10").replace("nep141", "forked-ft");
+        let outcome = test_utils::logs_outcome("forked mint", "fork.near", &[&forked_mint]);
+
+        assert!(extract_events(&IndexerSettings::default(), &outcome).is_empty());
+
+        let settings = IndexerSettings {
+            standard_aliases: std::collections::HashMap::from([(
+                "forked-ft".to_string(),
+                "nep141".to_string(),
+            )]),
+            ..IndexerSettings::default()
+        };
+        let events = extract_events(&settings, &outcome);
+        assert_eq!(events.len(), 1);
+        match &events[0].event {
+            event_types::NearEvent::Nep141(event) => match &event.event_kind {
+                event_types::Nep141EventKind::FtMint(mints) => {
+                    assert_eq!(mints[0].owner_id, "alice.near");
+                    assert_eq!(mints[0].amount, "10");
+                }
+                other => panic!("Unexpected event kind: {:?}", other),
+            },
+            other => panic!("Unexpected event: {:?}", other),
+        }
+        // The raw log keeps the original standard
+        assert_eq!(events[0].raw_log, forked_mint);
+    }
+
+    #[test]
+    fn event_prefix_tolerates_whitespace_and_optional_casing() {
+        let json = r#"{"standard":"nep141"}"#;
+        for (log, case_insensitive, expected) in [
+            ("EVENT_JSON:{}", false, Some("{}")),
+            ("EVENT_JSON : {}", false, Some(" {}")),
+            ("EVENT_JSON\t:{}", false, Some("{}")),
+            ("event_json:{}", false, None),
+            ("event_json:{}", true, Some("{}")),
+            ("Event_Json :{}", true, Some("{}")),
+            ("EVENT_JSONX:{}", true, None),
+            ("EVENT_JSO", false, None),
+            ("EVENT_JSON{}", false, None),
+        ] {
+            let log = log.replace("{}", json);
+            let expected = expected.map(|expected| expected.replace("{}", json));
+            assert_eq!(
+                strip_event_prefix(&log, case_insensitive).map(str::to_string),
+                expected,
+                "`{}`",
+                log
+            );
+        }
+
+        let padded_log = format!("  {}\n", test_utils::ft_mint_log("alice.near", "100"));
+        let outcome = test_utils::logs_outcome("padded", "token.near", &[&padded_log]);
+        assert_eq!(
+            extract_events(&IndexerSettings::default(), &outcome).len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn events_stored_before_are_not_emitted_again() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let sink = test_utils::VecSink::default();
+        let ft_mint_log = test_utils::ft_mint_log("alice.near", "100");
+        let nft_mint_log = test_utils::nft_mint_log("alice.near", &["1"]);
+        let block = test_utils::streamer_message(
+            784_001,
+            vec![
+                test_utils::logs_outcome("ft", "token.near", &[&ft_mint_log]),
+                test_utils::logs_outcome("nft", "nft.near", &[&nft_mint_log]),
+            ],
+        );
+
+        // The second attempt goes as after the crash between the events insert and `processed_blocks`
+        for _ in 0..2 {
+            store_events(
+                &pool,
+                &IndexerSettings::default(),
+                &block,
+                "mainnet",
+                &ContractsFilter::default(),
+                &sink,
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(sink.coin_events.lock().unwrap().len(), 1);
+        assert_eq!(sink.nft_events.lock().unwrap().len(), 1);
+        assert_eq!(test_utils::count_rows(&pool, "coin_events").await, 1);
+        assert_eq!(test_utils::count_rows(&pool, "nft_events").await, 1);
+    }
+
+    #[tokio::test]
+    async fn counters_include_only_inserted_events() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let ft_mint_log = test_utils::ft_mint_log("alice.near", "100");
+        let ft_other_mint_log = test_utils::ft_mint_log("bob.near", "5");
+        let nft_mint_log = test_utils::nft_mint_log("alice.near", &["1"]);
+        let block = test_utils::streamer_message(
+            775_001,
+            vec![
+                test_utils::logs_outcome("ft", "token.near", &[&ft_mint_log, &ft_other_mint_log]),
+                test_utils::logs_outcome("nft", "nft.near", &[&nft_mint_log]),
+            ],
+        );
+        let ft_before = crate::metrics::FT_EVENTS_TOTAL.get();
+        let nft_before = crate::metrics::NFT_EVENTS_TOTAL.get();
+
+        for _ in 0..2 {
+            store_events(
+                &pool,
+                &IndexerSettings::default(),
+                &block,
+                "mainnet",
+                &ContractsFilter::default(),
+                &NoopSink,
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(crate::metrics::FT_EVENTS_TOTAL.get() - ft_before, 2);
+        assert_eq!(crate::metrics::NFT_EVENTS_TOTAL.get() - nft_before, 1);
+    }
+
+    #[tokio::test]
+    async fn parse_failures_are_counted_once_per_block_processing() {
+        let _globals = test_utils::lock_globals().await;
+        let broken_logs = vec!["EVENT_JSON:{broken"; 1001];
+        let ft_mint_log = test_utils::ft_mint_log("alice.near", "100");
+        let block = test_utils::streamer_message(
+            857_001,
+            vec![
+                test_utils::logs_outcome("broken", "broken.near", &broken_logs),
+                test_utils::logs_outcome("good", "good.near", &[&ft_mint_log]),
+            ],
+        );
+        let failures = |contract_id: &str| {
+            crate::metrics::EVENT_PARSE_FAILURES_TOTAL
+                .with_label_values(&[&PARSE_FAILURE_CONTRACT_LABELS.get(contract_id.to_string())])
+                .get()
+        };
+        let broken_before = failures("broken.near");
+        let good_before = failures("good.near");
+
+        // The collectors extract the events by themselves, it's not counted
+        for outcome in &block.shards[0].receipt_execution_outcomes {
+            extract_events(&IndexerSettings::default(), outcome);
+        }
+        assert_eq!(failures("broken.near"), broken_before);
+
+        // The block is processed again, e.g. by `reindex`
+        for _ in 0..2 {
+            report_parse_failures(
+                &IndexerSettings::default(),
+                &block,
+                &ContractsFilter::default(),
+            );
+        }
+
+        assert_eq!(failures("broken.near") - broken_before, 2002);
+        assert_eq!(failures("good.near"), good_before);
+    }
+
+    async fn replay(pool: &sqlx::Pool<sqlx::Postgres>, fixture: &str) {
+        store_events(
+            pool,
+            &IndexerSettings::default(),
+            &test_utils::block_fixture(fixture),
+            "mainnet",
+            &ContractsFilter::default(),
+            &NoopSink,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn recorded_ft_blocks_are_replayed() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+
+        replay(&pool, "ft_mint").await;
+        replay(&pool, "ft_transfer").await;
+
+        let rows: Vec<(String, String, String, BigDecimal, Option<String>)> = sqlx::query_as(
+            "SELECT contract_account_id, affected_account_id, cause, delta_amount, event_memo
+             FROM coin_events ORDER BY event_index",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        let row = |affected: &str, cause: &str, delta: i64, memo: Option<&str>| {
+            (
+                "token.near".to_string(),
+                affected.to_string(),
+                cause.to_string(),
+                BigDecimal::from(delta),
+                memo.map(str::to_string),
+            )
+        };
+        assert_eq!(
+            rows,
+            vec![
+                row("alice.near", "MINT", 1000, None),
+                row("alice.near", "TRANSFER", -250, Some("rent")),
+                row("bob.near", "TRANSFER", 250, Some("rent")),
+            ]
+        );
+        assert_eq!(test_utils::count_rows(&pool, "nft_events").await, 0);
+    }
+
+    #[tokio::test]
+    async fn recorded_nft_block_is_replayed() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+
+        replay(&pool, "nft_mint").await;
+
+        let rows: Vec<(String, String, String, Option<String>)> = sqlx::query_as(
+            "SELECT contract_account_id, token_id, cause, new_owner_account_id
+             FROM nft_events ORDER BY event_index",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        let row = |token_id: &str| {
+            (
+                "nft.near".to_string(),
+                token_id.to_string(),
+                "MINT".to_string(),
+                Some("carol.near".to_string()),
+            )
+        };
+        assert_eq!(rows, vec![row("rabbit-1"), row("rabbit-2")]);
+        assert_eq!(test_utils::count_rows(&pool, "coin_events").await, 0);
+    }
+
+    #[tokio::test]
+    async fn contracts_filter_keeps_only_listed_contracts() {
+        let ft_mint_log = test_utils::ft_mint_log("alice.near", "100");
+        let nft_mint_log = test_utils::nft_mint_log("alice.near", &["rabbit"]);
+        let block = test_utils::streamer_message(
+            780_001,
+            vec![
+                test_utils::logs_outcome("ft", "token.near", &[&ft_mint_log]),
+                test_utils::logs_outcome("other ft", "other.near", &[&ft_mint_log]),
+                test_utils::logs_outcome("nft", "nft.near", &[&nft_mint_log]),
+                test_utils::logs_outcome("other nft", "other-nft.near", &[&nft_mint_log]),
+                test_utils::function_call_outcome(
+                    "legacy",
+                    "alice.near",
+                    "abc.tkn.near",
+                    "ft_transfer",
+                    br#"{"receiver_id":"bob.near","amount":"10"}"#,
+                    &[],
+                ),
+            ],
+        );
+        let only_contracts = ["token.near", "nft.near"]
+            .iter()
+            .map(|contract_id| contract_id.parse().unwrap())
+            .collect();
+        let sink = test_utils::VecSink::default();
+
+        store_events(
+            &test_utils::offline_pool(),
+            &IndexerSettings {
+                store_to_db: false,
+                ..IndexerSettings::default()
+            },
+            &block,
+            "mainnet",
+            &ContractsFilter::new(Some(only_contracts), HashSet::new()),
+            &sink,
+        )
+        .await
+        .unwrap();
+
+        let coin_contracts: Vec<String> = sink
+            .coin_events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|event| event.contract_account_id.clone())
+            .collect();
+        let nft_contracts: Vec<String> = sink
+            .nft_events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|event| event.contract_account_id.clone())
+            .collect();
+        assert_eq!(coin_contracts, vec!["token.near"]);
+        assert_eq!(nft_contracts, vec!["nft.near"]);
+    }
+
+    #[tokio::test]
+    async fn raw_logs_are_kept_only_when_asked() {
+        let ft_mint_log = test_utils::ft_mint_log("alice.near", "100");
+        let nft_mint_log = test_utils::nft_mint_log("alice.near", &["rabbit"]);
+        let padded_nft_mint_log = format!(" {}\n", nft_mint_log);
+        let block = test_utils::streamer_message(
+            796_001,
+            vec![
+                test_utils::logs_outcome("ft", "token.near", &[&ft_mint_log]),
+                test_utils::logs_outcome("nft", "nft.near", &[&padded_nft_mint_log]),
+            ],
+        );
+        let raw_events = |store_raw_events| {
+            let block = &block;
+            async move {
+                let sink = test_utils::VecSink::default();
+                store_events(
+                    &test_utils::offline_pool(),
+                    &IndexerSettings {
+                        store_to_db: false,
+                        store_raw_events,
+                        ..IndexerSettings::default()
+                    },
+                    block,
+                    "mainnet",
+                    &ContractsFilter::default(),
+                    &sink,
+                )
+                .await
+                .unwrap();
+                let coin_raw_event = sink.coin_events.lock().unwrap()[0].raw_event.clone();
+                let nft_raw_event = sink.nft_events.lock().unwrap()[0].raw_event.clone();
+                (coin_raw_event, nft_raw_event)
+            }
+        };
+
+        assert_eq!(raw_events(false).await, (None, None));
+        assert_eq!(
+            raw_events(true).await,
+            (Some(ft_mint_log.clone()), Some(nft_mint_log.clone()))
+        );
+    }
+
+    #[tokio::test]
+    async fn disabled_standard_is_not_indexed() {
+        let block = test_utils::streamer_message(
+            833_001,
+            vec![
+                test_utils::logs_outcome(
+                    "ft",
+                    "token.near",
+                    &[&test_utils::ft_mint_log("alice.near", "100")],
+                ),
+                test_utils::logs_outcome(
+                    "nft",
+                    "nft.near",
+                    &[&test_utils::nft_mint_log("alice.near", &["rabbit"])],
+                ),
+            ],
+        );
+        let indexed_events = |index_ft, index_nft| {
+            let block = &block;
+            async move {
+                let sink = test_utils::VecSink::default();
+                store_events(
+                    &test_utils::offline_pool(),
+                    &IndexerSettings {
+                        store_to_db: false,
+                        index_ft,
+                        index_nft,
+                        ..IndexerSettings::default()
+                    },
+                    block,
+                    "mainnet",
+                    &ContractsFilter::default(),
+                    &sink,
+                )
+                .await
+                .unwrap();
+                let coin_indexes = sink
+                    .coin_events
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|event| event.event_index.clone())
+                    .collect::<Vec<_>>();
+                let nft_indexes = sink
+                    .nft_events
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|event| event.event_index.clone())
+                    .collect::<Vec<_>>();
+                (coin_indexes, nft_indexes)
+            }
+        };
+
+        let (all_coin_indexes, all_nft_indexes) = indexed_events(true, true).await;
+        assert_eq!((all_coin_indexes.len(), all_nft_indexes.len()), (1, 1));
+        // The indexes of the enabled standard don't depend on the disabled one
+        assert_eq!(
+            indexed_events(true, false).await,
+            (all_coin_indexes, vec![])
+        );
+        assert_eq!(indexed_events(false, true).await, (vec![], all_nft_indexes));
+    }
+
+    #[tokio::test]
+    async fn unicode_memo_round_trips_through_the_db() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let memo = "Привет, \"мир\" 🚀\n\\u{1F680}\0";
+        let mint_log = format!(
+            "EVENT_JSON:{}",
+            serde_json::json!({
+                "standard": "nep141",
+                "version": "1.0.0",
+                "event": "ft_mint",
+                "data": [{ "owner_id": "alice.near", "amount": "1", "memo": memo }],
+            })
+        );
+        let block = test_utils::streamer_message(
+            835_001,
+            vec![test_utils::logs_outcome("memo", "token.near", &[&mint_log])],
+        );
+
+        store_events(
+            &pool,
+            &IndexerSettings::default(),
+            &block,
+            "mainnet",
+            &ContractsFilter::default(),
+            &NoopSink,
+        )
+        .await
+        .unwrap();
+
+        let stored_memo: Option<String> =
+            sqlx::query_scalar("SELECT event_memo FROM coin_events WHERE block_height = 835001")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        // Everything is kept as is, except NUL which Postgres text can't store
+        assert_eq!(
+            stored_memo.as_deref(),
+            Some("Привет, \"мир\" 🚀\n\\u{1F680}\u{FFFD}")
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_logs_are_kept_unless_dedup_is_asked() {
+        let _globals = test_utils::lock_globals().await;
+        let ft_mint_log = test_utils::ft_mint_log("alice.near", "100");
+        let nft_mint_log = test_utils::nft_mint_log("alice.near", &["rabbit"]);
+        let block = test_utils::streamer_message(
+            844_001,
+            vec![
+                test_utils::logs_outcome("ft", "token.near", &[&ft_mint_log, &ft_mint_log]),
+                // The same log in another receipt is not a duplicate
+                test_utils::logs_outcome("ft again", "token.near", &[&ft_mint_log]),
+                test_utils::logs_outcome("nft", "nft.near", &[&nft_mint_log, &nft_mint_log]),
+            ],
+        );
+        let stored_events = |dedup_receipt_logs| {
+            let block = &block;
+            async move {
+                let sink = test_utils::VecSink::default();
+                store_events(
+                    &test_utils::offline_pool(),
+                    &IndexerSettings {
+                        store_to_db: false,
+                        dedup_receipt_logs,
+                        ..IndexerSettings::default()
+                    },
+                    block,
+                    "mainnet",
+                    &ContractsFilter::default(),
+                    &sink,
+                )
+                .await
+                .unwrap();
+                let coin_events = sink.coin_events.lock().unwrap().len();
+                let nft_events = sink.nft_events.lock().unwrap().len();
+                (coin_events, nft_events)
             }
+        };
+
+        // Two equal transfers could be intentional, so the duplicates are only counted by default
+        let duplicates_before = crate::metrics::DUPLICATE_EVENT_LOGS_TOTAL.get();
+        assert_eq!(stored_events(false).await, (3, 2));
+        assert_eq!(
+            crate::metrics::DUPLICATE_EVENT_LOGS_TOTAL.get() - duplicates_before,
+            2
+        );
+
+        let duplicates_before = crate::metrics::DUPLICATE_EVENT_LOGS_TOTAL.get();
+        assert_eq!(stored_events(true).await, (2, 1));
+        assert_eq!(
+            crate::metrics::DUPLICATE_EVENT_LOGS_TOTAL.get() - duplicates_before,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn partially_written_block_is_completed_without_duplicates() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let mint_logs: Vec<String> = ["alice.near", "bob.near", "carol.near"]
+            .iter()
+            .map(|owner_id| test_utils::ft_mint_log(owner_id, "1"))
+            .collect();
+        let nft_mint_logs: Vec<String> = ["rabbit", "hare"]
+            .iter()
+            .map(|token_id| test_utils::nft_mint_log("alice.near", &[token_id]))
+            .collect();
+        let block = test_utils::streamer_message(
+            858_001,
+            vec![
+                test_utils::logs_outcome(
+                    "ft",
+                    "token.near",
+                    &mint_logs.iter().map(String::as_str).collect::<Vec<_>>(),
+                ),
+                test_utils::logs_outcome(
+                    "nft",
+                    "nft.near",
+                    &nft_mint_logs.iter().map(String::as_str).collect::<Vec<_>>(),
+                ),
+            ],
+        );
+        let settings = IndexerSettings::default();
+        let contracts_filter = ContractsFilter::default();
+        let store =
+            |sink| store_events(&pool, &settings, &block, "mainnet", &contracts_filter, sink);
+        store(&NoopSink).await.unwrap();
+        // The indexer stopped after writing only a part of the block
+        sqlx::query("DELETE FROM coin_events WHERE affected_account_id <> 'alice.near'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM nft_events WHERE token_id = 'hare'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let sink = test_utils::VecSink::default();
+        store(&sink).await.unwrap();
+
+        let emitted_accounts: Vec<String> = sink
+            .coin_events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|event| event.affected_account_id.clone())
+            .collect();
+        assert_eq!(emitted_accounts, vec!["bob.near", "carol.near"]);
+        let emitted_tokens: Vec<String> = sink
+            .nft_events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|event| event.token_id.clone())
+            .collect();
+        assert_eq!(emitted_tokens, vec!["hare"]);
+        assert_eq!(test_utils::count_rows(&pool, "coin_events").await, 3);
+        assert_eq!(test_utils::count_rows(&pool, "nft_events").await, 2);
+    }
+
+    #[tokio::test]
+    async fn block_checksum_follows_the_rows() {
+        let block = test_utils::streamer_message(
+            863_001,
+            vec![
+                test_utils::logs_outcome(
+                    "ft",
+                    "token.near",
+                    &[
+                        &test_utils::ft_mint_log("alice.near", "100"),
+                        &test_utils::ft_mint_log("bob.near", "5"),
+                    ],
+                ),
+                test_utils::logs_outcome(
+                    "nft",
+                    "nft.near",
+                    &[&test_utils::nft_mint_log("alice.near", &["rabbit"])],
+                ),
+            ],
+        );
+        let store = |store_block_checksums| {
+            let block = &block;
+            async move {
+                let sink = test_utils::VecSink::default();
+                let checksum = store_events(
+                    &test_utils::offline_pool(),
+                    &IndexerSettings {
+                        store_to_db: false,
+                        store_block_checksums,
+                        ..IndexerSettings::default()
+                    },
+                    block,
+                    "mainnet",
+                    &ContractsFilter::default(),
+                    &sink,
+                )
+                .await
+                .unwrap();
+                let coin_events = sink.coin_events.lock().unwrap().clone();
+                let nft_events = sink.nft_events.lock().unwrap().clone();
+                (checksum, coin_events, nft_events)
+            }
+        };
+
+        assert_eq!(store(false).await.0, None);
+        let (checksum, mut coin_events, nft_events) = store(true).await;
+        let checksum = checksum.unwrap();
+        assert_eq!(store(true).await.0, Some(checksum.clone()));
+
+        // The order the rows are collected in doesn't matter
+        coin_events.reverse();
+        assert_eq!(block_checksum(&coin_events, &nft_events).unwrap(), checksum);
+        // As if the parsing was changed
+        coin_events[0].delta_amount += BigDecimal::from(1);
+        assert_ne!(block_checksum(&coin_events, &nft_events).unwrap(), checksum);
+    }
+
+    #[tokio::test]
+    async fn deterministic_ordering_gives_the_same_rows_every_run() {
+        let mut block = test_utils::streamer_message(
+            871_001,
+            vec![test_utils::logs_outcome(
+                "shard 0",
+                "token.near",
+                &[&test_utils::ft_mint_log("alice.near", "1")],
+            )],
+        );
+        let mut second_shard = test_utils::streamer_message(
+            871_001,
+            vec![
+                test_utils::logs_outcome(
+                    "shard 1 ft",
+                    "token.near",
+                    &[&test_utils::ft_mint_log("bob.near", "2")],
+                ),
+                test_utils::logs_outcome(
+                    "shard 1 nft",
+                    "nft.near",
+                    &[&test_utils::nft_mint_log("bob.near", &["hare"])],
+                ),
+            ],
+        )
+        .shards
+        .remove(0);
+        second_shard.shard_id = 1;
+        // The shards come in the reverse order
+        block.shards.insert(0, second_shard);
+        let settings = IndexerSettings {
+            store_to_db: false,
+            deterministic_ordering: true,
+            ..IndexerSettings::default()
+        };
+        let store = || async {
+            let sink = test_utils::VecSink::default();
+            store_events(
+                &test_utils::offline_pool(),
+                &settings,
+                &block,
+                "mainnet",
+                &ContractsFilter::default(),
+                &sink,
+            )
+            .await
+            .unwrap();
+            let coin_events = sink.coin_events.lock().unwrap().clone();
+            let nft_events = sink.nft_events.lock().unwrap().clone();
+            (coin_events, nft_events)
+        };
+
+        let (coin_events, nft_events) = store().await;
+        let owners: Vec<_> = coin_events
+            .iter()
+            .map(|e| e.affected_account_id.as_str())
+            .collect();
+        assert_eq!(owners, vec!["alice.near", "bob.near"]);
+        assert_eq!(nft_events.len(), 1);
+        for _ in 0..3 {
+            let (next_coin_events, next_nft_events) = store().await;
+            let indices = |events: &[CoinEvent]| -> Vec<BigDecimal> {
+                events.iter().map(|e| e.event_index.clone()).collect()
+            };
+            assert_eq!(indices(&next_coin_events), indices(&coin_events));
+            assert_eq!(next_nft_events[0].event_index, nft_events[0].event_index);
         }
-    }).collect()
+    }
 }
@@ -1,15 +1,27 @@
 use crate::db_adapters::coin::{FT, FT_LEGACY};
+use crate::db_adapters::contracts_filter::ContractsFilter;
 use crate::db_adapters::nft::NFT;
+use crate::settings::IndexerSettings;
 use bigdecimal::BigDecimal;
 use near_lake_framework::near_indexer_primitives;
 use near_lake_framework::near_indexer_primitives::views::ExecutionStatusView;
+use std::borrow::Cow;
 use std::str::FromStr;
 
-mod coin;
+pub mod coin;
+pub mod contracts;
+pub mod contracts_filter;
+pub mod error;
 mod event_types;
-pub(crate) mod events;
+pub mod events;
 mod nft;
-mod numeric_types;
+pub(crate) mod numeric_types;
+pub mod processed_blocks;
+pub mod reindex;
+pub mod schema;
+pub mod validation;
+
+pub use processed_blocks::find_missing_blocks;
 
 pub(crate) const CHUNK_SIZE_FOR_BATCH_INSERT: usize = 100;
 pub(crate) const RETRY_COUNT: usize = 10;
@@ -25,6 +37,21 @@ pub(crate) enum Event {
     WrapNear,
 }
 
+/// Escapes the control chars (newlines, ANSI sequences) of the on-chain string before logging,
+/// so that the contract can't forge the log lines
+pub(crate) fn sanitize_for_log(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_control() {
+                c.escape_default().to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
 pub(crate) struct EventBase {
     pub standard: String,
     pub receipt_id: String,
@@ -38,7 +65,7 @@ pub(crate) fn get_base(
     event_type: Event,
     outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
     block_header: &near_indexer_primitives::views::BlockHeaderView,
-) -> anyhow::Result<EventBase> {
+) -> Result<EventBase, error::IndexerError> {
     Ok(EventBase {
         standard: get_standard(&event_type),
         receipt_id: outcome.receipt.receipt_id.to_string(),
@@ -63,6 +90,35 @@ fn get_standard(event_type: &Event) -> String {
     .to_string()
 }
 
+// Lake gives the shards ordered by id, but the deterministic ordering shouldn't rely on it
+pub(crate) fn ordered_shards<'a>(
+    settings: &IndexerSettings,
+    streamer_message: &'a near_indexer_primitives::StreamerMessage,
+) -> Vec<&'a near_indexer_primitives::IndexerShard> {
+    let mut shards: Vec<_> = streamer_message.shards.iter().collect();
+    if settings.deterministic_ordering {
+        shards.sort_by_key(|shard| shard.shard_id);
+    }
+    shards
+}
+
+// Leaves only the outcomes of the contracts allowed by the filter
+pub(crate) fn filter_outcomes_by_contracts<'a>(
+    receipt_execution_outcomes: &'a [near_indexer_primitives::IndexerExecutionOutcomeWithReceipt],
+    contracts_filter: &ContractsFilter,
+) -> Cow<'a, [near_indexer_primitives::IndexerExecutionOutcomeWithReceipt]> {
+    if contracts_filter.allows_everything() {
+        return Cow::Borrowed(receipt_execution_outcomes);
+    }
+    Cow::Owned(
+        receipt_execution_outcomes
+            .iter()
+            .filter(|outcome| contracts_filter.is_allowed(&outcome.receipt.receiver_id))
+            .cloned()
+            .collect(),
+    )
+}
+
 fn get_status(status: &ExecutionStatusView) -> String {
     match status {
         ExecutionStatusView::Unknown => {
@@ -79,6 +135,79 @@ fn get_status(status: &ExecutionStatusView) -> String {
     .to_string()
 }
 
+// The block could be partially written if the indexer stopped in the middle of it.
+// The indexes are stable, so on the second attempt we drop the events which are already stored
+pub(crate) async fn skip_stored_events<T: crate::models::SqlMethods>(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    events: &mut Vec<T>,
+    block_height: u64,
+    event_index: fn(&T) -> &BigDecimal,
+) -> anyhow::Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let stored_indexes: std::collections::HashSet<BigDecimal> = sqlx::query_scalar(&format!(
+        "SELECT event_index FROM {} WHERE block_height = $1",
+        T::name()
+    ))
+    .bind(BigDecimal::from(block_height))
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect();
+    if stored_indexes.is_empty() {
+        return Ok(());
+    }
+    let events_count = events.len();
+    events.retain(|event| !stored_indexes.contains(event_index(event)));
+    tracing::info!(
+        target: crate::LOGGING_PREFIX,
+        block_height,
+        table = %T::name(),
+        skipped = events_count - events.len(),
+        "Skipping the events already stored for the block",
+    );
+    Ok(())
+}
+
+/// How the event is found, stored to `contracts.detection_method`
+fn get_detection_method(event_type: &Event) -> &'static str {
+    match event_type {
+        Event::Nep141 | Event::Nep171 => "events",
+        Event::Aurora => "legacy_aurora",
+        Event::RainbowBridge => "legacy_rainbow_bridge",
+        Event::Skyward => "legacy_skyward",
+        Event::TknNear => "legacy_tkn_near",
+        Event::Wentokensir => "legacy_wentokensir",
+        Event::WrapNear => "legacy_wrap_near",
+    }
+}
+
+// Reverse of `compose_db_index` for the event type part
+fn event_type_from_db_index(db_index: &BigDecimal) -> Option<Event> {
+    let db_index = u128::from_str(&db_index.with_scale(0).to_string()).ok()?;
+    match (db_index / 1_000_000) % 1000 {
+        1 => Some(Event::Nep141),
+        2 => Some(Event::Nep171),
+        3 => Some(Event::Aurora),
+        4 => Some(Event::RainbowBridge),
+        5 => Some(Event::Skyward),
+        6 => Some(Event::TknNear),
+        7 => Some(Event::Wentokensir),
+        8 => Some(Event::WrapNear),
+        _ => None,
+    }
+}
+
+/// The detection method of the stored event, `None` for the unknown event type
+pub(crate) fn detection_method_from_db_index(db_index: &BigDecimal) -> Option<&'static str> {
+    event_type_from_db_index(db_index)
+        .as_ref()
+        .map(get_detection_method)
+}
+
+// The index depends only on the block, shard, event type and the position of the event,
+// so it's stable between the runs and could be used as the unique key for deduplication
 fn compose_db_index(
     block_timestamp: u64,
     shard_id: &near_primitives::types::ShardId,
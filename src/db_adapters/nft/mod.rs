@@ -1,8 +1,11 @@
+use crate::db_adapters::contracts_filter::ContractsFilter;
 use crate::db_adapters::Event;
 use crate::models;
 use crate::models::nft_events::NftEvent;
-use futures::future::try_join_all;
+use crate::settings::IndexerSettings;
+use crate::sinks::EventSink;
 use near_lake_framework::near_indexer_primitives;
+use tracing::Instrument;
 
 mod nep171_events;
 
@@ -11,20 +14,48 @@ pub const NFT: &str = "NFT_NEP171";
 
 pub(crate) async fn store_nft(
     pool: &sqlx::Pool<sqlx::Postgres>,
+    settings: &IndexerSettings,
     streamer_message: &near_indexer_primitives::StreamerMessage,
-) -> anyhow::Result<()> {
+    contracts_filter: &ContractsFilter,
+    sink: &dyn EventSink,
+) -> anyhow::Result<Vec<NftEvent>> {
     let mut nep171_events: Vec<NftEvent> = vec![];
-    let nft_events_futures = streamer_message.shards.iter().map(|shard| {
-        nep171_events::collect_nep171_events(
-            &shard.shard_id,
-            &shard.receipt_execution_outcomes,
-            &streamer_message.block.header,
-        )
-    });
-    for events in try_join_all(nft_events_futures).await? {
+    let nft_events_futures: Vec<_> = crate::db_adapters::ordered_shards(settings, streamer_message)
+        .into_iter()
+        .map(|shard| async {
+            nep171_events::collect_nep171_events(
+                settings,
+                &shard.shard_id,
+                &crate::db_adapters::filter_outcomes_by_contracts(
+                    &shard.receipt_execution_outcomes,
+                    contracts_filter,
+                ),
+                &streamer_message.block.header,
+            )
+            .instrument(tracing::info_span!("shard", shard_id = shard.shard_id))
+            .await
+        })
+        .collect();
+    for events in
+        crate::db_adapters::events::try_join_all_ordered(settings, nft_events_futures).await?
+    {
         nep171_events.extend(events);
     }
-    models::chunked_insert(pool, &nep171_events).await
+    // The events stored by the previous attempt of the block are not inserted, counted and emitted again
+    let mut new_events = nep171_events.clone();
+    if settings.store_to_db {
+        crate::db_adapters::skip_stored_events(
+            pool,
+            &mut new_events,
+            streamer_message.block.header.height,
+            |event| &event.event_index,
+        )
+        .await?;
+        models::chunked_insert(pool, &new_events).await?;
+    }
+    crate::metrics::NFT_EVENTS_TOTAL.inc_by(new_events.len() as u64);
+    sink.emit_nft_events(&new_events).await?;
+    Ok(nep171_events)
 }
 
 // todo it could be one method both for ft and nft
@@ -9,17 +9,27 @@ use num_traits::Zero;
 
 use crate::db_adapters::event_types;
 use crate::db_adapters::nft::NFT;
+use crate::settings::IndexerSettings;
 
 pub(crate) async fn collect_nep171_events(
+    settings: &IndexerSettings,
     shard_id: &near_indexer_primitives::types::ShardId,
     receipt_execution_outcomes: &[near_indexer_primitives::IndexerExecutionOutcomeWithReceipt],
     block_header: &near_indexer_primitives::views::BlockHeaderView,
 ) -> anyhow::Result<Vec<NftEvent>> {
     let mut res = Vec::new();
     for outcome in receipt_execution_outcomes {
-        for event in events::extract_events(outcome) {
-            if let event_types::NearEvent::Nep171(nft_events) = event {
-                compose_nft_db_events(&nft_events, outcome, block_header)?;
+        for extracted_event in events::extract_events(settings, outcome) {
+            if let event_types::NearEvent::Nep171(nft_events) = &extracted_event.event {
+                if extracted_event.should_skip_as_duplicate(settings, outcome) {
+                    continue;
+                }
+                let raw_event = events::raw_event_to_store(settings, &extracted_event.raw_log);
+                for mut event in compose_nft_db_events(settings, nft_events, outcome, block_header)?
+                {
+                    event.raw_event = raw_event.clone();
+                    res.push(event);
+                }
             }
         }
     }
@@ -29,15 +39,18 @@ pub(crate) async fn collect_nep171_events(
 }
 
 fn compose_nft_db_events(
+    settings: &IndexerSettings,
     events: &Nep171Event,
     outcome: &near_indexer_primitives::IndexerExecutionOutcomeWithReceipt,
     block_header: &near_indexer_primitives::views::BlockHeaderView,
 ) -> anyhow::Result<Vec<NftEvent>> {
     let mut nft_events = vec![];
     let contract_id = &outcome.receipt.receiver_id;
+    // One row is stored per token, `token_ids` keeps all the tokens of the same event entry
     match &events.event_kind {
         event_types::Nep171EventKind::NftMint(mint_events) => {
             for mint_event in mint_events {
+                let token_ids = escape_token_ids(&mint_event.token_ids);
                 for token_id in &mint_event.token_ids {
                     nft_events.push(NftEvent {
                         event_index: BigDecimal::zero(), // initialized later
@@ -57,13 +70,16 @@ fn compose_nft_db_events(
                         event_memo: mint_event
                             .memo
                             .as_ref()
-                            .map(|s| s.escape_default().to_string()),
+                            .map(|s| events::prepare_memo(settings, s)),
+                        token_ids: token_ids.clone(),
+                        raw_event: None, // initialized later
                     });
                 }
             }
         }
         event_types::Nep171EventKind::NftTransfer(transfer_events) => {
             for transfer_event in transfer_events {
+                let token_ids = escape_token_ids(&transfer_event.token_ids);
                 for token_id in &transfer_event.token_ids {
                     nft_events.push(NftEvent {
                         event_index: BigDecimal::zero(), // initialized later
@@ -88,13 +104,16 @@ fn compose_nft_db_events(
                         event_memo: transfer_event
                             .memo
                             .as_ref()
-                            .map(|s| s.escape_default().to_string()),
+                            .map(|s| events::prepare_memo(settings, s)),
+                        token_ids: token_ids.clone(),
+                        raw_event: None, // initialized later
                     });
                 }
             }
         }
         event_types::Nep171EventKind::NftBurn(burn_events) => {
             for burn_event in burn_events {
+                let token_ids = escape_token_ids(&burn_event.token_ids);
                 for token_id in &burn_event.token_ids {
                     nft_events.push(NftEvent {
                         event_index: BigDecimal::zero(), // initialized later
@@ -117,7 +136,9 @@ fn compose_nft_db_events(
                         event_memo: burn_event
                             .memo
                             .as_ref()
-                            .map(|s| s.escape_default().to_string()),
+                            .map(|s| events::prepare_memo(settings, s)),
+                        token_ids: token_ids.clone(),
+                        raw_event: None, // initialized later
                     });
                 }
             }
@@ -125,3 +146,266 @@ fn compose_nft_db_events(
     }
     Ok(nft_events)
 }
+
+fn escape_token_ids(token_ids: &[String]) -> Vec<String> {
+    token_ids
+        .iter()
+        .map(|token_id| token_id.escape_default().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn multi_token_mint_block(block_height: u64) -> near_indexer_primitives::StreamerMessage {
+        let mint_log = test_utils::nft_mint_log("alice.near", &["1", "2", "3"]);
+        let transfer_log = r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_transfer","data":[{"old_owner_id":"alice.near","new_owner_id":"bob.near","token_ids":["2"]}]}"#;
+        test_utils::streamer_message(
+            block_height,
+            vec![test_utils::logs_outcome(
+                "mint",
+                "nft.near",
+                &[&mint_log, transfer_log],
+            )],
+        )
+    }
+
+    #[tokio::test]
+    async fn multi_token_mint_is_one_row_per_token() {
+        let block = multi_token_mint_block(781_001);
+        let events = collect_nep171_events(
+            &IndexerSettings::default(),
+            &0,
+            &block.shards[0].receipt_execution_outcomes,
+            &block.block.header,
+        )
+        .await
+        .unwrap();
+
+        let rows: Vec<_> = events
+            .iter()
+            .map(|event| {
+                (
+                    event.cause.as_str(),
+                    event.token_id.as_str(),
+                    event.token_ids.join(","),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("MINT", "1", "1,2,3".to_string()),
+                ("MINT", "2", "1,2,3".to_string()),
+                ("MINT", "3", "1,2,3".to_string()),
+                ("TRANSFER", "2", "2".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn multi_token_mint_is_stored_with_all_token_ids() {
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let block = multi_token_mint_block(781_002);
+        let events = collect_nep171_events(
+            &IndexerSettings::default(),
+            &0,
+            &block.shards[0].receipt_execution_outcomes,
+            &block.block.header,
+        )
+        .await
+        .unwrap();
+        crate::models::chunked_insert(&pool, &events).await.unwrap();
+
+        let rows: Vec<(String, String, Vec<String>)> = sqlx::query_as(
+            "SELECT cause, token_id, token_ids FROM nft_events ORDER BY event_index",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        let all_token_ids = vec!["1".to_string(), "2".to_string(), "3".to_string()];
+        assert_eq!(
+            rows,
+            vec![
+                ("MINT".to_string(), "1".to_string(), all_token_ids.clone()),
+                ("MINT".to_string(), "2".to_string(), all_token_ids.clone()),
+                ("MINT".to_string(), "3".to_string(), all_token_ids),
+                (
+                    "TRANSFER".to_string(),
+                    "2".to_string(),
+                    vec!["2".to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn multi_owner_mint_is_one_row_per_token() {
+        let mint_log = r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_mint","data":[{"owner_id":"alice.near","token_ids":["a1","a2"]},{"owner_id":"bob.near","token_ids":["b1","b2","b3"]}]}"#;
+        let block = test_utils::streamer_message(
+            820_001,
+            vec![test_utils::logs_outcome("mint", "nft.near", &[mint_log])],
+        );
+        let events = collect_nep171_events(
+            &IndexerSettings::default(),
+            &0,
+            &block.shards[0].receipt_execution_outcomes,
+            &block.block.header,
+        )
+        .await
+        .unwrap();
+
+        let rows: Vec<_> = events
+            .iter()
+            .map(|event| {
+                (
+                    event.new_owner_account_id.as_deref().unwrap(),
+                    event.token_id.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("alice.near", "a1"),
+                ("alice.near", "a2"),
+                ("bob.near", "b1"),
+                ("bob.near", "b2"),
+                ("bob.near", "b3"),
+            ]
+        );
+        let event_indexes: std::collections::HashSet<_> =
+            events.iter().map(|event| &event.event_index).collect();
+        assert_eq!(event_indexes.len(), events.len());
+    }
+
+    async fn collect_log(block_height: u64, log: &str) -> Vec<NftEvent> {
+        let block = test_utils::streamer_message(
+            block_height,
+            vec![test_utils::logs_outcome("nft", "nft.near", &[log])],
+        );
+        collect_nep171_events(
+            &IndexerSettings::default(),
+            &0,
+            &block.shards[0].receipt_execution_outcomes,
+            &block.block.header,
+        )
+        .await
+        .unwrap()
+    }
+
+    // token id, old owner, new owner, authorized account
+    type Owners<'a> = (&'a str, Option<&'a str>, Option<&'a str>, Option<&'a str>);
+
+    fn owners(events: &[NftEvent]) -> Vec<Owners<'_>> {
+        events
+            .iter()
+            .map(|event| {
+                (
+                    event.token_id.as_str(),
+                    event.old_owner_account_id.as_deref(),
+                    event.new_owner_account_id.as_deref(),
+                    event.authorized_account_id.as_deref(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn authorized_transfer_keeps_operator_per_token() {
+        let transfer_log = r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_transfer","data":[{"authorized_id":"market.near","old_owner_id":"alice.near","new_owner_id":"bob.near","token_ids":["1","2"],"memo":"sale"},{"old_owner_id":"carol.near","new_owner_id":"dave.near","token_ids":["3"]}]}"#;
+
+        let events = collect_log(867_001, transfer_log).await;
+
+        assert_eq!(
+            owners(&events),
+            vec![
+                (
+                    "1",
+                    Some("alice.near"),
+                    Some("bob.near"),
+                    Some("market.near")
+                ),
+                (
+                    "2",
+                    Some("alice.near"),
+                    Some("bob.near"),
+                    Some("market.near")
+                ),
+                ("3", Some("carol.near"), Some("dave.near"), None),
+            ]
+        );
+        assert!(events.iter().all(|event| event.cause == "TRANSFER"));
+        assert_eq!(events[0].event_memo.as_deref(), Some("sale"));
+
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        crate::models::chunked_insert(&pool, &events).await.unwrap();
+        let stored: Vec<(String, Option<String>)> = sqlx::query_as(
+            "SELECT token_id, authorized_account_id FROM nft_events ORDER BY event_index",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            stored,
+            vec![
+                ("1".to_string(), Some("market.near".to_string())),
+                ("2".to_string(), Some("market.near".to_string())),
+                ("3".to_string(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn authorized_burn_keeps_owner_and_operator() {
+        let burn_log = r#"EVENT_JSON:{"standard":"nep171","version":"1.0.0","event":"nft_burn","data":[{"owner_id":"alice.near","authorized_id":"market.near","token_ids":["1","2"]},{"owner_id":"bob.near","token_ids":["3"]}]}"#;
+
+        let events = collect_log(840_001, burn_log).await;
+
+        assert_eq!(
+            owners(&events),
+            vec![
+                ("1", Some("alice.near"), None, Some("market.near")),
+                ("2", Some("alice.near"), None, Some("market.near")),
+                ("3", Some("bob.near"), None, None),
+            ]
+        );
+        assert!(events.iter().all(|event| event.cause == "BURN"));
+
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        crate::models::chunked_insert(&pool, &events).await.unwrap();
+        let stored: Vec<(String, Option<String>, Option<String>)> = sqlx::query_as(
+            "SELECT token_id, old_owner_account_id, authorized_account_id FROM nft_events ORDER BY event_index",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            stored,
+            vec![
+                (
+                    "1".to_string(),
+                    Some("alice.near".to_string()),
+                    Some("market.near".to_string())
+                ),
+                (
+                    "2".to_string(),
+                    Some("alice.near".to_string()),
+                    Some("market.near".to_string())
+                ),
+                ("3".to_string(), Some("bob.near".to_string()), None),
+            ]
+        );
+    }
+}
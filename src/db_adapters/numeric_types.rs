@@ -1,5 +1,9 @@
+use crate::db_adapters::error::IndexerError;
+use bigdecimal::BigDecimal;
 use borsh::{BorshDeserialize, BorshSerialize};
+use near_lake_framework::near_indexer_primitives::CryptoHash;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::str::FromStr;
 
 // Taken from https://github.com/near/near-sdk-rs/blob/master/near-sdk/src/json_types/integers.rs
 macro_rules! impl_str_type {
@@ -49,3 +53,55 @@ macro_rules! impl_str_type {
 
 impl_str_type!(U128, u128);
 impl_str_type!(U64, u64);
+
+// Amounts are u128 in all the token standards, so we expect a non-negative integer which fits into 128 bits.
+// Only for the raw strings from the logs and the return values, the typed args are already `U128`
+pub(crate) fn parse_amount(
+    amount: &str,
+    receipt_id: &CryptoHash,
+) -> Result<BigDecimal, IndexerError> {
+    let value = amount.parse::<u128>().map_err(|err| {
+        IndexerError::Parse(format!(
+            "Amount `{}` of receipt {} is not a non-negative 128-bit integer: {}",
+            amount, receipt_id, err
+        ))
+    })?;
+    Ok(BigDecimal::from_str(&value.to_string())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(amount: &str) -> Result<BigDecimal, IndexerError> {
+        parse_amount(amount, &CryptoHash::hash_bytes(b"receipt"))
+    }
+
+    #[test]
+    fn valid_amount_is_parsed() {
+        assert_eq!(parse("0").unwrap(), BigDecimal::from(0));
+        assert_eq!(
+            parse(&u128::MAX.to_string()).unwrap(),
+            BigDecimal::from_str(&u128::MAX.to_string()).unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_amount_error_names_amount_and_receipt() {
+        let receipt_id = CryptoHash::hash_bytes(b"receipt").to_string();
+        let overflow = (u128::MAX as f64 * 10.0).to_string();
+        for amount in [
+            "-5",
+            "340282366920938463463374607431768211456",
+            "12abc",
+            "",
+            &overflow,
+        ] {
+            let err = parse(amount).unwrap_err();
+            assert!(matches!(err, IndexerError::Parse(_)), "{amount}: {err:?}");
+            let message = err.to_string();
+            assert!(message.contains(&format!("`{amount}`")), "{message}");
+            assert!(message.contains(&receipt_id), "{message}");
+        }
+    }
+}
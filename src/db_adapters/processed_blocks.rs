@@ -0,0 +1,138 @@
+use crate::models;
+use crate::models::processed_blocks::ProcessedBlock;
+use bigdecimal::BigDecimal;
+use cached::{Cached, SizedCache};
+use near_lake_framework::near_indexer_primitives;
+use std::sync::Mutex;
+
+// Lake delivers the blocks at least once, so the recently processed heights are kept in memory
+// to skip the duplicates without going to the DB
+const RECENTLY_PROCESSED_BLOCKS_CACHE_SIZE: usize = 1000;
+
+lazy_static! {
+    static ref RECENTLY_PROCESSED_BLOCKS: Mutex<SizedCache<u64, ()>> =
+        Mutex::new(SizedCache::with_size(RECENTLY_PROCESSED_BLOCKS_CACHE_SIZE));
+}
+
+/// Checks whether the block is already stored: first in the recently processed heights, then in the DB
+pub(crate) async fn is_processed_block(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    block_height: u64,
+) -> anyhow::Result<bool> {
+    if RECENTLY_PROCESSED_BLOCKS
+        .lock()
+        .expect("Recently processed blocks lock is poisoned")
+        .cache_get(&block_height)
+        .is_some()
+    {
+        return Ok(true);
+    }
+    let (is_processed,): (bool,) =
+        sqlx::query_as("SELECT EXISTS(SELECT 1 FROM processed_blocks WHERE block_height = $1)")
+            .bind(BigDecimal::from(block_height))
+            .fetch_one(pool)
+            .await?;
+    if is_processed {
+        remember_processed_block(block_height);
+    }
+    Ok(is_processed)
+}
+
+fn remember_processed_block(block_height: u64) {
+    RECENTLY_PROCESSED_BLOCKS
+        .lock()
+        .expect("Recently processed blocks lock is poisoned")
+        .cache_set(block_height, ());
+}
+
+// Should be called only after all the events of the block are stored
+pub(crate) async fn store_processed_block(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    block_header: &near_indexer_primitives::views::BlockHeaderView,
+    block_checksum: Option<String>,
+) -> anyhow::Result<()> {
+    let block = ProcessedBlock {
+        block_height: BigDecimal::from(block_header.height),
+        block_timestamp: BigDecimal::from(block_header.timestamp),
+        block_checksum,
+    };
+    models::chunked_insert(pool, &[block]).await?;
+    remember_processed_block(block_header.height);
+    Ok(())
+}
+
+/// Returns the heights from `[from, to]` which are not marked as processed.
+/// Note: the chain may skip some heights, such heights are also returned
+pub async fn find_missing_blocks(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<Vec<u64>> {
+    let missing_blocks: Vec<(i64,)> = sqlx::query_as(
+        "SELECT series.block_height
+         FROM generate_series($1::bigint, $2::bigint) AS series(block_height)
+              LEFT JOIN processed_blocks ON processed_blocks.block_height = series.block_height
+         WHERE processed_blocks.block_height IS NULL
+         ORDER BY series.block_height",
+    )
+    .bind(i64::try_from(from)?)
+    .bind(i64::try_from(to)?)
+    .fetch_all(pool)
+    .await?;
+
+    missing_blocks
+        .into_iter()
+        .map(|(block_height,)| Ok(u64::try_from(block_height)?))
+        .collect()
+}
+
+/// Groups the sorted heights into the ranges of consecutive heights
+pub fn group_into_ranges(block_heights: &[u64]) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    for &block_height in block_heights {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == block_height => *end = block_height,
+            _ => ranges.push((block_height, block_height)),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn groups_consecutive_heights() {
+        assert_eq!(group_into_ranges(&[]), vec![]);
+        assert_eq!(group_into_ranges(&[5]), vec![(5, 5)]);
+        assert_eq!(
+            group_into_ranges(&[1, 2, 3, 7, 9, 10]),
+            vec![(1, 3), (7, 7), (9, 10)]
+        );
+    }
+
+    #[tokio::test]
+    async fn finds_gap_in_processed_blocks() {
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        for block_height in (785_001..=785_010).filter(|h| !(785_004..=785_006).contains(h)) {
+            store_processed_block(&pool, &test_utils::block_header(block_height), None)
+                .await
+                .unwrap();
+        }
+
+        let missing_blocks = find_missing_blocks(&pool, 785_001, 785_012).await.unwrap();
+        assert_eq!(
+            missing_blocks,
+            vec![785_004, 785_005, 785_006, 785_011, 785_012]
+        );
+        assert_eq!(
+            group_into_ranges(&missing_blocks),
+            vec![(785_004, 785_006), (785_011, 785_012)]
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use bigdecimal::BigDecimal;
+
+/// What `delete_block_range` removed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeletedRows {
+    pub events: u64,
+    pub processed_blocks: u64,
+    pub supply_snapshots: u64,
+    pub balance_snapshots: u64,
+}
+
+impl DeletedRows {
+    pub fn total(&self) -> u64 {
+        self.events + self.processed_blocks + self.supply_snapshots + self.balance_snapshots
+    }
+}
+
+/// Removes everything stored for the blocks `[from, to]`, so that the range could be indexed again from scratch.
+/// The snapshots are cumulative, so all of them since `from` are removed, `restore_after_reindex` rebuilds them.
+/// The contracts stay, their inconsistency marks are kept.
+/// Everything is deleted in one transaction, it's safe to run it again for the same range
+pub async fn delete_block_range(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<DeletedRows> {
+    let from = BigDecimal::from(from);
+    let to = BigDecimal::from(to);
+    let mut transaction = pool.begin().await?;
+    let mut deleted_rows = DeletedRows::default();
+    for query in [
+        "DELETE FROM coin_events WHERE block_height BETWEEN $1 AND $2",
+        "DELETE FROM nft_events WHERE block_height BETWEEN $1 AND $2",
+    ] {
+        deleted_rows.events += sqlx::query(query)
+            .bind(&from)
+            .bind(&to)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+    }
+    // Otherwise the blocks are skipped as already processed
+    deleted_rows.processed_blocks +=
+        sqlx::query("DELETE FROM processed_blocks WHERE block_height BETWEEN $1 AND $2")
+            .bind(&from)
+            .bind(&to)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+    deleted_rows.supply_snapshots +=
+        sqlx::query("DELETE FROM contract_supply_snapshots WHERE block_height >= $1")
+            .bind(&from)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+    deleted_rows.balance_snapshots +=
+        sqlx::query("DELETE FROM account_balance_snapshots WHERE block_height >= $1")
+            .bind(&from)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+    transaction.commit().await?;
+    Ok(deleted_rows)
+}
+
+/// Brings the derived tables in line with the events once the range since `from` is indexed again:
+/// updates the contracts first seen since `from` and rebuilds the snapshots since `from`.
+/// The snapshots are rebuilt only if they are used: either they were deleted or the current run stores them.
+/// Returns the number of the restored contracts
+pub async fn restore_after_reindex(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    from: u64,
+    rebuild_supply_snapshots: bool,
+    rebuild_balance_snapshots: bool,
+) -> anyhow::Result<usize> {
+    crate::db_adapters::contracts::refresh_first_events_since(pool, from).await?;
+    let contracts_count = crate::db_adapters::contracts::rebuild_contracts(pool).await?;
+    if rebuild_supply_snapshots {
+        crate::db_adapters::coin::supply::rebuild_supply_snapshots(pool, from).await?;
+    }
+    if rebuild_balance_snapshots {
+        crate::db_adapters::coin::balances::rebuild_balance_snapshots(pool, from).await?;
+    }
+    Ok(contracts_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn reindexed_range_leaves_no_leftovers() {
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        for block_height in 869_001..=869_010 {
+            crate::db_adapters::processed_blocks::store_processed_block(
+                &pool,
+                &test_utils::block_header(block_height),
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        let reindexed_event = test_utils::coin_event(2, 869_005, "token.near", "bob.near", 50);
+        let events = [
+            test_utils::coin_event(1, 869_002, "token.near", "alice.near", 100),
+            reindexed_event.clone(),
+            test_utils::coin_event(3, 869_008, "token.near", "alice.near", -30),
+            // The contract is not found on the reindexing, it should disappear
+            test_utils::coin_event(4, 869_005, "gone.near", "carol.near", 10),
+        ];
+        crate::models::chunked_insert(&pool, &events).await.unwrap();
+        crate::db_adapters::contracts::rebuild_contracts(&pool)
+            .await
+            .unwrap();
+        crate::db_adapters::coin::supply::rebuild_supply_snapshots(&pool, 0)
+            .await
+            .unwrap();
+        crate::db_adapters::coin::balances::rebuild_balance_snapshots(&pool, 0)
+            .await
+            .unwrap();
+        sqlx::query(
+            "UPDATE contracts
+             SET inconsistency_found_at_timestamp = 1, inconsistency_found_at_block_height = 869009,
+                 detection_method = 'legacy_test'
+             WHERE contract_account_id = 'token.near'",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let deleted_rows = delete_block_range(&pool, 869_004, 869_006).await.unwrap();
+
+        assert_eq!(
+            deleted_rows,
+            DeletedRows {
+                events: 2,
+                processed_blocks: 3,
+                supply_snapshots: 3,
+                balance_snapshots: 3,
+            }
+        );
+        let leftovers: i64 = sqlx::query_scalar(
+            "SELECT (SELECT COUNT(*) FROM coin_events WHERE block_height BETWEEN 869004 AND 869006)
+                  + (SELECT COUNT(*) FROM processed_blocks WHERE block_height BETWEEN 869004 AND 869006)
+                  + (SELECT COUNT(*) FROM contract_supply_snapshots WHERE block_height >= 869004)
+                  + (SELECT COUNT(*) FROM account_balance_snapshots WHERE block_height >= 869004)",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(leftovers, 0);
+
+        crate::models::chunked_insert(&pool, &[reindexed_event])
+            .await
+            .unwrap();
+        let contracts_count = restore_after_reindex(&pool, 869_004, true, true)
+            .await
+            .unwrap();
+
+        assert_eq!(contracts_count, 1);
+        let contracts: Vec<(String, BigDecimal, Option<BigDecimal>, Option<String>)> =
+            sqlx::query_as(
+                "SELECT contract_account_id, first_event_at_block_height,
+                        inconsistency_found_at_block_height, detection_method
+                 FROM contracts",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            contracts,
+            vec![(
+                "token.near".to_string(),
+                BigDecimal::from(869_002),
+                Some(BigDecimal::from(869_009)),
+                Some("legacy_test".to_string())
+            )]
+        );
+        let supply: Vec<(String, BigDecimal, BigDecimal)> = sqlx::query_as(
+            "SELECT contract_account_id, block_height, supply FROM contract_supply_snapshots
+             ORDER BY contract_account_id, block_height",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            supply,
+            vec![
+                ("token.near".to_string(), 869_002.into(), 100.into()),
+                ("token.near".to_string(), 869_005.into(), 150.into()),
+                ("token.near".to_string(), 869_008.into(), 120.into()),
+            ]
+        );
+        let balances: Vec<(String, BigDecimal, BigDecimal)> = sqlx::query_as(
+            "SELECT account_id, block_height, balance FROM account_balance_snapshots
+             ORDER BY account_id, block_height",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            balances,
+            vec![
+                ("alice.near".to_string(), 869_002.into(), 100.into()),
+                ("alice.near".to_string(), 869_008.into(), 70.into()),
+                ("bob.near".to_string(), 869_005.into(), 50.into()),
+            ]
+        );
+    }
+}
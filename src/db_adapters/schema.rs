@@ -0,0 +1,211 @@
+use std::collections::{HashMap, HashSet};
+
+// The columns the indexer writes to, should be updated together with the migrations
+const EXPECTED_COLUMNS: &[(&str, &[&str])] = &[
+    (
+        "coin_events",
+        &[
+            "event_index",
+            "standard",
+            "receipt_id",
+            "block_height",
+            "block_timestamp",
+            "contract_account_id",
+            "affected_account_id",
+            "involved_account_id",
+            "delta_amount",
+            "cause",
+            "status",
+            "event_memo",
+            "raw_event",
+        ],
+    ),
+    (
+        "nft_events",
+        &[
+            "event_index",
+            "standard",
+            "receipt_id",
+            "block_height",
+            "block_timestamp",
+            "contract_account_id",
+            "token_id",
+            "cause",
+            "status",
+            "old_owner_account_id",
+            "new_owner_account_id",
+            "authorized_account_id",
+            "event_memo",
+            "token_ids",
+            "raw_event",
+        ],
+    ),
+    (
+        "processed_blocks",
+        &["block_height", "block_timestamp", "block_checksum"],
+    ),
+    (
+        "contracts",
+        &[
+            "contract_account_id",
+            "standard",
+            "first_event_at_timestamp",
+            "first_event_at_block_height",
+            "inconsistency_found_at_timestamp",
+            "inconsistency_found_at_block_height",
+            "detection_method",
+            "standard_changed_at_timestamp",
+            "standard_changed_at_block_height",
+        ],
+    ),
+    (
+        "contract_metadata",
+        &[
+            "contract_account_id",
+            "spec",
+            "name",
+            "symbol",
+            "decimals",
+            "fetched_at_block_height",
+        ],
+    ),
+    (
+        "contract_supply_snapshots",
+        &[
+            "contract_account_id",
+            "block_height",
+            "block_timestamp",
+            "supply",
+        ],
+    ),
+    (
+        "account_balance_snapshots",
+        &[
+            "contract_account_id",
+            "account_id",
+            "block_height",
+            "block_timestamp",
+            "balance",
+        ],
+    ),
+    (
+        "nep141_cutovers",
+        &["contract_account_id", "block_height", "block_timestamp"],
+    ),
+];
+
+/// Tables which are written regardless of the options
+pub const REQUIRED_TABLES: &[&str] = &[
+    "coin_events",
+    "nft_events",
+    "processed_blocks",
+    "contracts",
+    "nep141_cutovers",
+];
+
+/// Checks that the given tables and all their columns exist in the schema.
+/// Otherwise the first insert fails in the middle of the stream with a cryptic error,
+/// so it's better to fail at the start with the list of what's missing
+pub async fn check_schema(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    schema: &str,
+    tables: &[&str],
+) -> anyhow::Result<()> {
+    let columns: Vec<(String, String)> = sqlx::query_as(
+        "SELECT table_name::text, column_name::text
+         FROM information_schema.columns
+         WHERE table_schema = $1",
+    )
+    .bind(schema)
+    .fetch_all(pool)
+    .await?;
+    let mut existing: HashMap<String, HashSet<String>> = HashMap::new();
+    for (table, column) in columns {
+        existing.entry(table).or_default().insert(column);
+    }
+
+    let mut missing = vec![];
+    for table in tables {
+        let expected_columns = EXPECTED_COLUMNS
+            .iter()
+            .find(|(name, _)| name == table)
+            .map(|(_, columns)| *columns)
+            .ok_or_else(|| anyhow::anyhow!("Unknown table `{}`", table))?;
+        match existing.get(*table) {
+            None => missing.push(format!("table {}", table)),
+            Some(existing_columns) => missing.extend(
+                expected_columns
+                    .iter()
+                    .filter(|column| !existing_columns.contains(**column))
+                    .map(|column| format!("column {}.{}", table, column)),
+            ),
+        }
+    }
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "Database schema `{}` is out of date, apply the migrations. Missing: {}",
+            schema,
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::coin_events::CoinEvent;
+    use crate::models::CopyMethods;
+
+    #[test]
+    fn copy_columns_are_the_expected_ones() {
+        let expected_columns = EXPECTED_COLUMNS
+            .iter()
+            .find(|(table, _)| *table == "coin_events")
+            .map(|(_, columns)| *columns);
+
+        assert_eq!(Some(CoinEvent::columns()), expected_columns);
+    }
+
+    #[tokio::test]
+    async fn missing_columns_and_tables_are_listed() {
+        let pool = match crate::test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let schema: String = sqlx::query_scalar("SELECT current_schema()::text")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        check_schema(&pool, &schema, REQUIRED_TABLES).await.unwrap();
+
+        sqlx::query("ALTER TABLE contracts DROP COLUMN detection_method")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DROP TABLE nep141_cutovers")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let err = check_schema(&pool, &schema, REQUIRED_TABLES)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Database schema `{}` is out of date, apply the migrations. \
+                 Missing: column contracts.detection_method, table nep141_cutovers",
+                schema
+            )
+        );
+        // Only the given tables are checked
+        check_schema(
+            &pool,
+            &schema,
+            &["coin_events", "account_balance_snapshots"],
+        )
+        .await
+        .unwrap();
+    }
+}
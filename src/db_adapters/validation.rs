@@ -0,0 +1,156 @@
+use crate::db_adapters::{coin, events};
+use crate::settings::IndexerSettings;
+use near_lake_framework::near_indexer_primitives;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+/// What the indexer drops for the contract
+#[derive(Debug, Default)]
+pub struct ContractReport {
+    /// `EVENT_JSON` logs we failed to parse
+    pub unparsed_events: u64,
+    /// Legacy function calls we don't know how to handle
+    pub unknown_method_calls: u64,
+    pub unknown_methods: BTreeSet<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    pub contracts: BTreeMap<String, ContractReport>,
+}
+
+lazy_static! {
+    // Collected only while the validation is running
+    static ref VALIDATION_REPORT: Mutex<Option<ValidationReport>> = Mutex::new(None);
+}
+
+fn with_contract_report(contract_id: &str, f: impl FnOnce(&mut ContractReport)) {
+    if let Some(report) = VALIDATION_REPORT
+        .lock()
+        .expect("Validation report lock is poisoned")
+        .as_mut()
+    {
+        f(report.contracts.entry(contract_id.to_string()).or_default());
+    }
+}
+
+pub(crate) fn record_unknown_method(contract_id: &str, method_name: &str) {
+    with_contract_report(contract_id, |report| {
+        report.unknown_method_calls += 1;
+        report.unknown_methods.insert(method_name.to_string());
+    });
+}
+
+pub fn start_validation() {
+    *VALIDATION_REPORT
+        .lock()
+        .expect("Validation report lock is poisoned") = Some(ValidationReport::default());
+}
+
+pub fn finish_validation() -> ValidationReport {
+    VALIDATION_REPORT
+        .lock()
+        .expect("Validation report lock is poisoned")
+        .take()
+        .unwrap_or_default()
+}
+
+/// Runs the extraction of the events without storing them, the problems go to the validation report.
+/// Should be called between `start_validation` and `finish_validation`
+pub async fn validate_block(
+    settings: &IndexerSettings,
+    streamer_message: &near_indexer_primitives::StreamerMessage,
+    chain_id: &str,
+) -> anyhow::Result<()> {
+    for shard in &streamer_message.shards {
+        for outcome in &shard.receipt_execution_outcomes {
+            let unparsed_events = outcome
+                .execution_outcome
+                .outcome
+                .logs
+                .iter()
+                .filter_map(|log| {
+                    events::strip_event_prefix(log.trim(), settings.case_insensitive_event_prefix)
+                })
+                .filter(|event_json| events::parse_event(settings, event_json.trim()).is_err())
+                .count() as u64;
+            if unparsed_events > 0 {
+                with_contract_report(outcome.receipt.receiver_id.as_str(), |report| {
+                    report.unparsed_events += unparsed_events
+                });
+            }
+        }
+        // Unknown methods are recorded by the legacy handlers themselves
+        coin::legacy::collect_legacy(
+            None,
+            settings,
+            &shard.shard_id,
+            &shard.receipt_execution_outcomes,
+            &streamer_message.block.header,
+            chain_id,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn report_counts_unparsed_events_and_unknown_methods() {
+        let _globals = test_utils::lock_globals().await;
+        let block = test_utils::streamer_message(
+            823_001,
+            vec![
+                test_utils::function_call_outcome(
+                    "unknown method",
+                    "alice.near",
+                    "wrap.near",
+                    "brand_new_method",
+                    b"{}",
+                    &[],
+                ),
+                test_utils::function_call_outcome(
+                    "unknown method again",
+                    "bob.near",
+                    "wrap.near",
+                    "brand_new_method",
+                    b"{}",
+                    &[],
+                ),
+                test_utils::logs_outcome(
+                    "broken events",
+                    "token.near",
+                    &[
+                        r#"EVENT_JSON:{"standard":"nep141""#,
+                        "EVENT_JSON:not a json",
+                        &test_utils::ft_mint_log("alice.near", "1"),
+                    ],
+                ),
+            ],
+        );
+
+        start_validation();
+        validate_block(&IndexerSettings::default(), &block, "mainnet")
+            .await
+            .unwrap();
+        let report = finish_validation();
+
+        assert_eq!(report.contracts.len(), 2);
+        let wrap_near = &report.contracts["wrap.near"];
+        assert_eq!(wrap_near.unparsed_events, 0);
+        assert_eq!(wrap_near.unknown_method_calls, 2);
+        assert_eq!(
+            wrap_near.unknown_methods,
+            BTreeSet::from(["brand_new_method".to_string()])
+        );
+        let token = &report.contracts["token.near"];
+        assert_eq!(token.unparsed_events, 2);
+        assert_eq!(token.unknown_method_calls, 0);
+        // Nothing is collected outside of the validation
+        assert!(finish_validation().contracts.is_empty());
+    }
+}
@@ -1,22 +1,728 @@
-use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, ItemStruct};
-
-#[proc_macro_derive(FieldCount)]
-pub fn derive_field_count(input: TokenStream) -> TokenStream {
-    let input = parse_macro_input!(input as ItemStruct);
-
-    let name = &input.ident;
-    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
-    let field_count = input.fields.iter().count();
-
-    let output = quote! {
-        impl #impl_generics FieldCount for #name #ty_generics #where_clause {
-            fn field_count() -> usize {
-                #field_count
+//! Collects balance-changing events about FTs, NFTs from the NEAR blockchain and stores them to Postgres.
+//!
+//! The binary is a thin wrapper around this crate: it reads the blocks from NEAR Lake
+//! and passes each of them to [`handle_streamer_message`].
+//! You can do the same with your own stream of `StreamerMessage`s.
+use near_lake_framework::near_indexer_primitives;
+use tracing::Instrument;
+
+pub mod db_adapters;
+pub mod metrics;
+pub mod models;
+pub mod rpc_helpers;
+pub mod settings;
+pub mod sinks;
+#[cfg(test)]
+mod test_utils;
+
+#[macro_use]
+extern crate lazy_static;
+
+pub const LOGGING_PREFIX: &str = "indexer_events";
+
+// The highest block committed by this process. The blocks go one by one now,
+// but with the concurrent processing a lower height could be committed after a higher one
+static MAX_COMMITTED_BLOCK_HEIGHT: std::sync::atomic::AtomicU64 =
+    std::sync::atomic::AtomicU64::new(0);
+
+fn track_committed_block_height(block_height: u64) {
+    let max_committed_height =
+        MAX_COMMITTED_BLOCK_HEIGHT.fetch_max(block_height, std::sync::atomic::Ordering::Relaxed);
+    if block_height < max_committed_height {
+        metrics::OUT_OF_ORDER_COMMITS_TOTAL.inc();
+        tracing::debug!(
+            target: crate::LOGGING_PREFIX,
+            "Block {} is committed after block {}",
+            block_height,
+            max_committed_height
+        );
+    }
+}
+
+const INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+const MAX_DELAY_TIME: std::time::Duration = std::time::Duration::from_secs(120);
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct AccountWithContract {
+    pub account_id: near_primitives::types::AccountId,
+    pub contract_account_id: near_primitives::types::AccountId,
+}
+
+/// What [`handle_streamer_message`] did with the block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandledBlock {
+    /// The events of the block are collected and stored
+    Committed(u64),
+    /// The block was already processed by the previous run, nothing is stored again
+    Skipped(u64),
+}
+
+impl HandledBlock {
+    pub fn block_height(&self) -> u64 {
+        match self {
+            HandledBlock::Committed(block_height) | HandledBlock::Skipped(block_height) => {
+                *block_height
+            }
+        }
+    }
+}
+
+/// Decides when the bounded run is over: `--once` stops after the first handled block, even a skipped one,
+/// `--max-blocks` counts only the committed blocks
+#[derive(Debug, Clone, Copy)]
+pub struct BlocksLimit {
+    once: bool,
+    max_blocks: Option<u64>,
+    committed_blocks: u64,
+}
+
+impl BlocksLimit {
+    pub fn new(max_blocks: Option<u64>) -> Self {
+        Self {
+            once: false,
+            max_blocks,
+            committed_blocks: 0,
+        }
+    }
+
+    pub fn once() -> Self {
+        Self {
+            once: true,
+            ..Self::new(None)
+        }
+    }
+
+    pub fn is_bounded(&self) -> bool {
+        self.once || self.max_blocks.is_some()
+    }
+
+    /// Returns true if the run should stop
+    pub fn record(&mut self, handled_block: HandledBlock) -> bool {
+        if let HandledBlock::Committed(_) = handled_block {
+            self.committed_blocks += 1;
+        }
+        self.once
+            || self
+                .max_blocks
+                .map_or(false, |max_blocks| self.committed_blocks >= max_blocks)
+    }
+
+    pub fn committed_blocks(&self) -> u64 {
+        self.committed_blocks
+    }
+}
+
+const LAKE_RECONNECT_INITIAL_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const LAKE_RECONNECT_MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The delays before reconnecting to Lake: doubled after each unexpected end of the stream up to the limit,
+/// back to the initial one once the blocks are handled again
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectBackoff {
+    delay: std::time::Duration,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self {
+            delay: LAKE_RECONNECT_INITIAL_DELAY,
+        }
+    }
+}
+
+impl ReconnectBackoff {
+    /// Counts the reconnect and returns how long to wait before it
+    pub fn next_delay(&mut self) -> std::time::Duration {
+        metrics::LAKE_RECONNECTS_TOTAL.inc();
+        let delay = self.delay;
+        self.delay = (delay * 2).min(LAKE_RECONNECT_MAX_DELAY);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+fn is_progress_logged(settings: &settings::IndexerSettings, block_height: u64) -> bool {
+    // 0 would mean "never" but breaks the modulo, so we log every block instead
+    block_height % settings.log_every_n_blocks.max(1) == 0
+}
+
+/// Collects and stores all the events from the block, updates the metrics.
+/// Returns whether the block is committed or skipped as already processed
+pub async fn handle_streamer_message(
+    streamer_message: near_indexer_primitives::StreamerMessage,
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    settings: &settings::IndexerSettings,
+    chain_id: &str,
+    contracts_filter: &db_adapters::contracts_filter::ContractsFilter,
+    sink: &dyn sinks::EventSink,
+) -> anyhow::Result<HandledBlock> {
+    metrics::BLOCK_PROCESSED_TOTAL.inc();
+    // Prometheus Gauge Metric type do not support u64
+    // https://github.com/tikv/rust-prometheus/issues/470
+    metrics::LATEST_BLOCK_HEIGHT.set(i64::try_from(streamer_message.block.header.height)?);
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    // block timestamp is in nanoseconds
+    let block_timestamp_seconds = streamer_message.block.header.timestamp / 1_000_000_000;
+    let lag_seconds = i64::try_from(now.as_secs())? - i64::try_from(block_timestamp_seconds)?;
+    metrics::LATEST_BLOCK_TIMESTAMP_DIFF.set(lag_seconds);
+
+    // The targeted backfill goes through the processed blocks on purpose, so it's never skipped
+    let block_height = streamer_message.block.header.height;
+    let stores_whole_block = settings.store_to_db && settings.indexes_everything(contracts_filter);
+    if stores_whole_block
+        && db_adapters::processed_blocks::is_processed_block(pool, block_height).await?
+    {
+        metrics::DUPLICATE_BLOCKS_SKIPPED_TOTAL.inc();
+        metrics::update_last_progress_timestamp();
+        tracing::debug!(
+            target: crate::LOGGING_PREFIX,
+            "Block {} is already processed, skipping",
+            block_height
+        );
+        return Ok(HandledBlock::Skipped(block_height));
+    }
+
+    if streamer_message.shards.is_empty() {
+        metrics::EMPTY_SHARD_BLOCKS_TOTAL.inc();
+        if settings.fail_on_empty_shards {
+            anyhow::bail!(
+                "Block {} has no shards",
+                streamer_message.block.header.height
+            );
+        }
+        tracing::warn!(
+            target: crate::LOGGING_PREFIX,
+            "Block {} has no shards, the data from Lake may be corrupted",
+            streamer_message.block.header.height
+        );
+    }
+
+    metrics::SHARDS_PER_BLOCK.set(i64::try_from(streamer_message.shards.len())?);
+    metrics::RECEIPT_EXECUTION_OUTCOMES_TOTAL.inc_by(
+        streamer_message
+            .shards
+            .iter()
+            .map(|shard| shard.receipt_execution_outcomes.len() as u64)
+            .sum(),
+    );
+
+    if is_progress_logged(settings, streamer_message.block.header.height) {
+        tracing::info!(
+            target: crate::LOGGING_PREFIX,
+            "{} / shards {} / lag {} seconds",
+            streamer_message.block.header.height,
+            streamer_message.shards.len(),
+            lag_seconds
+        );
+    }
+
+    let started_at = settings.verbose_block_timing.then(std::time::Instant::now);
+    // The errors logged while collecting the events get the block height from this span
+    let block_checksum = db_adapters::events::store_events(
+        pool,
+        settings,
+        &streamer_message,
+        chain_id,
+        contracts_filter,
+        sink,
+    )
+    .instrument(tracing::info_span!(
+        "block",
+        block_height = streamer_message.block.header.height
+    ))
+    .await?;
+    // The filtered run stores only a part of the block, so the block is not complete in the DB
+    if stores_whole_block {
+        db_adapters::processed_blocks::store_processed_block(
+            pool,
+            &streamer_message.block.header,
+            block_checksum,
+        )
+        .await?;
+    }
+    track_committed_block_height(streamer_message.block.header.height);
+    metrics::update_last_progress_timestamp();
+    if let Some(started_at) = started_at {
+        let elapsed = started_at.elapsed();
+        metrics::BLOCK_PROCESSING_DURATION_SECONDS.observe(elapsed.as_secs_f64());
+        tracing::debug!(
+            target: crate::LOGGING_PREFIX,
+            "Block {} is processed in {:.3?}",
+            streamer_message.block.header.height,
+            elapsed
+        );
+    }
+
+    Ok(HandledBlock::Committed(
+        streamer_message.block.header.height,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_adapters::contracts_filter::ContractsFilter;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn replayed_block_is_skipped() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let sink = test_utils::VecSink::default();
+        let mint_log = test_utils::ft_mint_log("alice.near", "100");
+        let block = || {
+            test_utils::streamer_message(
+                839_001,
+                vec![test_utils::logs_outcome("mint", "token.near", &[&mint_log])],
+            )
+        };
+        let skipped_before = metrics::DUPLICATE_BLOCKS_SKIPPED_TOTAL.get();
+
+        for _ in 0..2 {
+            handle_streamer_message(
+                block(),
+                &pool,
+                &settings::IndexerSettings::default(),
+                "mainnet",
+                &ContractsFilter::default(),
+                &sink,
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(
+            metrics::DUPLICATE_BLOCKS_SKIPPED_TOTAL.get() - skipped_before,
+            1
+        );
+        assert_eq!(sink.coin_events.lock().unwrap().len(), 1);
+        assert_eq!(test_utils::count_rows(&pool, "coin_events").await, 1);
+        assert_eq!(test_utils::count_rows(&pool, "processed_blocks").await, 1);
+    }
+
+    #[tokio::test]
+    async fn filtered_run_does_not_mark_block_as_processed() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let mint_log = test_utils::ft_mint_log("alice.near", "100");
+        let block = test_utils::streamer_message(
+            839_002,
+            vec![
+                test_utils::logs_outcome("mint", "token.near", &[&mint_log]),
+                test_utils::logs_outcome("other mint", "other.near", &[&mint_log]),
+            ],
+        );
+        let only_token = ContractsFilter::new(
+            Some(["token.near".parse().unwrap()].into_iter().collect()),
+            Default::default(),
+        );
+
+        handle_streamer_message(
+            block,
+            &pool,
+            &settings::IndexerSettings::default(),
+            "mainnet",
+            &only_token,
+            &sinks::NoopSink,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(test_utils::count_rows(&pool, "coin_events").await, 1);
+        assert_eq!(test_utils::count_rows(&pool, "processed_blocks").await, 0);
+        assert!(
+            !db_adapters::processed_blocks::is_processed_block(&pool, 839_002)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn ft_only_run_does_not_hide_nft_events_from_full_run() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let ft_mint_log = test_utils::ft_mint_log("alice.near", "100");
+        let nft_mint_log = test_utils::nft_mint_log("alice.near", &["1"]);
+        let block = || {
+            test_utils::streamer_message(
+                839_003,
+                vec![
+                    test_utils::logs_outcome("ft", "token.near", &[&ft_mint_log]),
+                    test_utils::logs_outcome("nft", "nft.near", &[&nft_mint_log]),
+                ],
+            )
+        };
+        let ft_only = settings::IndexerSettings {
+            index_nft: false,
+            ..settings::IndexerSettings::default()
+        };
+
+        handle_streamer_message(
+            block(),
+            &pool,
+            &ft_only,
+            "mainnet",
+            &ContractsFilter::default(),
+            &sinks::NoopSink,
+        )
+        .await
+        .unwrap();
+        assert_eq!(test_utils::count_rows(&pool, "nft_events").await, 0);
+        assert_eq!(test_utils::count_rows(&pool, "processed_blocks").await, 0);
+
+        let handled_block = handle_streamer_message(
+            block(),
+            &pool,
+            &settings::IndexerSettings::default(),
+            "mainnet",
+            &ContractsFilter::default(),
+            &sinks::NoopSink,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(handled_block, HandledBlock::Committed(839_003));
+        assert_eq!(test_utils::count_rows(&pool, "coin_events").await, 1);
+        assert_eq!(test_utils::count_rows(&pool, "nft_events").await, 1);
+        assert_eq!(test_utils::count_rows(&pool, "processed_blocks").await, 1);
+    }
+
+    #[tokio::test]
+    async fn max_blocks_counts_only_committed_blocks() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        // Committed by the previous run
+        handle_streamer_message(
+            test_utils::streamer_message(861_002, vec![]),
+            &pool,
+            &settings::IndexerSettings::default(),
+            "mainnet",
+            &ContractsFilter::default(),
+            &sinks::NoopSink,
+        )
+        .await
+        .unwrap();
+        let mut limit = BlocksLimit::new(Some(3));
+        let mut handled_blocks = vec![];
+
+        for block_height in 861_001..=861_010 {
+            let handled_block = handle_streamer_message(
+                test_utils::streamer_message(block_height, vec![]),
+                &pool,
+                &settings::IndexerSettings::default(),
+                "mainnet",
+                &ContractsFilter::default(),
+                &sinks::NoopSink,
+            )
+            .await
+            .unwrap();
+            handled_blocks.push(handled_block);
+            if limit.record(handled_block) {
+                break;
+            }
+        }
+
+        assert_eq!(
+            handled_blocks,
+            vec![
+                HandledBlock::Committed(861_001),
+                HandledBlock::Skipped(861_002),
+                HandledBlock::Committed(861_003),
+                HandledBlock::Committed(861_004),
+            ]
+        );
+        assert_eq!(limit.committed_blocks(), 3);
+        assert_eq!(test_utils::count_rows(&pool, "processed_blocks").await, 4);
+    }
+
+    #[tokio::test]
+    async fn once_commits_exactly_one_block() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let mut limit = BlocksLimit::once();
+        let mut handled_blocks = vec![];
+
+        for block_height in 843_001..=843_003 {
+            let handled_block = handle_streamer_message(
+                test_utils::streamer_message(block_height, vec![]),
+                &pool,
+                &settings::IndexerSettings::default(),
+                "mainnet",
+                &ContractsFilter::default(),
+                &sinks::NoopSink,
+            )
+            .await
+            .unwrap();
+            handled_blocks.push(handled_block);
+            if limit.record(handled_block) {
+                break;
+            }
+        }
+
+        assert!(limit.is_bounded());
+        assert_eq!(handled_blocks, vec![HandledBlock::Committed(843_001)]);
+        assert_eq!(limit.committed_blocks(), 1);
+        assert_eq!(test_utils::count_rows(&pool, "processed_blocks").await, 1);
+    }
+
+    #[tokio::test]
+    async fn once_stops_after_already_processed_block() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let handle = |block_height| {
+            let pool = pool.clone();
+            async move {
+                handle_streamer_message(
+                    test_utils::streamer_message(block_height, vec![]),
+                    &pool,
+                    &settings::IndexerSettings::default(),
+                    "mainnet",
+                    &ContractsFilter::default(),
+                    &sinks::NoopSink,
+                )
+                .await
+                .unwrap()
             }
+        };
+        // Committed by the previous run
+        handle(861_101).await;
+        let mut limit = BlocksLimit::once();
+
+        let handled_block = handle(861_101).await;
+
+        assert_eq!(handled_block, HandledBlock::Skipped(861_101));
+        assert!(limit.record(handled_block));
+        assert_eq!(limit.committed_blocks(), 0);
+        assert_eq!(test_utils::count_rows(&pool, "processed_blocks").await, 1);
+    }
+
+    // Handles the block without the DB, the events go nowhere
+    async fn handle_offline(
+        block: near_indexer_primitives::StreamerMessage,
+        settings: settings::IndexerSettings,
+    ) -> anyhow::Result<HandledBlock> {
+        handle_streamer_message(
+            block,
+            &test_utils::offline_pool(),
+            &settings::IndexerSettings {
+                store_to_db: false,
+                ..settings
+            },
+            "mainnet",
+            &ContractsFilter::default(),
+            &sinks::NoopSink,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn shards_per_block_gauge_follows_the_block() {
+        let _globals = test_utils::lock_globals().await;
+        let mut block = test_utils::streamer_message(798_001, vec![]);
+        block.shards.push(near_indexer_primitives::IndexerShard {
+            shard_id: 1,
+            chunk: None,
+            receipt_execution_outcomes: vec![],
+            state_changes: vec![],
+        });
+
+        handle_offline(block, settings::IndexerSettings::default())
+            .await
+            .unwrap();
+        assert_eq!(metrics::SHARDS_PER_BLOCK.get(), 2);
+
+        handle_offline(
+            test_utils::streamer_message(798_002, vec![]),
+            settings::IndexerSettings::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(metrics::SHARDS_PER_BLOCK.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn block_timing_is_observed_only_in_verbose_mode() {
+        let _globals = test_utils::lock_globals().await;
+        let samples_before = metrics::BLOCK_PROCESSING_DURATION_SECONDS.get_sample_count();
+
+        handle_offline(
+            test_utils::streamer_message(821_001, vec![]),
+            settings::IndexerSettings::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            metrics::BLOCK_PROCESSING_DURATION_SECONDS.get_sample_count(),
+            samples_before
+        );
+
+        let verbose_settings = settings::IndexerSettings {
+            verbose_block_timing: true,
+            ..settings::IndexerSettings::default()
+        };
+        handle_offline(
+            test_utils::streamer_message(821_002, vec![]),
+            verbose_settings,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            metrics::BLOCK_PROCESSING_DURATION_SECONDS.get_sample_count(),
+            samples_before + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn error_logs_have_receipt_shard_and_block() {
+        let _globals = test_utils::lock_globals().await;
+        let broken_transfer = test_utils::function_call_outcome(
+            "broken",
+            "alice.near",
+            "abc.tkn.near",
+            "ft_transfer",
+            br#"{"receiver_id":"bob.near""#,
+            &[],
+        );
+        let receipt_id = broken_transfer.receipt.receipt_id;
+        let tolerant_settings = settings::IndexerSettings {
+            tolerant_parsing: true,
+            ..settings::IndexerSettings::default()
+        };
+
+        let (logs, _guard) = test_utils::capture_logs();
+        handle_offline(
+            test_utils::streamer_message(830_001, vec![broken_transfer]),
+            tolerant_settings,
+        )
+        .await
+        .unwrap();
+
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let error_line = logs
+            .lines()
+            .find(|line| line.contains("Skipping receipt with unparseable args"))
+            .unwrap_or_else(|| panic!("No error is logged: {}", logs));
+        for field in [
+            "block{block_height=830001}".to_string(),
+            "shard{shard_id=0}".to_string(),
+            format!("receipt_id={}", receipt_id),
+            "contract_id=abc.tkn.near".to_string(),
+        ] {
+            assert!(
+                error_line.contains(&field),
+                "`{}` is missing: {}",
+                field,
+                error_line
+            );
         }
-    };
+    }
+
+    #[tokio::test]
+    async fn reconnect_backoff_is_bounded_and_reset_by_blocks() {
+        let _globals = test_utils::lock_globals().await;
+        let reconnects_before = metrics::LAKE_RECONNECTS_TOTAL.get();
+        let mut backoff = ReconnectBackoff::default();
+
+        // The stream keeps ending right after the reconnect
+        let delays: Vec<u64> = (0..8).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(metrics::LAKE_RECONNECTS_TOTAL.get() - reconnects_before, 8);
+
+        // The block is handled after the reconnect
+        backoff.reset();
+        assert_eq!(backoff.next_delay().as_secs(), 1);
+    }
+
+    #[tokio::test]
+    async fn blocks_committed_in_reverse_are_counted() {
+        let _globals = test_utils::lock_globals().await;
+        // Other tests commit their own heights
+        MAX_COMMITTED_BLOCK_HEIGHT.store(0, std::sync::atomic::Ordering::Relaxed);
+        let out_of_order_before = metrics::OUT_OF_ORDER_COMMITS_TOTAL.get();
+
+        for block_height in [870_003, 870_002, 870_001, 870_004] {
+            handle_offline(
+                test_utils::streamer_message(block_height, vec![]),
+                settings::IndexerSettings::default(),
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(
+            metrics::OUT_OF_ORDER_COMMITS_TOTAL.get() - out_of_order_before,
+            2
+        );
+    }
+
+    #[test]
+    fn progress_is_logged_every_n_blocks() {
+        let logged_blocks = |log_every_n_blocks| {
+            let settings = settings::IndexerSettings {
+                log_every_n_blocks,
+                ..settings::IndexerSettings::default()
+            };
+            (804_001..=804_010)
+                .filter(|block_height| is_progress_logged(&settings, *block_height))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(logged_blocks(5), vec![804_005, 804_010]);
+        assert_eq!(logged_blocks(1), (804_001..=804_010).collect::<Vec<_>>());
+        assert_eq!(logged_blocks(0), logged_blocks(1));
+    }
+
+    #[tokio::test]
+    async fn block_without_shards_is_counted_and_fails_only_if_asked() {
+        let _globals = test_utils::lock_globals().await;
+        let block_without_shards = |block_height| {
+            let mut block = test_utils::streamer_message(block_height, vec![]);
+            block.shards.clear();
+            block
+        };
+        let empty_blocks_before = metrics::EMPTY_SHARD_BLOCKS_TOTAL.get();
+
+        let handled = handle_offline(
+            block_without_shards(807_001),
+            settings::IndexerSettings::default(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(handled, HandledBlock::Committed(807_001)));
+        assert_eq!(metrics::SHARDS_PER_BLOCK.get(), 0);
 
-    TokenStream::from(output)
+        let strict_settings = settings::IndexerSettings {
+            fail_on_empty_shards: true,
+            ..settings::IndexerSettings::default()
+        };
+        let err = handle_offline(block_without_shards(807_002), strict_settings)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Block 807002 has no shards");
+        assert_eq!(
+            metrics::EMPTY_SHARD_BLOCKS_TOTAL.get() - empty_blocks_before,
+            2
+        );
+    }
 }
@@ -1,95 +1,499 @@
 // TODO cleanup imports in all the files in the end
-use crate::configs::{init_tracing, Opts};
+use crate::configs::{init_tracing, Command, Opts};
+use anyhow::Context;
 use clap::Parser;
 use dotenv::dotenv;
 use futures::StreamExt;
-use near_lake_framework::near_indexer_primitives;
+use indexer_events::{
+    db_adapters, handle_streamer_message, metrics, rpc_helpers, settings::IndexerSettings, sinks,
+    LOGGING_PREFIX,
+};
 use std::env;
+use std::sync::{Arc, RwLock};
 mod configs;
-mod db_adapters;
-mod metrics;
-mod models;
 
-#[macro_use]
-extern crate lazy_static;
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv().ok();
+    configs::load_secrets_from_files()?;
+    let opts: Opts = Opts::parse();
+    let database_url = env::var("DATABASE_URL").ok();
+    let store_to_db = opts.output == configs::Output::Postgres;
 
-pub(crate) const LOGGING_PREFIX: &str = "indexer_events";
+    if opts.dump_metrics {
+        for definition in metrics::metric_definitions() {
+            println!("{}", serde_json::to_string(&definition)?);
+        }
+        return Ok(());
+    }
+    if opts.print_config {
+        println!("{}", opts.effective_config(database_url.as_deref()));
+        return Ok(());
+    }
 
-const INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
-const MAX_DELAY_TIME: std::time::Duration = std::time::Duration::from_secs(120);
+    let connect_options = opts.pg_connect_options(database_url.as_deref())?;
+    let pool_options = opts.pg_pool_options();
+    let pool = if store_to_db {
+        pool_options
+            .connect_with(connect_options)
+            .await
+            .context("Failed to connect to Postgres from DATABASE_URL")?
+    } else {
+        pool_options.connect_lazy_with(connect_options)
+    };
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
-pub struct AccountWithContract {
-    pub account_id: near_primitives::types::AccountId,
-    pub contract_account_id: near_primitives::types::AccountId,
+    let worker_guard = init_tracing(opts.debug, opts.log_format, opts.log_output)?;
+    let result = run(opts, pool, database_url.as_deref()).await;
+    configs::finish_tracing(worker_guard, &result);
+    result
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    dotenv().ok();
-    let opts: Opts = Opts::parse();
+async fn run(
+    opts: Opts,
+    pool: sqlx::Pool<sqlx::Postgres>,
+    database_url: Option<&str>,
+) -> anyhow::Result<()> {
+    tracing::info!(
+        target: LOGGING_PREFIX,
+        "Effective configuration:\n{}",
+        opts.effective_config(database_url)
+    );
+
+    // The settings are shared with the subcommands
+    let settings = opts.indexer_settings()?;
+
+    match &opts.command {
+        Some(Command::CheckGaps { from, to }) => {
+            let missing_blocks = db_adapters::find_missing_blocks(&pool, *from, *to).await?;
+            for (start, end) in db_adapters::processed_blocks::group_into_ranges(&missing_blocks) {
+                println!("{start}-{end}");
+            }
+            return Ok(());
+        }
+        Some(Command::Validate { from, to }) => {
+            validate(&opts, &settings, *from, *to).await?;
+            return Ok(());
+        }
+        Some(Command::RebuildContracts) => {
+            let contracts_count = db_adapters::contracts::rebuild_contracts(&pool).await?;
+            println!("{contracts_count} contracts are restored");
+            return Ok(());
+        }
+        Some(Command::RebuildSupplySnapshots) => {
+            let snapshots_count =
+                db_adapters::coin::supply::rebuild_supply_snapshots(&pool, 0).await?;
+            println!("{snapshots_count} supply snapshots are restored");
+            return Ok(());
+        }
+        Some(Command::Reconcile {
+            account,
+            contract,
+            block,
+        }) => {
+            let rpc_client =
+                near_jsonrpc_client::JsonRpcClient::connect(opts.near_archival_rpc_url()?);
+            let indexed_balance =
+                db_adapters::coin::balances::get_indexed_balance(&pool, contract, account, *block)
+                    .await?;
+            let chain_balance =
+                rpc_helpers::balance_at_block_boundary(&rpc_client, contract, account, *block)
+                    .await?;
+            println!("indexed: {indexed_balance}");
+            println!("chain: {chain_balance}");
+            println!("diff: {}", &chain_balance - &indexed_balance);
+            println!(
+                "status: {}",
+                if chain_balance == indexed_balance {
+                    "matches"
+                } else {
+                    "diverges"
+                }
+            );
+            return Ok(());
+        }
+        Some(Command::Reindex { .. }) | None => {}
+    }
 
-    let pool = sqlx::PgPool::connect(&env::var("DATABASE_URL")?).await?;
+    let store_to_db = settings.store_to_db;
+    if !store_to_db
+        && (opts.store_supply_snapshots
+            || opts.store_balance_snapshots
+            || opts.fetch_ft_metadata
+            || opts.store_block_checksums
+            || opts.verify_balances_interval_seconds.is_some())
+    {
+        anyhow::bail!("Snapshots, metadata, block checksums and balances verification need the DB, they can't be used with `--output jsonl`");
+    }
 
-    let _worker_guard = init_tracing(opts.debug)?;
+    let mut tables = db_adapters::schema::REQUIRED_TABLES.to_vec();
+    if opts.fetch_ft_metadata {
+        tables.push("contract_metadata");
+    }
+    if opts.store_supply_snapshots {
+        tables.push("contract_supply_snapshots");
+    }
+    if opts.store_balance_snapshots {
+        tables.push("account_balance_snapshots");
+    }
+    if store_to_db {
+        db_adapters::schema::check_schema(&pool, &opts.db_schema, &tables).await?;
+    }
+    // The reindexing goes through the usual pipeline, so it needs all the settings above
+    if let Some(Command::Reindex { from, to }) = &opts.command {
+        return reindex(&opts, &settings, &pool, *from, *to).await;
+    }
+
+    let start_block_height = match (opts.start_block_height, opts.start_timestamp) {
+        (Some(start_block_height), _) => start_block_height,
+        (None, Some(start_timestamp)) => {
+            let rpc_client =
+                near_jsonrpc_client::JsonRpcClient::connect(opts.near_archival_rpc_url()?);
+            let start_block_height =
+                rpc_helpers::find_block_height_by_timestamp(&rpc_client, &start_timestamp).await?;
+            tracing::info!(
+                target: LOGGING_PREFIX,
+                "Start timestamp {} is resolved to block {}",
+                start_timestamp,
+                start_block_height
+            );
+            start_block_height
+        }
+        (None, None) => anyhow::bail!("START_BLOCK_HEIGHT or START_TIMESTAMP is required"),
+    };
+    // The start counts as the progress, otherwise the liveness check fails before the first block arrives
+    metrics::update_last_progress_timestamp();
+    metrics::update_last_db_commit_timestamp();
 
-    let config: near_lake_framework::LakeConfig = opts.to_lake_config().await;
-    let (_lake_handle, stream) = near_lake_framework::streamer(config);
+    let contracts_filter = Arc::new(RwLock::new(Arc::new(opts.contracts_filter()?)));
+    spawn_contracts_filter_reloader(&opts, contracts_filter.clone())?;
+    spawn_balances_verifier(&opts, &pool)?;
 
-    tokio::spawn(async move {
-        let mut handlers = tokio_stream::wrappers::ReceiverStream::new(stream)
-            .map(|streamer_message| {
-                handle_streamer_message(streamer_message, &pool, &opts.chain_id)
-            })
-            .buffer_unordered(1usize);
-
-        let mut time_now = std::time::Instant::now();
-        while let Some(handle_message) = handlers.next().await {
-            match handle_message {
-                Ok(block_height) => {
-                    let elapsed = time_now.elapsed();
-                    tracing::info!(
-                        target: LOGGING_PREFIX,
-                        "Elapsed time spent on block {}: {:.3?}",
-                        block_height,
-                        elapsed
-                    );
-                    time_now = std::time::Instant::now();
+    let sink: Box<dyn sinks::EventSink> = match &opts.output_path {
+        Some(output_path) if !store_to_db => Box::new(sinks::JsonlFileSink::open(output_path)?),
+        _ => Box::new(sinks::NoopSink),
+    };
+    let chain_id = opts.chain_id()?.to_string();
+    let blocks_limit = if opts.once {
+        indexer_events::BlocksLimit::once()
+    } else {
+        indexer_events::BlocksLimit::new(opts.max_blocks)
+    };
+    let stream_opts = opts.clone();
+    let indexer_handle = tokio::spawn(async move {
+        let pool = &pool;
+        let settings = &settings;
+        let chain_id = &chain_id;
+        let sink = sink.as_ref();
+        let mut next_block_height = start_block_height;
+        let mut reconnect_backoff = indexer_events::ReconnectBackoff::default();
+        let mut blocks_limit = blocks_limit;
+        loop {
+            let config: near_lake_framework::LakeConfig =
+                stream_opts.to_lake_config(next_block_height).await?;
+            let (lake_handle, stream) = near_lake_framework::streamer(config);
+            let mut handlers = tokio_stream::wrappers::ReceiverStream::new(stream)
+                .map(|streamer_message| {
+                    // The filter could be reloaded meanwhile, but the whole block should be processed with the same one
+                    let contracts_filter = contracts_filter
+                        .read()
+                        .expect("Contracts filter lock is poisoned")
+                        .clone();
+                    async move {
+                        handle_streamer_message(
+                            streamer_message,
+                            pool,
+                            settings,
+                            chain_id,
+                            &contracts_filter,
+                            sink,
+                        )
+                        .await
+                    }
+                })
+                .buffer_unordered(1usize);
+
+            let mut time_now = std::time::Instant::now();
+            while let Some(handle_message) = handlers.next().await {
+                match handle_message {
+                    Ok(handled_block) => {
+                        let block_height = handled_block.block_height();
+                        let elapsed = time_now.elapsed();
+                        tracing::info!(
+                            target: LOGGING_PREFIX,
+                            "Elapsed time spent on block {}: {:.3?}",
+                            block_height,
+                            elapsed
+                        );
+                        time_now = std::time::Instant::now();
+                        next_block_height = block_height + 1;
+                        reconnect_backoff.reset();
+                        // `--max-blocks` doesn't count the blocks skipped as already processed, `--once` does
+                        if blocks_limit.record(handled_block) {
+                            tracing::info!(
+                                target: LOGGING_PREFIX,
+                                "{} blocks are committed, stopping at block {}",
+                                blocks_limit.committed_blocks(),
+                                block_height
+                            );
+                            return Ok(());
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!(target: LOGGING_PREFIX, "Stop indexing due to {}", e);
+                        // with metrics enabled, this thread is just stopped with error,
+                        // main thread continues serving metrics
+                        anyhow::bail!(e)
+                    }
                 }
-                Err(e) => {
-                    tracing::error!(target: LOGGING_PREFIX, "Stop indexing due to {}", e);
-                    // we do not catch this error anywhere, this thread is just stopped with error,
-                    // main thread continues serving metrics
-                    anyhow::bail!(e)
+            }
+
+            // Lake never ends the stream by itself, so it's a network or S3 error.
+            // We start again from the next block after the last processed one
+            let lake_result = lake_handle.await;
+            let reconnect_delay = reconnect_backoff.next_delay();
+            tracing::warn!(
+                target: LOGGING_PREFIX,
+                "Lake stream ended unexpectedly ({:?}), reconnecting from block {} in {:?}",
+                lake_result,
+                next_block_height,
+                reconnect_delay
+            );
+            tokio::time::sleep(reconnect_delay).await;
+        }
+    });
+
+    let serve = async {
+        if !opts.starts_metrics_server() {
+            // Nothing else keeps the process alive, so we wait for the indexer and return its error if any
+            return indexer_handle.await?;
+        }
+        let metrics_server = metrics::init_metrics_server(
+            opts.metrics_host,
+            opts.port,
+            metrics::HealthConfig {
+                max_block_lag_seconds: opts.ready_max_lag_seconds,
+                max_seconds_since_db_commit: opts.ready_max_seconds_since_db_commit,
+                liveness_window_seconds: opts.liveness_window_seconds,
+            },
+        );
+        if !blocks_limit.is_bounded() {
+            return metrics_server.await;
+        }
+        // The bounded run is over when the indexer is finished, the metrics server is stopped with it
+        tokio::select! {
+            result = indexer_handle => result?,
+            result = metrics_server => result,
+        }
+    };
+    let lag_circuit_breaker = async {
+        match opts.exit_on_lag_seconds {
+            Some(exit_on_lag_seconds) => {
+                metrics::lag_circuit_breaker(exit_on_lag_seconds, opts.exit_on_lag_window_seconds)
+                    .await
+            }
+            None => std::future::pending().await,
+        }
+    };
+    let metrics_pusher = async {
+        match &opts.metrics_pushgateway {
+            Some(pushgateway_url) => {
+                metrics::push_metrics_periodically(
+                    pushgateway_url.clone(),
+                    opts.metrics_push_job.clone(),
+                    opts.metrics_push_interval_seconds,
+                )
+                .await
+            }
+            None => std::future::pending().await,
+        }
+    };
+    // The circuit breaker returns the error instead of exiting, so the logs are flushed in `main`
+    let result = tokio::select! {
+        result = serve => result,
+        result = lag_circuit_breaker => result,
+        result = metrics_pusher => result,
+    };
+    // The last values of the short-lived job are pushed even if the periodic push didn't happen yet
+    if let Some(pushgateway_url) = &opts.metrics_pushgateway {
+        if let Err(err) = metrics::push_metrics(
+            &reqwest::Client::new(),
+            pushgateway_url,
+            &opts.metrics_push_job,
+        )
+        .await
+        {
+            tracing::warn!(
+                target: LOGGING_PREFIX,
+                error = %err,
+                "Failed to push the final metrics to the Pushgateway",
+            );
+        }
+    }
+    result
+}
+
+fn spawn_contracts_filter_reloader(
+    opts: &Opts,
+    contracts_filter: Arc<RwLock<Arc<db_adapters::contracts_filter::ContractsFilter>>>,
+) -> anyhow::Result<()> {
+    if opts.allow_list_file.is_none() && opts.deny_list_file.is_none() {
+        return Ok(());
+    }
+    let opts = opts.clone();
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match opts.contracts_filter() {
+                Ok(new_filter) => {
+                    *contracts_filter
+                        .write()
+                        .expect("Contracts filter lock is poisoned") = Arc::new(new_filter);
+                    tracing::info!(target: LOGGING_PREFIX, "Contracts filter is reloaded");
                 }
+                Err(err) => tracing::error!(
+                    target: LOGGING_PREFIX,
+                    "Failed to reload contracts filter, keeping the previous one: {}",
+                    err
+                ),
             }
         }
-        Ok(()) // unreachable statement, loop above is endless
     });
+    Ok(())
+}
 
-    metrics::init_metrics_server(opts.port).await
+fn spawn_balances_verifier(opts: &Opts, pool: &sqlx::Pool<sqlx::Postgres>) -> anyhow::Result<()> {
+    let interval_seconds = match opts.verify_balances_interval_seconds {
+        Some(interval_seconds) => interval_seconds,
+        None => return Ok(()),
+    };
+    let rpc_client = near_jsonrpc_client::JsonRpcClient::connect(opts.near_archival_rpc_url()?);
+    let sample_percent = opts.verify_balances_sample_percent;
+    let sample_size = opts.verify_balances_sample_size;
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_seconds));
+        loop {
+            interval.tick().await;
+            // The verification is optional, so its errors should not stop the indexer
+            match db_adapters::coin::balances::verify_sampled_balances(
+                &pool,
+                &rpc_client,
+                sample_percent,
+                sample_size,
+            )
+            .await
+            {
+                Ok(mismatches) => tracing::info!(
+                    target: LOGGING_PREFIX,
+                    "Balances verification found {} mismatches",
+                    mismatches
+                ),
+                Err(err) => tracing::error!(
+                    target: LOGGING_PREFIX,
+                    "Balances verification failed: {}",
+                    err
+                ),
+            }
+        }
+    });
+    Ok(())
 }
 
-async fn handle_streamer_message(
-    streamer_message: near_indexer_primitives::StreamerMessage,
+async fn reindex(
+    opts: &Opts,
+    settings: &IndexerSettings,
     pool: &sqlx::Pool<sqlx::Postgres>,
-    chain_id: &str,
-) -> anyhow::Result<u64> {
-    metrics::BLOCK_PROCESSED_TOTAL.inc();
-    // Prometheus Gauge Metric type do not support u64
-    // https://github.com/tikv/rust-prometheus/issues/470
-    metrics::LATEST_BLOCK_HEIGHT.set(i64::try_from(streamer_message.block.header.height)?);
-
-    if streamer_message.block.header.height % 100 == 0 {
-        tracing::info!(
-            target: crate::LOGGING_PREFIX,
-            "{} / shards {}",
-            streamer_message.block.header.height,
-            streamer_message.shards.len()
-        );
+    from: u64,
+    to: u64,
+) -> anyhow::Result<()> {
+    if opts.output != configs::Output::Postgres {
+        anyhow::bail!("Reindexing overwrites the DB, it can't be used with `--output jsonl`");
+    }
+    // All the events of the range are deleted, so all of them should be indexed again
+    let contracts_filter = opts.contracts_filter()?;
+    if !contracts_filter.allows_everything() {
+        anyhow::bail!("Reindexing can't be used with the allow/deny lists");
+    }
+    if from > to {
+        anyhow::bail!("Empty range: {} > {}", from, to);
     }
 
-    db_adapters::events::store_events(pool, &streamer_message, chain_id).await?;
+    // Nothing is deleted if the Lake can't be configured
+    let config = opts.to_lake_config(from).await?;
+    let chain_id = opts.chain_id()?;
+    let deleted_rows = db_adapters::reindex::delete_block_range(pool, from, to).await?;
+    tracing::info!(
+        target: LOGGING_PREFIX,
+        "{} rows of the blocks {}-{} are deleted, reindexing",
+        deleted_rows.total(),
+        from,
+        to
+    );
 
-    Ok(streamer_message.block.header.height)
+    let (_lake_handle, mut stream) = near_lake_framework::streamer(config);
+    let mut blocks_count = 0;
+    while let Some(streamer_message) = stream.recv().await {
+        if streamer_message.block.header.height > to {
+            break;
+        }
+        handle_streamer_message(
+            streamer_message,
+            pool,
+            settings,
+            chain_id,
+            &contracts_filter,
+            &sinks::NoopSink,
+        )
+        .await?;
+        blocks_count += 1;
+    }
+    let contracts_count = db_adapters::reindex::restore_after_reindex(
+        pool,
+        from,
+        opts.store_supply_snapshots || deleted_rows.supply_snapshots > 0,
+        opts.store_balance_snapshots || deleted_rows.balance_snapshots > 0,
+    )
+    .await?;
+    println!("{blocks_count} blocks are reindexed, {contracts_count} contracts are restored");
+    Ok(())
+}
+
+async fn validate(
+    opts: &Opts,
+    settings: &IndexerSettings,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<()> {
+    let config = opts.to_lake_config(from).await?;
+    let (_lake_handle, mut stream) = near_lake_framework::streamer(config);
+
+    db_adapters::validation::start_validation();
+    while let Some(streamer_message) = stream.recv().await {
+        if streamer_message.block.header.height > to {
+            break;
+        }
+        db_adapters::validation::validate_block(settings, &streamer_message, opts.chain_id()?)
+            .await?;
+    }
+    let report = db_adapters::validation::finish_validation();
+
+    println!(
+        "{:<64} {:>16} {:>20}  unknown methods",
+        "contract", "unparsed events", "unknown method calls"
+    );
+    for (contract_id, contract_report) in &report.contracts {
+        println!(
+            "{:<64} {:>16} {:>20}  {}",
+            contract_id,
+            contract_report.unparsed_events,
+            contract_report.unknown_method_calls,
+            contract_report
+                .unknown_methods
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    Ok(())
 }
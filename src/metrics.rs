@@ -1,10 +1,74 @@
-use actix_web::{get, App, HttpServer, Responder};
-use prometheus::{Encoder, IntCounter, IntGauge, Opts};
+use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
 
 use crate::LOGGING_PREFIX;
 
 type Result<T, E> = std::result::Result<T, E>;
 
+// Unix timestamp (seconds) of the last time the indexer made progress, used by the liveness check
+static LAST_PROGRESS_TIMESTAMP: AtomicI64 = AtomicI64::new(0);
+
+fn now_seconds() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+pub fn update_last_progress_timestamp() {
+    LAST_PROGRESS_TIMESTAMP.store(now_seconds(), Ordering::Relaxed);
+}
+
+// Unix timestamp (seconds) of the last successful write to the DB.
+// Blocks may keep coming while the writes fail and are retried, the block lag does not show it
+static LAST_DB_COMMIT_TIMESTAMP: AtomicI64 = AtomicI64::new(0);
+
+pub fn update_last_db_commit_timestamp() {
+    LAST_DB_COMMIT_TIMESTAMP.store(now_seconds(), Ordering::Relaxed);
+}
+
+// The gauge should grow even if nothing happens, so we recalculate it on each request
+fn refresh_seconds_since_last_db_commit() -> i64 {
+    let seconds = now_seconds() - LAST_DB_COMMIT_TIMESTAMP.load(Ordering::Relaxed);
+    SECONDS_SINCE_LAST_DB_COMMIT.set(seconds);
+    seconds
+}
+
+/// Keeps the number of the label values bounded: the first `max_labels` values are used as is,
+/// all the next ones are reported as `other`
+pub(crate) struct BoundedLabels {
+    max_labels: usize,
+    seen_labels: Mutex<HashSet<String>>,
+}
+
+impl BoundedLabels {
+    pub(crate) fn new(max_labels: usize) -> Self {
+        Self {
+            max_labels,
+            seen_labels: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub(crate) fn get(&self, label: String) -> String {
+        let mut seen_labels = self
+            .seen_labels
+            .lock()
+            .expect("Seen labels lock is poisoned");
+        if seen_labels.contains(&label) || seen_labels.len() < self.max_labels {
+            seen_labels.insert(label.clone());
+            label
+        } else {
+            "other".to_string()
+        }
+    }
+}
+
 fn try_create_int_counter(name: &str, help: &str) -> Result<IntCounter, prometheus::Error> {
     let opts = Opts::new(name, help);
     let counter = IntCounter::with_opts(opts)?;
@@ -19,6 +83,36 @@ fn try_create_int_gauge(name: &str, help: &str) -> Result<IntGauge, prometheus::
     Ok(gauge)
 }
 
+fn try_create_int_counter_vec(
+    name: &str,
+    help: &str,
+    labels: &[&str],
+) -> Result<IntCounterVec, prometheus::Error> {
+    let opts = Opts::new(name, help);
+    let counter = IntCounterVec::new(opts, labels)?;
+    prometheus::register(Box::new(counter.clone()))?;
+    Ok(counter)
+}
+
+fn try_create_histogram(name: &str, help: &str) -> Result<Histogram, prometheus::Error> {
+    let opts = HistogramOpts::new(name, help);
+    let histogram = Histogram::with_opts(opts)?;
+    prometheus::register(Box::new(histogram.clone()))?;
+    Ok(histogram)
+}
+
+fn try_create_histogram_vec(
+    name: &str,
+    help: &str,
+    labels: &[&str],
+    buckets: Vec<f64>,
+) -> Result<HistogramVec, prometheus::Error> {
+    let opts = HistogramOpts::new(name, help).buckets(buckets);
+    let histogram = HistogramVec::new(opts, labels)?;
+    prometheus::register(Box::new(histogram.clone()))?;
+    Ok(histogram)
+}
+
 lazy_static! {
     pub(crate) static ref BLOCK_PROCESSED_TOTAL: IntCounter = try_create_int_counter(
         "indexer_events_total_blocks_processed",
@@ -30,10 +124,177 @@ lazy_static! {
         "Last seen block height by indexer"
     )
     .unwrap();
+    pub(crate) static ref LATEST_BLOCK_TIMESTAMP_DIFF: IntGauge = try_create_int_gauge(
+        "indexer_events_latest_block_timestamp_diff",
+        "Difference in seconds between the current time and the timestamp of the last seen block"
+    )
+    .unwrap();
+    pub(crate) static ref SHARDS_PER_BLOCK: IntGauge = try_create_int_gauge(
+        "indexer_events_shards_per_block",
+        "Number of shards in the last seen block"
+    )
+    .unwrap();
+    pub(crate) static ref EMPTY_SHARD_BLOCKS_TOTAL: IntCounter = try_create_int_counter(
+        "indexer_events_empty_shard_blocks_total",
+        "Total number of blocks without shards seen by indexer"
+    )
+    .unwrap();
+    pub(crate) static ref OUT_OF_ORDER_COMMITS_TOTAL: IntCounter = try_create_int_counter(
+        "indexer_events_out_of_order_commits_total",
+        "Total number of blocks committed after a block with a higher height"
+    )
+    .unwrap();
+    pub(crate) static ref DUPLICATE_BLOCKS_SKIPPED_TOTAL: IntCounter = try_create_int_counter(
+        "indexer_events_duplicate_blocks_skipped_total",
+        "Total number of blocks skipped because they were already processed"
+    )
+    .unwrap();
+    pub(crate) static ref RECEIPT_EXECUTION_OUTCOMES_TOTAL: IntCounter = try_create_int_counter(
+        "indexer_events_receipt_execution_outcomes_total",
+        "Total number of receipt execution outcomes seen by indexer in all the shards"
+    )
+    .unwrap();
+    pub(crate) static ref BLOCK_PROCESSING_DURATION_SECONDS: Histogram = try_create_histogram(
+        "indexer_events_block_processing_duration_seconds",
+        "Time spent on storing the events of the block (only with --verbose-block-timing)"
+    )
+    .unwrap();
+    pub(crate) static ref LEGACY_UNKNOWN_METHOD_TOTAL: IntCounterVec = try_create_int_counter_vec(
+        "indexer_events_legacy_unknown_method_total",
+        "Total number of calls of the legacy contracts methods we don't know how to handle",
+        &["method"]
+    )
+    .unwrap();
+    pub(crate) static ref FT_EVENTS_TOTAL: IntCounter = try_create_int_counter(
+        "indexer_events_ft_events_total",
+        "Total number of FT events (NEP-141 and legacy) stored by indexer"
+    )
+    .unwrap();
+    pub(crate) static ref NFT_EVENTS_TOTAL: IntCounter = try_create_int_counter(
+        "indexer_events_nft_events_total",
+        "Total number of NFT events (NEP-171) stored by indexer"
+    )
+    .unwrap();
+    pub(crate) static ref DUPLICATE_EVENT_LOGS_TOTAL: IntCounter = try_create_int_counter(
+        "indexer_events_duplicate_event_logs_total",
+        "Total number of event logs repeated within the same receipt (skipped only with --dedup-receipt-logs)"
+    )
+    .unwrap();
+    pub(crate) static ref BALANCE_MISMATCHES_TOTAL: IntCounter = try_create_int_counter(
+        "indexer_events_balance_mismatches_total",
+        "Total number of sampled balances which differ from `ft_balance_of` (only with --verify-balances-interval-seconds)"
+    )
+    .unwrap();
+    pub(crate) static ref RPC_BUDGET_EXCEEDED_TOTAL: IntCounter = try_create_int_counter(
+        "indexer_events_rpc_budget_exceeded_total",
+        "Total number of RPC calls deferred because the block exceeded --max-rpc-per-block"
+    )
+    .unwrap();
+    pub static ref LAKE_RECONNECTS_TOTAL: IntCounter = try_create_int_counter(
+        "indexer_events_lake_reconnects_total",
+        "Total number of times the NEAR Lake stream ended unexpectedly and was restarted"
+    )
+    .unwrap();
+    pub(crate) static ref EVENT_PARSE_FAILURES_TOTAL: IntCounterVec = try_create_int_counter_vec(
+        "indexer_events_event_parse_failures_total",
+        "Total number of event logs which don't correspond to NEP formats, by contract (first 100 contracts, then `other`)",
+        &["contract"]
+    )
+    .unwrap();
+    pub(crate) static ref PARSE_ERRORS_TOTAL: IntCounter = try_create_int_counter(
+        "indexer_events_parse_errors_total",
+        "Total number of receipts skipped because of unparseable args (only with --tolerant-parsing)"
+    )
+    .unwrap();
+    // Batches are never bigger than CHUNK_SIZE_FOR_BATCH_INSERT (100)
+    pub(crate) static ref DB_INSERT_ROWS: HistogramVec = try_create_histogram_vec(
+        "indexer_events_db_insert_rows",
+        "Number of rows in one insert batch",
+        &["table"],
+        vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0]
+    )
+    .unwrap();
+    pub(crate) static ref DB_INSERT_DURATION_SECONDS: HistogramVec = try_create_histogram_vec(
+        "indexer_events_db_insert_duration_seconds",
+        "Time spent on the successful attempt to insert one batch, the failed attempts and the backoff are not counted",
+        &["table"],
+        prometheus::DEFAULT_BUCKETS.to_vec()
+    )
+    .unwrap();
+    static ref SECONDS_SINCE_LAST_DB_COMMIT: IntGauge = try_create_int_gauge(
+        "indexer_events_seconds_since_last_db_commit",
+        "Number of seconds since the last successful write to the database"
+    )
+    .unwrap();
 }
 
-#[get("/metrics")]
-async fn get_metrics() -> impl Responder {
+// The metrics are registered on the first use, so the list is needed to see all of them at once.
+// A new metric should be added here to appear in `--dump-metrics`, the test fails if it is forgotten
+fn all_metrics() -> Vec<&'static dyn Collector> {
+    vec![
+        &*BLOCK_PROCESSED_TOTAL,
+        &*LATEST_BLOCK_HEIGHT,
+        &*LATEST_BLOCK_TIMESTAMP_DIFF,
+        &*SHARDS_PER_BLOCK,
+        &*EMPTY_SHARD_BLOCKS_TOTAL,
+        &*OUT_OF_ORDER_COMMITS_TOTAL,
+        &*DUPLICATE_BLOCKS_SKIPPED_TOTAL,
+        &*RECEIPT_EXECUTION_OUTCOMES_TOTAL,
+        &*BLOCK_PROCESSING_DURATION_SECONDS,
+        &*LEGACY_UNKNOWN_METHOD_TOTAL,
+        &*FT_EVENTS_TOTAL,
+        &*NFT_EVENTS_TOTAL,
+        &*DUPLICATE_EVENT_LOGS_TOTAL,
+        &*BALANCE_MISMATCHES_TOTAL,
+        &*RPC_BUDGET_EXCEEDED_TOTAL,
+        &*LAKE_RECONNECTS_TOTAL,
+        &*EVENT_PARSE_FAILURES_TOTAL,
+        &*PARSE_ERRORS_TOTAL,
+        &*DB_INSERT_ROWS,
+        &*DB_INSERT_DURATION_SECONDS,
+        &*SECONDS_SINCE_LAST_DB_COMMIT,
+    ]
+}
+
+/// Name, type, help and label names of one metric, see `metric_definitions`
+#[derive(Debug, serde::Serialize)]
+pub struct MetricDefinition {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub metric_type: String,
+    pub help: String,
+    pub labels: Vec<String>,
+}
+
+/// Definitions of all the metrics of the indexer, sorted by name.
+/// `prometheus::gather()` skips the labeled metrics without values, so each collector is asked directly
+pub fn metric_definitions() -> Vec<MetricDefinition> {
+    let mut definitions: Vec<MetricDefinition> = all_metrics()
+        .into_iter()
+        .flat_map(|metric| {
+            let labels: Vec<String> = metric
+                .desc()
+                .iter()
+                .flat_map(|desc| desc.variable_labels.clone())
+                .collect();
+            metric
+                .collect()
+                .into_iter()
+                .map(move |family| MetricDefinition {
+                    name: family.get_name().to_string(),
+                    metric_type: format!("{:?}", family.get_field_type()).to_lowercase(),
+                    help: family.get_help().to_string(),
+                    labels: labels.clone(),
+                })
+        })
+        .collect();
+    definitions.sort_by(|a, b| a.name.cmp(&b.name));
+    definitions
+}
+
+// Text exposition format of all the registered metrics, used both by `/metrics` and by the Pushgateway
+fn encode_metrics() -> String {
+    refresh_seconds_since_last_db_commit();
     let encoder = prometheus::TextEncoder::new();
 
     let mut buffer = Vec::new();
@@ -41,7 +302,7 @@ async fn get_metrics() -> impl Responder {
         tracing::error!(target: LOGGING_PREFIX, "could not encode metrics: {}", e);
     };
 
-    match String::from_utf8(buffer.clone()) {
+    match String::from_utf8(buffer) {
         Ok(v) => v,
         Err(e) => {
             tracing::error!(
@@ -54,15 +315,413 @@ async fn get_metrics() -> impl Responder {
     }
 }
 
-pub(crate) async fn init_metrics_server(port: u16) -> anyhow::Result<()> {
+#[get("/metrics")]
+async fn get_metrics() -> impl Responder {
+    encode_metrics()
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// The indexer is considered not ready if it lags behind the chain more than this value
+    pub max_block_lag_seconds: i64,
+    /// The indexer is considered not ready if it has not written anything to the DB during this period
+    pub max_seconds_since_db_commit: i64,
+    /// The indexer is considered dead if it has not processed any block during this period
+    pub liveness_window_seconds: i64,
+}
+
+#[get("/probe")]
+async fn health_check(config: web::Data<HealthConfig>) -> impl Responder {
+    let since_last_progress = now_seconds() - LAST_PROGRESS_TIMESTAMP.load(Ordering::Relaxed);
+    let body = format!(
+        "latest_block_height: {}\nseconds_since_last_processed_block: {}\n",
+        LATEST_BLOCK_HEIGHT.get(),
+        since_last_progress
+    );
+    if since_last_progress > config.liveness_window_seconds {
+        HttpResponse::ServiceUnavailable().body(body)
+    } else {
+        HttpResponse::Ok().body(body)
+    }
+}
+
+#[get("/height")]
+async fn latest_block_height() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain")
+        .body(LATEST_BLOCK_HEIGHT.get().to_string())
+}
+
+#[get("/ready")]
+async fn readiness_check(config: web::Data<HealthConfig>) -> impl Responder {
+    let lag = LATEST_BLOCK_TIMESTAMP_DIFF.get();
+    let since_last_db_commit = refresh_seconds_since_last_db_commit();
+    if lag > config.max_block_lag_seconds {
+        HttpResponse::ServiceUnavailable().body(format!(
+            "Indexer lags behind the chain for {lag} seconds, allowed {} seconds\n",
+            config.max_block_lag_seconds
+        ))
+    } else if since_last_db_commit > config.max_seconds_since_db_commit {
+        HttpResponse::ServiceUnavailable().body(format!(
+            "Indexer has not written to the database for {since_last_db_commit} seconds, allowed {} seconds\n",
+            config.max_seconds_since_db_commit
+        ))
+    } else {
+        HttpResponse::Ok().body(format!(
+            "Indexer lags behind the chain for {lag} seconds, last database write {since_last_db_commit} seconds ago\n"
+        ))
+    }
+}
+
+/// Fails if the indexer lags behind the chain more than `max_lag_seconds`
+/// during `window_seconds` in a row, so that the process exits and the orchestrator could restart it.
+/// Short spikes of the lag are ignored
+pub async fn lag_circuit_breaker(max_lag_seconds: i64, window_seconds: i64) -> anyhow::Result<()> {
+    let mut lagging_since: Option<i64> = None;
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+        check_lag(
+            &mut lagging_since,
+            LATEST_BLOCK_TIMESTAMP_DIFF.get(),
+            now_seconds(),
+            max_lag_seconds,
+            window_seconds,
+        )?;
+    }
+}
+
+// One tick of `lag_circuit_breaker`, `lagging_since` is the start of the current lag period
+fn check_lag(
+    lagging_since: &mut Option<i64>,
+    lag: i64,
+    now: i64,
+    max_lag_seconds: i64,
+    window_seconds: i64,
+) -> anyhow::Result<()> {
+    if lag <= max_lag_seconds {
+        *lagging_since = None;
+        return Ok(());
+    }
+    let since = *lagging_since.get_or_insert(now);
+    if now - since >= window_seconds {
+        anyhow::bail!(
+            "Indexer lags behind the chain for {} seconds during the last {} seconds",
+            lag,
+            now - since
+        );
+    }
+    Ok(())
+}
+
+/// Pushes all the metrics to the Pushgateway, replacing the previous values of the job
+pub async fn push_metrics(
+    client: &reqwest::Client,
+    pushgateway_url: &str,
+    job: &str,
+) -> anyhow::Result<()> {
+    let url = format!(
+        "{}/metrics/job/{}",
+        pushgateway_url.trim_end_matches('/'),
+        job
+    );
+    client
+        .put(url)
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            prometheus::TextEncoder::new().format_type(),
+        )
+        .body(encode_metrics())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Pushes the metrics every `interval_seconds`, for short-lived jobs which are finished before the scrape.
+/// The failed push is only logged, the indexing should not stop because of the Pushgateway
+pub async fn push_metrics_periodically(
+    pushgateway_url: String,
+    job: String,
+    interval_seconds: u64,
+) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_seconds));
+    loop {
+        interval.tick().await;
+        if let Err(err) = push_metrics(&client, &pushgateway_url, &job).await {
+            tracing::warn!(
+                target: LOGGING_PREFIX,
+                pushgateway_url = %pushgateway_url,
+                error = %err,
+                "Failed to push metrics to the Pushgateway",
+            );
+        }
+    }
+}
+
+pub async fn init_metrics_server(
+    host: std::net::IpAddr,
+    port: u16,
+    health_config: HealthConfig,
+) -> anyhow::Result<()> {
     tracing::info!(
         target: LOGGING_PREFIX,
-        "Starting metrics server on http://0.0.0.0:{port}/metrics"
+        "Starting metrics server on http://{host}:{port}/metrics"
     );
 
-    HttpServer::new(|| App::new().service(get_metrics))
-        .bind(("0.0.0.0", port))?
-        .run()
-        .await
-        .map_err(|e| anyhow::anyhow!("Error while executing HTTP Server: {}", e))
+    HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(health_config.clone()))
+            .service(get_metrics)
+            .service(health_check)
+            .service(readiness_check)
+            .service(latest_block_height)
+    })
+    .bind((host, port))?
+    .run()
+    .await
+    .map_err(|e| anyhow::anyhow!("Error while executing HTTP Server: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use actix_web::http::StatusCode;
+
+    const HEALTH_CONFIG: HealthConfig = HealthConfig {
+        max_block_lag_seconds: 60,
+        max_seconds_since_db_commit: 60,
+        liveness_window_seconds: 60,
+    };
+
+    // Serves one request with the same services as the metrics server
+    async fn get(uri: &str) -> (StatusCode, String) {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(HEALTH_CONFIG))
+                .service(get_metrics)
+                .service(health_check)
+                .service(readiness_check)
+                .service(latest_block_height),
+        )
+        .await;
+        let response = actix_web::test::call_service(
+            &app,
+            actix_web::test::TestRequest::get().uri(uri).to_request(),
+        )
+        .await;
+        let status = response.status();
+        let body = actix_web::test::read_body(response).await;
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn ready_follows_block_lag_and_db_writes() {
+        let _globals = test_utils::lock_globals().await;
+        update_last_db_commit_timestamp();
+        LATEST_BLOCK_TIMESTAMP_DIFF.set(5);
+        assert_eq!(get("/ready").await.0, StatusCode::OK);
+
+        LATEST_BLOCK_TIMESTAMP_DIFF.set(61);
+        let (status, body) = get("/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(
+            body.contains("lags behind the chain for 61 seconds"),
+            "{}",
+            body
+        );
+
+        LATEST_BLOCK_TIMESTAMP_DIFF.set(5);
+        LAST_DB_COMMIT_TIMESTAMP.store(now_seconds() - 61, Ordering::Relaxed);
+        let (status, body) = get("/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("has not written to the database"), "{}", body);
+        update_last_db_commit_timestamp();
+    }
+
+    #[actix_web::test]
+    async fn seconds_since_db_commit_grows_until_next_write() {
+        let _globals = test_utils::lock_globals().await;
+        LAST_DB_COMMIT_TIMESTAMP.store(now_seconds() - 30, Ordering::Relaxed);
+
+        let (_, metrics) = get("/metrics").await;
+        let seconds = metrics
+            .lines()
+            .find_map(|line| line.strip_prefix("indexer_events_seconds_since_last_db_commit "))
+            .unwrap();
+        assert!(
+            (30..=31).contains(&seconds.parse::<i64>().unwrap()),
+            "{}",
+            seconds
+        );
+
+        update_last_db_commit_timestamp();
+        assert!(refresh_seconds_since_last_db_commit() <= 1);
+    }
+
+    #[test]
+    fn lag_breaker_ignores_short_spikes() {
+        let mut lagging_since = None;
+        let ticks = |lagging_since: &mut Option<i64>, lags: &[(i64, i64)]| {
+            lags.iter()
+                .map(|(now, lag)| check_lag(lagging_since, *lag, *now, 60, 30).is_ok())
+                .collect::<Vec<_>>()
+        };
+
+        // The spike is over before the window ends
+        assert_eq!(
+            ticks(
+                &mut lagging_since,
+                &[(0, 100), (10, 100), (20, 100), (30, 5)]
+            ),
+            vec![true, true, true, true]
+        );
+        assert_eq!(lagging_since, None);
+        // The lag lasts for the whole window
+        assert_eq!(
+            ticks(
+                &mut lagging_since,
+                &[(40, 100), (50, 100), (60, 100), (70, 100)]
+            ),
+            vec![true, true, true, false]
+        );
+    }
+
+    #[actix_web::test]
+    async fn height_is_plain_text() {
+        let _globals = test_utils::lock_globals().await;
+        LATEST_BLOCK_HEIGHT.set(806_001);
+
+        assert_eq!(get("/height").await, (StatusCode::OK, "806001".to_string()));
+    }
+
+    #[actix_web::test]
+    async fn probe_fails_without_progress() {
+        let _globals = test_utils::lock_globals().await;
+        update_last_progress_timestamp();
+        assert_eq!(get("/probe").await.0, StatusCode::OK);
+
+        LAST_PROGRESS_TIMESTAMP.store(now_seconds() - 61, Ordering::Relaxed);
+        let (status, body) = get("/probe").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(
+            body.contains("seconds_since_last_processed_block: 6"),
+            "{}",
+            body
+        );
+        update_last_progress_timestamp();
+    }
+
+    #[test]
+    fn dump_includes_every_defined_metric() {
+        // The names of all the metrics defined in this file, the hand-written list should have each of them
+        let re = regex::Regex::new(r#""(indexer_events_[a-z0-9_]+)""#).unwrap();
+        let defined_names: std::collections::BTreeSet<&str> = re
+            .captures_iter(include_str!("metrics.rs"))
+            .map(|cap| cap.get(1).unwrap().as_str())
+            .collect();
+        let dumped_names: std::collections::BTreeSet<String> = metric_definitions()
+            .into_iter()
+            .map(|definition| definition.name)
+            .collect();
+
+        assert!(!defined_names.is_empty());
+        for name in defined_names {
+            assert!(
+                dumped_names.contains(name),
+                "{} is missing in `all_metrics`",
+                name
+            );
+        }
+    }
+
+    // Answers the single request with the given status line, returns the request line, the headers and the body
+    fn mock_pushgateway(
+        status_line: &'static str,
+    ) -> (
+        String,
+        std::thread::JoinHandle<(String, Vec<String>, String)>,
+    ) {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        // The trailing slash is not doubled in the push URL
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let request = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut headers = vec![];
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                headers.push(line.trim().to_lowercase());
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                stream,
+                "{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status_line
+            )
+            .unwrap();
+            (
+                request_line.trim().to_string(),
+                headers,
+                String::from_utf8(body).unwrap(),
+            )
+        });
+        (url, request)
+    }
+
+    #[tokio::test]
+    async fn pushed_metrics_are_in_text_format() {
+        let (url, request) = mock_pushgateway("HTTP/1.1 200 OK");
+        // The metrics are registered on the first use
+        BLOCK_PROCESSED_TOTAL.get();
+
+        push_metrics(&reqwest::Client::new(), &url, "backfill-859")
+            .await
+            .unwrap();
+
+        let (request_line, headers, body) = request.join().unwrap();
+        assert_eq!(request_line, "PUT /metrics/job/backfill-859 HTTP/1.1");
+        assert!(
+            headers.contains(&"content-type: text/plain; version=0.0.4".to_string()),
+            "{:?}",
+            headers
+        );
+        assert!(
+            body.contains("# TYPE indexer_events_total_blocks_processed counter\n"),
+            "{}",
+            body
+        );
+        // Every line is a comment or a sample `name{labels} value`
+        for line in body.lines().filter(|line| !line.starts_with('#')) {
+            let (_, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_push_is_an_error() {
+        let (url, request) = mock_pushgateway("HTTP/1.1 400 Bad Request");
+
+        assert!(push_metrics(&reqwest::Client::new(), &url, "backfill-859")
+            .await
+            .is_err());
+        request.join().unwrap();
+    }
 }
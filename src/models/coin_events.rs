@@ -3,8 +3,10 @@ use sqlx::Arguments;
 
 use crate::models::FieldCount;
 
-#[derive(Debug, sqlx::FromRow, FieldCount)]
+#[derive(Debug, Clone, serde::Serialize, sqlx::FromRow, FieldCount)]
 pub struct CoinEvent {
+    // Primary key of the table, see `db_adapters::compose_db_index`.
+    // It's deterministic, so reprocessing the same block produces the same keys and the duplicates are skipped
     pub event_index: BigDecimal,
     pub standard: String,
     pub receipt_id: String,
@@ -17,6 +19,8 @@ pub struct CoinEvent {
     pub cause: String,
     pub status: String,
     pub event_memo: Option<String>,
+    // Original log of the event, stored only with `--store-raw-events`
+    pub raw_event: Option<String>,
 }
 
 impl crate::models::SqlMethods for CoinEvent {
@@ -33,15 +37,62 @@ impl crate::models::SqlMethods for CoinEvent {
         args.add(&self.cause);
         args.add(&self.status);
         args.add(&self.event_memo);
+        args.add(&self.raw_event);
     }
 
     fn insert_query(items_count: usize) -> anyhow::Result<String> {
         Ok("INSERT INTO coin_events VALUES ".to_owned()
             + &crate::models::create_placeholders(items_count, CoinEvent::field_count())?
-            + " ON CONFLICT DO NOTHING")
+            + " "
+            + <CoinEvent as crate::models::CopyMethods>::on_conflict())
     }
 
     fn name() -> String {
         "coin_events".to_string()
     }
 }
+
+impl crate::models::CopyMethods for CoinEvent {
+    fn columns() -> &'static [&'static str] {
+        &[
+            "event_index",
+            "standard",
+            "receipt_id",
+            "block_height",
+            "block_timestamp",
+            "contract_account_id",
+            "affected_account_id",
+            "involved_account_id",
+            "delta_amount",
+            "cause",
+            "status",
+            "event_memo",
+            "raw_event",
+        ]
+    }
+
+    fn add_to_csv(&self, csv: &mut String) {
+        crate::models::push_csv_row(
+            csv,
+            &[
+                Some(&self.event_index.to_string()),
+                Some(&self.standard),
+                Some(&self.receipt_id),
+                Some(&self.block_height.to_string()),
+                Some(&self.block_timestamp.to_string()),
+                Some(&self.contract_account_id),
+                Some(&self.affected_account_id),
+                self.involved_account_id.as_deref(),
+                Some(&self.delta_amount.to_string()),
+                Some(&self.cause),
+                Some(&self.status),
+                self.event_memo.as_deref(),
+                self.raw_event.as_deref(),
+            ],
+        );
+    }
+
+    fn on_conflict() -> &'static str {
+        "ON CONFLICT (event_index) DO NOTHING"
+    }
+}
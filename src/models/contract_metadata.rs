@@ -0,0 +1,35 @@
+use bigdecimal::BigDecimal;
+use sqlx::Arguments;
+
+use crate::models::FieldCount;
+
+#[derive(Debug, sqlx::FromRow, FieldCount)]
+pub struct ContractMetadata {
+    pub contract_account_id: String,
+    pub spec: Option<String>,
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub decimals: Option<i32>,
+    pub fetched_at_block_height: BigDecimal,
+}
+
+impl crate::models::SqlMethods for ContractMetadata {
+    fn add_to_args(&self, args: &mut sqlx::postgres::PgArguments) {
+        args.add(&self.contract_account_id);
+        args.add(&self.spec);
+        args.add(&self.name);
+        args.add(&self.symbol);
+        args.add(self.decimals);
+        args.add(&self.fetched_at_block_height);
+    }
+
+    fn insert_query(items_count: usize) -> anyhow::Result<String> {
+        Ok("INSERT INTO contract_metadata VALUES ".to_owned()
+            + &crate::models::create_placeholders(items_count, ContractMetadata::field_count())?
+            + " ON CONFLICT (contract_account_id) DO NOTHING")
+    }
+
+    fn name() -> String {
+        "contract_metadata".to_string()
+    }
+}
@@ -11,6 +11,9 @@ pub struct Contract {
     pub first_event_at_block_height: BigDecimal,
     pub inconsistency_found_at_timestamp: Option<BigDecimal>,
     pub inconsistency_found_at_block_height: Option<BigDecimal>,
+    pub detection_method: Option<String>,
+    pub standard_changed_at_timestamp: Option<BigDecimal>,
+    pub standard_changed_at_block_height: Option<BigDecimal>,
 }
 
 impl crate::models::SqlMethods for Contract {
@@ -21,16 +24,23 @@ impl crate::models::SqlMethods for Contract {
         args.add(&self.first_event_at_block_height);
         args.add(&self.inconsistency_found_at_timestamp);
         args.add(&self.inconsistency_found_at_block_height);
+        args.add(&self.detection_method);
+        args.add(&self.standard_changed_at_timestamp);
+        args.add(&self.standard_changed_at_block_height);
     }
 
     fn insert_query(items_count: usize) -> anyhow::Result<String> {
         Ok("INSERT INTO contracts VALUES ".to_owned()
             + &crate::models::create_placeholders(items_count, Contract::field_count())?
             + " ON CONFLICT (contract_account_id) DO UPDATE SET "
+            + " standard = excluded.standard, "
             + " first_event_at_timestamp = least(contracts.first_event_at_timestamp, excluded.first_event_at_timestamp), "
             + " first_event_at_block_height = least(contracts.first_event_at_block_height, excluded.first_event_at_block_height), "
             + " inconsistency_found_at_timestamp = least(contracts.inconsistency_found_at_timestamp, excluded.inconsistency_found_at_timestamp), "
-            + " inconsistency_found_at_block_height = least(contracts.inconsistency_found_at_block_height, excluded.inconsistency_found_at_block_height)")
+            + " inconsistency_found_at_block_height = least(contracts.inconsistency_found_at_block_height, excluded.inconsistency_found_at_block_height), "
+            + " detection_method = coalesce(excluded.detection_method, contracts.detection_method), "
+            + " standard_changed_at_timestamp = least(contracts.standard_changed_at_timestamp, excluded.standard_changed_at_timestamp), "
+            + " standard_changed_at_block_height = least(contracts.standard_changed_at_block_height, excluded.standard_changed_at_block_height)")
     }
 
     fn name() -> String {
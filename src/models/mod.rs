@@ -1,11 +1,14 @@
+use crate::settings::IndexerSettings;
 use futures::future::try_join_all;
 use std::fmt::Write;
 
-pub use indexer_events::FieldCount;
+pub use indexer_events_derive::FieldCount;
 
-pub(crate) mod coin_events;
+pub mod coin_events;
+pub(crate) mod contract_metadata;
 pub(crate) mod contracts;
-pub(crate) mod nft_events;
+pub mod nft_events;
+pub(crate) mod processed_blocks;
 
 pub trait FieldCount {
     /// Get the number of fields on a struct.
@@ -18,6 +21,131 @@ pub trait SqlMethods {
     fn name() -> String;
 }
 
+/// Tables which could be filled with `COPY`, see [`bulk_insert`]
+pub trait CopyMethods: SqlMethods {
+    /// The columns of the table, named explicitly so `COPY` doesn't depend on the order of the columns in the DB
+    fn columns() -> &'static [&'static str];
+    /// Appends the item as one CSV line (see `push_csv_row`), the values go in the order of `columns`
+    fn add_to_csv(&self, csv: &mut String);
+    /// The same conflict handling as in `insert_query`
+    fn on_conflict() -> &'static str;
+}
+
+/// Inserts the items with `COPY` or with `INSERT` depending on the insert mode
+pub async fn bulk_insert<T: CopyMethods + std::fmt::Debug>(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    settings: &IndexerSettings,
+    items: &[T],
+) -> anyhow::Result<()> {
+    if settings.copy_insert_mode {
+        copy_insert(pool, items).await
+    } else {
+        chunked_insert(pool, items).await
+    }
+}
+
+// `COPY` can't skip the duplicates, so the rows go to the temporary table first
+// and then they are moved to the real table with the usual conflict handling
+async fn copy_insert<T: CopyMethods + std::fmt::Debug>(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    items: &[T],
+) -> anyhow::Result<()> {
+    if items.is_empty() {
+        return Ok(());
+    }
+    let table = T::name();
+    let mut csv = String::new();
+    for item in items {
+        item.add_to_csv(&mut csv);
+    }
+
+    let mut interval = crate::INTERVAL;
+    for _ in 0..crate::db_adapters::RETRY_COUNT {
+        let started_at = std::time::Instant::now();
+        match copy_via_staging_table::<T>(pool, &table, &csv).await {
+            Ok(()) => {
+                crate::metrics::update_last_db_commit_timestamp();
+                crate::metrics::DB_INSERT_ROWS
+                    .with_label_values(&[&table])
+                    .observe(items.len() as f64);
+                crate::metrics::DB_INSERT_DURATION_SECONDS
+                    .with_label_values(&[&table])
+                    .observe(started_at.elapsed().as_secs_f64());
+                return Ok(());
+            }
+            Err(err) => {
+                tracing::warn!(
+                    target: crate::LOGGING_PREFIX,
+                    "Error occurred during COPY to {}:\n{} rows were not stored. \n{} \n Retrying in {} milliseconds...",
+                    table,
+                    items.len(),
+                    err,
+                    interval.as_millis(),
+                );
+                tokio::time::sleep(interval).await;
+                if interval < crate::MAX_DELAY_TIME {
+                    interval *= 2;
+                }
+            }
+        }
+    }
+    Err(anyhow::anyhow!(
+        "Failed to perform COPY to database after {} attempts. Stop trying.",
+        crate::db_adapters::RETRY_COUNT
+    ))
+}
+
+async fn copy_via_staging_table<T: CopyMethods>(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    table: &str,
+    csv: &str,
+) -> anyhow::Result<()> {
+    let staging_table = format!("{}_staging", table);
+    let columns = T::columns().join(", ");
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!(
+        "CREATE TEMP TABLE {} (LIKE {} INCLUDING DEFAULTS) ON COMMIT DROP",
+        staging_table, table
+    ))
+    .execute(&mut tx)
+    .await?;
+    let mut copy = tx
+        .copy_in_raw(&format!(
+            "COPY {} ({}) FROM STDIN WITH (FORMAT csv)",
+            staging_table, columns
+        ))
+        .await?;
+    copy.send(csv.as_bytes()).await?;
+    copy.finish().await?;
+    sqlx::query(&format!(
+        "INSERT INTO {} ({}) SELECT {} FROM {} {}",
+        table,
+        columns,
+        columns,
+        staging_table,
+        T::on_conflict()
+    ))
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// In CSV, an unquoted empty value is NULL and a quoted one is an empty string
+pub(crate) fn push_csv_row(csv: &mut String, values: &[Option<&str>]) {
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            csv.push(',');
+        }
+        if let Some(value) = value {
+            csv.push('"');
+            csv.push_str(&value.replace('"', "\"\""));
+            csv.push('"');
+        }
+    }
+    csv.push('\n');
+}
+
 pub async fn chunked_insert<T: SqlMethods + std::fmt::Debug>(
     pool: &sqlx::Pool<sqlx::Postgres>,
     items: &[T],
@@ -36,6 +164,7 @@ async fn insert_retry_or_panic<T: SqlMethods + std::fmt::Debug>(
     let mut interval = crate::INTERVAL;
     let mut retry_attempt = 0usize;
     let query = T::insert_query(items.len())?;
+    let table = T::name();
 
     loop {
         if retry_attempt == retry_count {
@@ -46,13 +175,23 @@ async fn insert_retry_or_panic<T: SqlMethods + std::fmt::Debug>(
         }
         retry_attempt += 1;
 
+        let started_at = std::time::Instant::now();
         let mut args = sqlx::postgres::PgArguments::default();
         for item in items {
             item.add_to_args(&mut args);
         }
 
         match sqlx::query_with(&query, args).execute(pool).await {
-            Ok(_) => break,
+            Ok(_) => {
+                crate::metrics::update_last_db_commit_timestamp();
+                crate::metrics::DB_INSERT_ROWS
+                    .with_label_values(&[&table])
+                    .observe(items.len() as f64);
+                crate::metrics::DB_INSERT_DURATION_SECONDS
+                    .with_label_values(&[&table])
+                    .observe(started_at.elapsed().as_secs_f64());
+                break;
+            }
             Err(async_error) => {
                 tracing::warn!(
                     target: crate::LOGGING_PREFIX,
@@ -115,3 +254,70 @@ pub(crate) fn create_placeholder(
     item += ")";
     Ok(item)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::coin_events::CoinEvent;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn double_insert_keeps_one_row_per_event_index() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let events: Vec<CoinEvent> = (1..=3)
+            .map(|event_index| {
+                test_utils::coin_event(event_index, 774_001, "token.near", "alice.near", 100)
+            })
+            .collect();
+
+        chunked_insert(&pool, &events).await.unwrap();
+        chunked_insert(&pool, &events).await.unwrap();
+        assert_eq!(test_utils::count_rows(&pool, "coin_events").await, 3);
+
+        let copy_settings = IndexerSettings {
+            copy_insert_mode: true,
+            ..IndexerSettings::default()
+        };
+        bulk_insert(&pool, &copy_settings, &events).await.unwrap();
+        assert_eq!(test_utils::count_rows(&pool, "coin_events").await, 3);
+    }
+
+    // Run with `cargo test --release copy_insert_vs_chunked_insert -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn copy_insert_vs_chunked_insert() {
+        let _globals = test_utils::lock_globals().await;
+        let pool = match test_utils::test_pool().await {
+            Some(pool) => pool,
+            None => return,
+        };
+        let events: Vec<CoinEvent> = (1..=20_000)
+            .map(|event_index| {
+                test_utils::coin_event(event_index, 848_001, "token.near", "alice.near", 100)
+            })
+            .collect();
+
+        let started_at = std::time::Instant::now();
+        chunked_insert(&pool, &events).await.unwrap();
+        let chunked_insert_duration = started_at.elapsed();
+        sqlx::query("DELETE FROM coin_events")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let started_at = std::time::Instant::now();
+        copy_insert(&pool, &events).await.unwrap();
+        let copy_insert_duration = started_at.elapsed();
+
+        assert_eq!(test_utils::count_rows(&pool, "coin_events").await, 20_000);
+        println!(
+            "{} rows: chunked_insert {:?}, copy_insert {:?}",
+            events.len(),
+            chunked_insert_duration,
+            copy_insert_duration
+        );
+    }
+}
@@ -3,8 +3,10 @@ use sqlx::Arguments;
 
 use crate::models::FieldCount;
 
-#[derive(Debug, sqlx::FromRow, FieldCount)]
+#[derive(Debug, Clone, serde::Serialize, sqlx::FromRow, FieldCount)]
 pub struct NftEvent {
+    // Primary key of the table, see `db_adapters::compose_db_index`.
+    // It's deterministic, so reprocessing the same block produces the same keys and the duplicates are skipped
     pub event_index: BigDecimal,
     pub standard: String,
     pub receipt_id: String,
@@ -18,6 +20,10 @@ pub struct NftEvent {
     pub new_owner_account_id: Option<String>,
     pub authorized_account_id: Option<String>,
     pub event_memo: Option<String>,
+    // We store one row per token, but it's useful to know all the tokens mentioned in the same event
+    pub token_ids: Vec<String>,
+    // Original log of the event, stored only with `--store-raw-events`
+    pub raw_event: Option<String>,
 }
 
 impl crate::models::SqlMethods for NftEvent {
@@ -35,12 +41,14 @@ impl crate::models::SqlMethods for NftEvent {
         args.add(&self.new_owner_account_id);
         args.add(&self.authorized_account_id);
         args.add(&self.event_memo);
+        args.add(&self.token_ids);
+        args.add(&self.raw_event);
     }
 
     fn insert_query(items_count: usize) -> anyhow::Result<String> {
         Ok("INSERT INTO nft_events VALUES ".to_owned()
             + &crate::models::create_placeholders(items_count, NftEvent::field_count())?
-            + " ON CONFLICT DO NOTHING")
+            + " ON CONFLICT (event_index) DO NOTHING")
     }
 
     fn name() -> String {
@@ -0,0 +1,29 @@
+use bigdecimal::BigDecimal;
+use sqlx::Arguments;
+
+use crate::models::FieldCount;
+
+#[derive(Debug, sqlx::FromRow, FieldCount)]
+pub struct ProcessedBlock {
+    pub block_height: BigDecimal,
+    pub block_timestamp: BigDecimal,
+    pub block_checksum: Option<String>,
+}
+
+impl crate::models::SqlMethods for ProcessedBlock {
+    fn add_to_args(&self, args: &mut sqlx::postgres::PgArguments) {
+        args.add(&self.block_height);
+        args.add(&self.block_timestamp);
+        args.add(&self.block_checksum);
+    }
+
+    fn insert_query(items_count: usize) -> anyhow::Result<String> {
+        Ok("INSERT INTO processed_blocks VALUES ".to_owned()
+            + &crate::models::create_placeholders(items_count, ProcessedBlock::field_count())?
+            + " ON CONFLICT (block_height) DO NOTHING")
+    }
+
+    fn name() -> String {
+        "processed_blocks".to_string()
+    }
+}
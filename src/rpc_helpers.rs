@@ -0,0 +1,457 @@
+use crate::db_adapters::error::IndexerError;
+use crate::db_adapters::numeric_types;
+use bigdecimal::BigDecimal;
+use near_jsonrpc_client::errors::JsonRpcError;
+use near_jsonrpc_client::{methods, JsonRpcClient};
+use near_jsonrpc_primitives::types::blocks::RpcBlockError;
+use near_jsonrpc_primitives::types::query::{QueryResponseKind, RpcQueryError, RpcQueryResponse};
+use near_primitives::types::{AccountId, BlockId, BlockReference, FunctionArgs};
+use near_primitives::views::QueryRequest;
+use std::str::FromStr;
+
+/// The number of RPC calls left for the block being processed, see `IndexerSettings::max_rpc_per_block`
+pub(crate) struct RpcBudget {
+    block_height: u64,
+    calls_left: usize,
+    deferred_calls: usize,
+}
+
+impl RpcBudget {
+    pub(crate) fn for_block(block_height: u64, max_calls: Option<usize>) -> Self {
+        Self {
+            block_height,
+            calls_left: max_calls.unwrap_or(usize::MAX),
+            deferred_calls: 0,
+        }
+    }
+
+    /// Returns false if the budget is exhausted, the caller should defer the call
+    pub(crate) fn try_spend(&mut self) -> bool {
+        if self.calls_left == 0 {
+            self.deferred_calls += 1;
+            crate::metrics::RPC_BUDGET_EXCEEDED_TOTAL.inc();
+            return false;
+        }
+        self.calls_left -= 1;
+        true
+    }
+
+    // Should be called once the block is processed, so that there's one warning per block
+    pub(crate) fn warn_if_exceeded(&self) {
+        if self.deferred_calls > 0 {
+            tracing::warn!(
+                target: crate::LOGGING_PREFIX,
+                block_height = self.block_height,
+                deferred_calls = self.deferred_calls,
+                "Block exceeded the RPC budget, the rest of the calls are deferred",
+            );
+        }
+    }
+}
+
+/// Returns the result of `ft_balance_of` at the boundary right after the given block:
+/// all the receipts executed in `block_height` are applied, nothing from the next block is.
+/// It's the balance our deltas sum up to when `block_height` is included.
+///
+/// To get the balance before the block (e.g. to check the deltas of the block), pass `prev_height`
+/// from the block header. `block_height - 1` could be wrong because the chain skips some heights
+pub async fn balance_at_block_boundary(
+    rpc_client: &JsonRpcClient,
+    contract_id: &AccountId,
+    account_id: &AccountId,
+    block_height: u64,
+) -> anyhow::Result<BigDecimal> {
+    let args = serde_json::json!({ "account_id": account_id })
+        .to_string()
+        .into_bytes();
+    let response = call_function(rpc_client, contract_id, "ft_balance_of", args, block_height)
+        .await
+        .map_err(|err| to_indexer_error(err, block_height))?;
+
+    match response.kind {
+        QueryResponseKind::CallResult(result) => {
+            let balance = serde_json::from_slice::<numeric_types::U128>(&result.result)?;
+            Ok(BigDecimal::from_str(&balance.0.to_string())?)
+        }
+        _ => anyhow::bail!(
+            "Unexpected response for ft_balance_of {} at {}: {:?}",
+            contract_id,
+            block_height,
+            response.kind
+        ),
+    }
+}
+
+/// NEP-148 fungible token metadata, only the fields we store
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct FtMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+/// Returns the result of `ft_metadata` at the end of the given block.
+/// `None` means the contract does not implement the method or returns the unexpected data
+pub async fn get_ft_metadata(
+    rpc_client: &JsonRpcClient,
+    contract_id: &AccountId,
+    block_height: u64,
+) -> anyhow::Result<Option<FtMetadata>> {
+    let response = match call_function(
+        rpc_client,
+        contract_id,
+        "ft_metadata",
+        b"{}".to_vec(),
+        block_height,
+    )
+    .await
+    {
+        Ok(response) => response,
+        // The node executed the request, but the contract failed
+        Err(err)
+            if matches!(
+                err.handler_error(),
+                Some(RpcQueryError::ContractExecutionError { .. })
+                    | Some(RpcQueryError::NoContractCode { .. })
+            ) =>
+        {
+            tracing::warn!(
+                target: crate::LOGGING_PREFIX,
+                "Could not get ft_metadata of {}: {}",
+                contract_id,
+                err
+            );
+            return Ok(None);
+        }
+        Err(err) => return Err(to_indexer_error(err, block_height).into()),
+    };
+
+    if let QueryResponseKind::CallResult(result) = response.kind {
+        match serde_json::from_slice::<FtMetadata>(&result.result) {
+            Ok(metadata) => return Ok(Some(metadata)),
+            Err(err) => tracing::warn!(
+                target: crate::LOGGING_PREFIX,
+                "Unexpected ft_metadata of {}: {}",
+                contract_id,
+                err
+            ),
+        }
+    }
+    Ok(None)
+}
+
+// The view calls at the block see the state after the chunks of this block are applied,
+// so the block itself is the boundary right after it. All the state reads go through this reference
+fn block_reference_after(block_height: u64) -> BlockReference {
+    BlockReference::BlockId(BlockId::Height(block_height))
+}
+
+// The node may not have the fresh block yet, so we wait a bit for it
+const UNKNOWN_BLOCK_RETRY_COUNT: usize = 5;
+
+async fn call_function(
+    rpc_client: &JsonRpcClient,
+    contract_id: &AccountId,
+    method_name: &str,
+    args: Vec<u8>,
+    block_height: u64,
+) -> Result<RpcQueryResponse, JsonRpcError<RpcQueryError>> {
+    let mut interval = crate::INTERVAL;
+    let mut retry_attempt = 0usize;
+    loop {
+        let request = methods::query::RpcQueryRequest {
+            block_reference: block_reference_after(block_height),
+            request: QueryRequest::CallFunction {
+                account_id: contract_id.clone(),
+                method_name: method_name.to_string(),
+                args: FunctionArgs::from(args.clone()),
+            },
+        };
+        match rpc_client.call(request).await {
+            Err(err)
+                if matches!(
+                    err.handler_error(),
+                    Some(RpcQueryError::UnknownBlock { .. })
+                ) && retry_attempt < UNKNOWN_BLOCK_RETRY_COUNT =>
+            {
+                retry_attempt += 1;
+                tracing::warn!(
+                    target: crate::LOGGING_PREFIX,
+                    "Block {} is unknown to the RPC node, retrying in {} milliseconds...",
+                    block_height,
+                    interval.as_millis(),
+                );
+                tokio::time::sleep(interval).await;
+                interval *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+// The errors about the missing block should be clear, it's not a problem of the contract
+fn to_indexer_error(err: JsonRpcError<RpcQueryError>, block_height: u64) -> IndexerError {
+    match err.handler_error() {
+        Some(RpcQueryError::GarbageCollectedBlock { .. }) => IndexerError::Rpc(format!(
+            "Block {} is garbage collected on the RPC node, archival node is required",
+            block_height
+        )),
+        Some(RpcQueryError::UnknownBlock { .. }) => IndexerError::Rpc(format!(
+            "Block {} is still unknown to the RPC node after {} retries: it's either too new or the node lost it",
+            block_height, UNKNOWN_BLOCK_RETRY_COUNT
+        )),
+        _ => IndexerError::Rpc(err.to_string()),
+    }
+}
+
+/// Returns the height of the first block produced at or after the given time
+pub async fn find_block_height_by_timestamp<Tz: chrono::TimeZone>(
+    rpc_client: &JsonRpcClient,
+    timestamp: &chrono::DateTime<Tz>,
+) -> anyhow::Result<u64> {
+    let target_timestamp = u64::try_from(timestamp.timestamp_nanos())?;
+    let genesis_height = rpc_client
+        .call(methods::EXPERIMENTAL_genesis_config::RpcGenesisConfigRequest)
+        .await
+        .map_err(|err| IndexerError::Rpc(err.to_string()))?
+        .genesis_height;
+    let latest_block_height = rpc_client
+        .call(methods::status::RpcStatusRequest)
+        .await
+        .map_err(|err| IndexerError::Rpc(err.to_string()))?
+        .sync_info
+        .latest_block_height;
+
+    let best = find_first_block_at_or_after(
+        genesis_height,
+        latest_block_height,
+        target_timestamp,
+        |height| get_block_timestamp(rpc_client, height),
+    )
+    .await?;
+    best.ok_or_else(|| {
+        anyhow::anyhow!(
+            "There are no blocks at or after {:?} on the RPC node",
+            timestamp
+        )
+    })
+}
+
+// Binary search over the heights in `[low, high]`, keeping in mind some heights are skipped by the chain.
+// `block_timestamp` returns `None` for the skipped heights
+async fn find_first_block_at_or_after<F, Fut>(
+    mut low: u64,
+    mut high: u64,
+    target_timestamp: u64,
+    block_timestamp: F,
+) -> anyhow::Result<Option<u64>>
+where
+    F: Fn(u64) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<Option<u64>>>,
+{
+    let mut best: Option<u64> = None;
+    while low <= high {
+        let middle = low + (high - low) / 2;
+        match get_first_block_in_range(&block_timestamp, middle, high).await? {
+            Some((height, block_timestamp)) if block_timestamp >= target_timestamp => {
+                best = Some(height);
+                if middle == 0 {
+                    break;
+                }
+                high = middle - 1;
+            }
+            Some((height, _)) => low = height + 1,
+            None if middle == 0 => break,
+            None => high = middle - 1,
+        }
+    }
+    Ok(best)
+}
+
+// Returns the height and the timestamp of the first existing block in `[from, to]`
+async fn get_first_block_in_range<F, Fut>(
+    block_timestamp: &F,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<Option<(u64, u64)>>
+where
+    F: Fn(u64) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<Option<u64>>>,
+{
+    for height in from..=to {
+        if let Some(timestamp) = block_timestamp(height).await? {
+            return Ok(Some((height, timestamp)));
+        }
+    }
+    Ok(None)
+}
+
+async fn get_block_timestamp(
+    rpc_client: &JsonRpcClient,
+    height: u64,
+) -> anyhow::Result<Option<u64>> {
+    let request = methods::block::RpcBlockRequest {
+        block_reference: BlockReference::BlockId(BlockId::Height(height)),
+    };
+    match rpc_client.call(request).await {
+        Ok(block) => Ok(Some(block.header.timestamp)),
+        // The chain may skip the height
+        Err(err)
+            if matches!(
+                err.handler_error(),
+                Some(RpcBlockError::UnknownBlock { .. })
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(IndexerError::Rpc(err.to_string()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn handler_error(cause: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "code": -32000,
+            "message": "Server error",
+            "name": "HANDLER_ERROR",
+            "cause": cause,
+        })
+    }
+
+    #[tokio::test]
+    async fn too_new_block_is_retried() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let url = test_utils::mock_query_rpc({
+            let calls = calls.clone();
+            move |_, _, _, block_height| {
+                // The node gets the block after the second retry
+                if calls.fetch_add(1, Ordering::SeqCst) < 2 {
+                    return Err(handler_error(serde_json::json!({
+                        "name": "UNKNOWN_BLOCK",
+                        "info": { "block_reference": { "block_id": block_height } },
+                    })));
+                }
+                Ok(serde_json::json!("42"))
+            }
+        });
+
+        let balance = balance_at_block_boundary(
+            &JsonRpcClient::connect(url),
+            &"token.near".parse().unwrap(),
+            &"alice.near".parse().unwrap(),
+            824_001,
+        )
+        .await
+        .unwrap();
+        assert_eq!(balance, BigDecimal::from(42));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn garbage_collected_block_fails_clearly() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let url = test_utils::mock_query_rpc({
+            let calls = calls.clone();
+            move |_, _, _, block_height| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(handler_error(serde_json::json!({
+                    "name": "GARBAGE_COLLECTED_BLOCK",
+                    "info": {
+                        "block_height": block_height,
+                        "block_hash": near_primitives::hash::CryptoHash::default(),
+                    },
+                })))
+            }
+        });
+
+        let err = balance_at_block_boundary(
+            &JsonRpcClient::connect(url),
+            &"token.near".parse().unwrap(),
+            &"alice.near".parse().unwrap(),
+            824_002,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            IndexerError::Rpc(
+                "Block 824002 is garbage collected on the RPC node, archival node is required"
+                    .to_string()
+            )
+            .to_string()
+        );
+        // There's no point to wait for the block the node has already dropped
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn timestamp_is_resolved_to_the_first_block_after_it() {
+        // Height -> timestamp, the chain skipped 834_003 and 834_006
+        let blocks = HashMap::from([
+            (834_001, 100),
+            (834_002, 200),
+            (834_004, 400),
+            (834_005, 500),
+            (834_007, 700),
+        ]);
+        let resolve = |target_timestamp| {
+            let blocks = &blocks;
+            find_first_block_at_or_after(834_001, 834_007, target_timestamp, move |height| {
+                let timestamp = blocks.get(&height).copied();
+                async move { Ok(timestamp) }
+            })
+        };
+
+        assert_eq!(resolve(0).await.unwrap(), Some(834_001));
+        assert_eq!(resolve(100).await.unwrap(), Some(834_001));
+        assert_eq!(resolve(101).await.unwrap(), Some(834_002));
+        // Falls into the skipped heights
+        assert_eq!(resolve(300).await.unwrap(), Some(834_004));
+        assert_eq!(resolve(600).await.unwrap(), Some(834_007));
+        assert_eq!(resolve(700).await.unwrap(), Some(834_007));
+        assert_eq!(resolve(701).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn balance_is_read_at_the_given_block() {
+        let queried_heights = Arc::new(std::sync::Mutex::new(vec![]));
+        let url = test_utils::mock_view_rpc({
+            let queried_heights = queried_heights.clone();
+            move |_, method_name, _, block_height| {
+                assert_eq!(method_name, "ft_balance_of");
+                queried_heights.lock().unwrap().push(block_height);
+                serde_json::json!(block_height.to_string())
+            }
+        });
+        let rpc_client = JsonRpcClient::connect(url);
+        let mut block_header = test_utils::block_header(864_005);
+        // The chain skipped 864_004
+        block_header.prev_height = Some(864_003);
+
+        // After the block and before it
+        for block_height in [block_header.height, block_header.prev_height.unwrap()] {
+            let balance = balance_at_block_boundary(
+                &rpc_client,
+                &"token.near".parse().unwrap(),
+                &"alice.near".parse().unwrap(),
+                block_height,
+            )
+            .await
+            .unwrap();
+            assert_eq!(balance, BigDecimal::from(block_height));
+        }
+        assert_eq!(*queried_heights.lock().unwrap(), vec![864_005, 864_003]);
+        assert_eq!(
+            block_reference_after(864_005),
+            BlockReference::BlockId(BlockId::Height(864_005))
+        );
+    }
+}
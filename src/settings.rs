@@ -0,0 +1,100 @@
+use crate::db_adapters::coin::metadata::ContractMetadataCache;
+use crate::db_adapters::contracts_filter::ContractsFilter;
+use near_primitives::types::AccountId;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// How the blocks are indexed. Passed to [`crate::handle_streamer_message`] and to the subcommands,
+/// so several indexers in one process could run with different settings.
+/// `Default` gives the settings of the plain run: everything is indexed and stored to the DB
+#[derive(Clone)]
+pub struct IndexerSettings {
+    /// Without the DB the events only go to the sink
+    pub store_to_db: bool,
+    /// FT (NEP-141 and legacy) and NFT events could be switched off separately
+    pub index_ft: bool,
+    pub index_nft: bool,
+    /// Some contracts write `event_json:` instead of `EVENT_JSON:`, we accept it only if it's explicitly allowed
+    pub case_insensitive_event_prefix: bool,
+    /// Shards and standards are processed concurrently by default. Deterministic ordering processes them
+    /// one by one in a fixed order (FT before NFT, shards by id, NEP-141 before legacy), so the DB writes
+    /// and the sink output always go in the same order
+    pub deterministic_ordering: bool,
+    /// Raw event logs take a lot of space, so we store them only when it's explicitly asked
+    pub store_raw_events: bool,
+    /// By default, unparseable args of the successful receipt stop the indexer.
+    /// Tolerant mode skips such receipts so that one broken contract does not block everything
+    pub tolerant_parsing: bool,
+    /// Two identical logs in one receipt could be a bug of the contract or two equal transfers,
+    /// we can't distinguish them, so the duplicates are dropped only if it's explicitly asked
+    pub dedup_receipt_logs: bool,
+    /// Memo is an arbitrary string from the user, we cut it to keep the rows reasonably small
+    pub max_memo_length: usize,
+    /// Custom `standard` values of the events which should be parsed as one of the supported standards
+    pub standard_aliases: HashMap<String, String>,
+    /// NEP-141 event versions to index, empty list means any version
+    pub supported_nep141_versions: Vec<String>,
+    /// The checksums cost the serialization of all the events, so they are computed only if it's explicitly asked
+    pub store_block_checksums: bool,
+    /// Sending the tokens to the contract itself is often an effective burn or a deposit.
+    /// Such transfers get `TRANSFER_TO_CONTRACT` cause only if it's explicitly asked, the default keeps them as `TRANSFER`
+    pub classify_transfers_to_contract: bool,
+    /// The snapshots are written only if it's explicitly asked
+    pub store_supply_snapshots: bool,
+    pub store_balance_snapshots: bool,
+    /// `COPY` is much faster for the backfills, but it needs a staging table, so it's used only if it's explicitly asked
+    pub copy_insert_mode: bool,
+    /// Legacy contracts whose function call args are decoded with Borsh instead of JSON
+    pub borsh_args_contracts: HashSet<AccountId>,
+    /// Protects the archival node from the blocks touching too many contracts, unlimited if not set
+    pub max_rpc_per_block: Option<usize>,
+    /// Progress is logged every N blocks
+    pub log_every_n_blocks: u64,
+    /// Lake should never give us a block without shards, it means the data is broken
+    pub fail_on_empty_shards: bool,
+    /// Measuring each block costs a bit, so it's done only if it's explicitly asked
+    pub verbose_block_timing: bool,
+    /// `ft_metadata` of the new contracts is fetched only if the cache is set
+    pub contract_metadata_cache: Option<Arc<ContractMetadataCache>>,
+}
+
+impl IndexerSettings {
+    /// True if the run stores all the events of the block. Only such runs mark the block as processed
+    /// and skip the blocks processed before, the partial runs (`--only-contract`, `--standards`,
+    /// `--nep141-versions`) go through them again to add what was left
+    pub fn indexes_everything(&self, contracts_filter: &ContractsFilter) -> bool {
+        contracts_filter.allows_everything()
+            && self.index_ft
+            && self.index_nft
+            && self.supported_nep141_versions.is_empty()
+    }
+}
+
+impl Default for IndexerSettings {
+    fn default() -> Self {
+        Self {
+            store_to_db: true,
+            index_ft: true,
+            index_nft: true,
+            case_insensitive_event_prefix: false,
+            deterministic_ordering: false,
+            store_raw_events: false,
+            tolerant_parsing: false,
+            dedup_receipt_logs: false,
+            max_memo_length: 1000,
+            standard_aliases: HashMap::new(),
+            supported_nep141_versions: vec![],
+            store_block_checksums: false,
+            classify_transfers_to_contract: false,
+            store_supply_snapshots: false,
+            store_balance_snapshots: false,
+            copy_insert_mode: false,
+            borsh_args_contracts: HashSet::new(),
+            max_rpc_per_block: None,
+            log_every_n_blocks: 100,
+            fail_on_empty_shards: false,
+            verbose_block_timing: false,
+            contract_metadata_cache: None,
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use futures::future::BoxFuture;
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::models::coin_events::CoinEvent;
+use crate::models::nft_events::NftEvent;
+
+/// Receives the events right after they are successfully stored to the DB (if the DB is used).
+/// The events already stored by the previous attempt of the same block are not emitted again.
+/// Could be used to stream the events to the message queue; the DB is still the source of truth
+pub trait EventSink: Send + Sync {
+    fn emit_coin_events<'a>(
+        &'a self,
+        _events: &'a [CoinEvent],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn emit_nft_events<'a>(&'a self, _events: &'a [NftEvent]) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Default sink, does nothing
+pub struct NoopSink;
+
+impl EventSink for NoopSink {}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum JsonlEvent<'a> {
+    Coin(&'a CoinEvent),
+    Nft(&'a NftEvent),
+}
+
+/// Appends the events to the file, one JSON per line. Useful for the local analysis without the DB
+pub struct JsonlFileSink {
+    file: Mutex<std::io::BufWriter<std::fs::File>>,
+}
+
+impl JsonlFileSink {
+    pub fn open(path: &std::path::Path) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            file: Mutex::new(std::io::BufWriter::new(file)),
+        })
+    }
+
+    // The whole batch is flushed at once, so the file never ends in the middle of the block
+    fn write_events(&self, events: &[JsonlEvent]) -> anyhow::Result<()> {
+        let mut file = self.file.lock().expect("JSONL file lock is poisoned");
+        for event in events {
+            serde_json::to_writer(&mut *file, event)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl EventSink for JsonlFileSink {
+    fn emit_coin_events<'a>(
+        &'a self,
+        events: &'a [CoinEvent],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let events: Vec<JsonlEvent> = events.iter().map(JsonlEvent::Coin).collect();
+            self.write_events(&events)
+        })
+    }
+
+    fn emit_nft_events<'a>(&'a self, events: &'a [NftEvent]) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let events: Vec<JsonlEvent> = events.iter().map(JsonlEvent::Nft).collect();
+            self.write_events(&events)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_adapters::contracts_filter::ContractsFilter;
+    use crate::settings::IndexerSettings;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn block_is_written_as_jsonl() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let sink = JsonlFileSink::open(&path).unwrap();
+        let block = test_utils::streamer_message(
+            855_001,
+            vec![
+                test_utils::logs_outcome(
+                    "ft",
+                    "token.near",
+                    &[&test_utils::ft_mint_log("alice.near", "100")],
+                ),
+                test_utils::logs_outcome(
+                    "nft",
+                    "nft.near",
+                    &[&test_utils::nft_mint_log("bob.near", &["rabbit"])],
+                ),
+            ],
+        );
+
+        crate::db_adapters::events::store_events(
+            &test_utils::offline_pool(),
+            &IndexerSettings {
+                store_to_db: false,
+                ..IndexerSettings::default()
+            },
+            &block,
+            "mainnet",
+            &ContractsFilter::default(),
+            &sink,
+        )
+        .await
+        .unwrap();
+
+        // FT and NFT are stored concurrently, so their order in the file is not fixed
+        let mut lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        lines.sort_by_key(|line| line["kind"].to_string());
+        assert_eq!(
+            lines,
+            vec![
+                serde_json::json!({
+                    "kind": "coin",
+                    "event_index": "16008550010000000000000000001000000",
+                    "standard": "FT_NEP141",
+                    "receipt_id": block.shards[0].receipt_execution_outcomes[0].receipt.receipt_id.to_string(),
+                    "block_height": "855001",
+                    "block_timestamp": "1600855001000000000",
+                    "contract_account_id": "token.near",
+                    "affected_account_id": "alice.near",
+                    "involved_account_id": null,
+                    "delta_amount": "100",
+                    "cause": "MINT",
+                    "status": "SUCCESS",
+                    "event_memo": null,
+                    "raw_event": null,
+                }),
+                serde_json::json!({
+                    "kind": "nft",
+                    "event_index": "16008550010000000000000000002000000",
+                    "standard": "NFT_NEP171",
+                    "receipt_id": block.shards[0].receipt_execution_outcomes[1].receipt.receipt_id.to_string(),
+                    "block_height": "855001",
+                    "block_timestamp": "1600855001000000000",
+                    "contract_account_id": "nft.near",
+                    "token_id": "rabbit",
+                    "cause": "MINT",
+                    "status": "SUCCESS",
+                    "old_owner_account_id": null,
+                    "new_owner_account_id": "bob.near",
+                    "authorized_account_id": null,
+                    "event_memo": null,
+                    "token_ids": ["rabbit"],
+                    "raw_event": null,
+                }),
+            ]
+        );
+    }
+}
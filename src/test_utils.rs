@@ -0,0 +1,429 @@
+//! Fixtures shared by the unit tests.
+//!
+//! The tests touching Postgres run only if `TEST_DATABASE_URL` is set, otherwise they are skipped.
+//! Each of them gets its own schema with all the migrations applied, so they could run in parallel.
+//! The recently processed heights are cached globally, so each test uses its own block heights
+use near_lake_framework::near_indexer_primitives;
+use near_lake_framework::near_indexer_primitives::views::{
+    ActionView, BlockHeaderView, BlockView, ExecutionOutcomeView, ExecutionOutcomeWithIdView,
+    ExecutionStatusView, ReceiptEnumView, ReceiptView,
+};
+use near_lake_framework::near_indexer_primitives::CryptoHash;
+use sqlx::Executor;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+lazy_static! {
+    // The settings and the metrics are global, the tests depending on them go one by one
+    static ref GLOBALS: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+pub(crate) async fn lock_globals() -> tokio::sync::MutexGuard<'static, ()> {
+    GLOBALS.lock().await
+}
+
+static SCHEMA_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The pool working in a fresh schema with all the migrations, `None` if `TEST_DATABASE_URL` is not set
+pub(crate) async fn test_pool() -> Option<sqlx::Pool<sqlx::Postgres>> {
+    let database_url = match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("TEST_DATABASE_URL is not set, skipping the test");
+            return None;
+        }
+    };
+    let schema = format!(
+        "test_{}_{}",
+        std::process::id(),
+        SCHEMA_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let admin_pool = sqlx::PgPool::connect(&database_url)
+        .await
+        .expect("Failed to connect to TEST_DATABASE_URL");
+    admin_pool
+        .execute(format!("DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}").as_str())
+        .await
+        .expect("Failed to create the test schema");
+
+    let search_path = format!("SET search_path TO {schema}");
+    let pool = sqlx::postgres::PgPoolOptions::new()
+        .max_connections(4)
+        .after_connect(move |conn| {
+            let search_path = search_path.clone();
+            Box::pin(async move {
+                conn.execute(search_path.as_str()).await?;
+                Ok(())
+            })
+        })
+        .connect(&database_url)
+        .await
+        .expect("Failed to connect to TEST_DATABASE_URL");
+
+    // `CREATE INDEX CONCURRENTLY` can't go in a transaction, so the statements are sent one by one
+    let mut migrations: Vec<_> =
+        std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+            .expect("Failed to read migrations")
+            .map(|entry| entry.expect("Failed to read migration").path())
+            .collect();
+    migrations.sort();
+    for migration in migrations {
+        let sql = std::fs::read_to_string(&migration).expect("Failed to read migration");
+        let sql: String = sql
+            .lines()
+            .filter(|line| !line.trim_start().starts_with("--"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        for statement in sql.split(';').filter(|s| !s.trim().is_empty()) {
+            pool.execute(statement)
+                .await
+                .unwrap_or_else(|err| panic!("{}: {}", migration.display(), err));
+        }
+    }
+    Some(pool)
+}
+
+/// The pool which never connects, for the runs which don't store anything (`store_to_db: false`)
+pub(crate) fn offline_pool() -> sqlx::Pool<sqlx::Postgres> {
+    sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgres://postgres@127.0.0.1:1/offline")
+        .unwrap()
+}
+
+pub(crate) fn block_header(height: u64) -> BlockHeaderView {
+    BlockHeaderView {
+        height,
+        prev_height: height.checked_sub(1),
+        epoch_id: CryptoHash::default(),
+        next_epoch_id: CryptoHash::default(),
+        hash: CryptoHash::hash_bytes(&height.to_le_bytes()),
+        prev_hash: CryptoHash::default(),
+        prev_state_root: CryptoHash::default(),
+        chunk_receipts_root: CryptoHash::default(),
+        chunk_headers_root: CryptoHash::default(),
+        chunk_tx_root: CryptoHash::default(),
+        outcome_root: CryptoHash::default(),
+        chunks_included: 1,
+        challenges_root: CryptoHash::default(),
+        // The timestamps go one second per block
+        timestamp: 1_600_000_000_000_000_000 + height * 1_000_000_000,
+        timestamp_nanosec: 1_600_000_000_000_000_000 + height * 1_000_000_000,
+        random_value: CryptoHash::default(),
+        validator_proposals: vec![],
+        chunk_mask: vec![true],
+        gas_price: 0,
+        block_ordinal: Some(height),
+        rent_paid: 0,
+        validator_reward: 0,
+        total_supply: 0,
+        challenges_result: vec![],
+        last_final_block: CryptoHash::default(),
+        last_ds_final_block: CryptoHash::default(),
+        next_bp_hash: CryptoHash::default(),
+        block_merkle_root: CryptoHash::default(),
+        epoch_sync_data_hash: None,
+        approvals: vec![],
+        signature: Default::default(),
+        latest_protocol_version: 0,
+    }
+}
+
+/// The block with one shard containing the given outcomes
+pub(crate) fn streamer_message(
+    height: u64,
+    outcomes: Vec<near_indexer_primitives::IndexerExecutionOutcomeWithReceipt>,
+) -> near_indexer_primitives::StreamerMessage {
+    near_indexer_primitives::StreamerMessage {
+        block: BlockView {
+            author: "validator.near".parse().unwrap(),
+            header: block_header(height),
+            chunks: vec![],
+        },
+        shards: vec![near_indexer_primitives::IndexerShard {
+            shard_id: 0,
+            chunk: None,
+            receipt_execution_outcomes: outcomes,
+            state_changes: vec![],
+        }],
+    }
+}
+
+/// Successful receipt calling `method_name` on `receiver_id`. Different `seed`s give different receipt ids
+pub(crate) fn function_call_outcome(
+    seed: &str,
+    predecessor_id: &str,
+    receiver_id: &str,
+    method_name: &str,
+    args: &[u8],
+    logs: &[&str],
+) -> near_indexer_primitives::IndexerExecutionOutcomeWithReceipt {
+    let receipt_id = CryptoHash::hash_bytes(seed.as_bytes());
+    near_indexer_primitives::IndexerExecutionOutcomeWithReceipt {
+        execution_outcome: ExecutionOutcomeWithIdView {
+            proof: vec![],
+            block_hash: CryptoHash::default(),
+            id: receipt_id,
+            outcome: ExecutionOutcomeView {
+                logs: logs.iter().map(|log| log.to_string()).collect(),
+                receipt_ids: vec![],
+                gas_burnt: 0,
+                tokens_burnt: 0,
+                executor_id: receiver_id.parse().unwrap(),
+                status: ExecutionStatusView::SuccessValue(String::new()),
+                metadata: Default::default(),
+            },
+        },
+        receipt: ReceiptView {
+            predecessor_id: predecessor_id.parse().unwrap(),
+            receiver_id: receiver_id.parse().unwrap(),
+            receipt_id,
+            receipt: ReceiptEnumView::Action {
+                signer_id: predecessor_id.parse().unwrap(),
+                signer_public_key: "ed25519:11111111111111111111111111111111".parse().unwrap(),
+                gas_price: 0,
+                output_data_receivers: vec![],
+                input_data_ids: vec![],
+                actions: vec![ActionView::FunctionCall {
+                    method_name: method_name.to_string(),
+                    args: base64::encode(args),
+                    gas: 0,
+                    deposit: 1,
+                }],
+            },
+        },
+    }
+}
+
+/// Successful receipt of `contract_id` writing the given logs
+pub(crate) fn logs_outcome(
+    seed: &str,
+    contract_id: &str,
+    logs: &[&str],
+) -> near_indexer_primitives::IndexerExecutionOutcomeWithReceipt {
+    function_call_outcome(seed, "alice.near", contract_id, "some_method", b"{}", logs)
+}
+
+/// Keeps all the emitted events in memory
+#[derive(Default)]
+pub(crate) struct VecSink {
+    pub coin_events: std::sync::Mutex<Vec<crate::models::coin_events::CoinEvent>>,
+    pub nft_events: std::sync::Mutex<Vec<crate::models::nft_events::NftEvent>>,
+}
+
+impl crate::sinks::EventSink for VecSink {
+    fn emit_coin_events<'a>(
+        &'a self,
+        events: &'a [crate::models::coin_events::CoinEvent],
+    ) -> futures::future::BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.coin_events.lock().unwrap().extend_from_slice(events);
+            Ok(())
+        })
+    }
+
+    fn emit_nft_events<'a>(
+        &'a self,
+        events: &'a [crate::models::nft_events::NftEvent],
+    ) -> futures::future::BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.nft_events.lock().unwrap().extend_from_slice(events);
+            Ok(())
+        })
+    }
+}
+
+/// Collects the text logs written while the returned guard is alive, on the current thread only
+pub(crate) fn capture_logs() -> (
+    std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    tracing::subscriber::DefaultGuard,
+) {
+    #[derive(Clone)]
+    struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let logs = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let writer = CapturedLogs(logs.clone());
+    let subscriber = tracing_subscriber::fmt::Subscriber::builder()
+        .with_writer(move || writer.clone())
+        .with_env_filter("indexer_events=info")
+        .with_ansi(false)
+        .finish();
+    (logs, tracing::subscriber::set_default(subscriber))
+}
+
+pub(crate) fn ft_mint_log(owner_id: &str, amount: &str) -> String {
+    format!(
+        r#"EVENT_JSON:{{"standard":"nep141","version":"1.0.0","event":"ft_mint","data":[{{"owner_id":"{owner_id}","amount":"{amount}"}}]}}"#
+    )
+}
+
+pub(crate) async fn count_rows(pool: &sqlx::Pool<sqlx::Postgres>, table: &str) -> i64 {
+    sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+pub(crate) fn nft_mint_log(owner_id: &str, token_ids: &[&str]) -> String {
+    format!(
+        r#"EVENT_JSON:{{"standard":"nep171","version":"1.0.0","event":"nft_mint","data":[{{"owner_id":"{owner_id}","token_ids":{}}}]}}"#,
+        serde_json::to_string(token_ids).unwrap()
+    )
+}
+
+/// Serves `ft_balance_of` over JSON RPC from the given map: (contract, account, block height) -> balance.
+/// The missing balances are zero. Returns the URL of the server
+pub(crate) fn mock_ft_balances_rpc(balances: HashMap<(&str, &str, u64), u128>) -> String {
+    let balances: HashMap<(String, String, u64), u128> = balances
+        .into_iter()
+        .map(|((contract_id, account_id, block_height), balance)| {
+            (
+                (
+                    contract_id.to_string(),
+                    account_id.to_string(),
+                    block_height,
+                ),
+                balance,
+            )
+        })
+        .collect();
+    mock_view_rpc(move |contract_id, _method_name, args, block_height| {
+        let balance = balances
+            .get(&(
+                contract_id.to_string(),
+                args["account_id"].as_str().unwrap().to_string(),
+                block_height,
+            ))
+            .copied()
+            .unwrap_or(0);
+        serde_json::json!(balance.to_string())
+    })
+}
+
+/// Serves the view calls over JSON RPC: the handler gets the contract, the method, JSON args and the block height
+/// and returns the JSON result of the call. Returns the URL of the server
+pub(crate) fn mock_view_rpc<F>(handler: F) -> String
+where
+    F: Fn(&str, &str, &serde_json::Value, u64) -> serde_json::Value + Send + 'static,
+{
+    mock_query_rpc(move |contract_id, method_name, args, block_height| {
+        Ok(handler(contract_id, method_name, args, block_height))
+    })
+}
+
+/// Same as `mock_view_rpc`, but the handler could also return the JSON RPC error object of the node
+pub(crate) fn mock_query_rpc<F>(handler: F) -> String
+where
+    F: Fn(&str, &str, &serde_json::Value, u64) -> Result<serde_json::Value, serde_json::Value>
+        + Send
+        + 'static,
+{
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let params = &request["params"];
+            let args: serde_json::Value = serde_json::from_slice(
+                &base64::decode(params["args_base64"].as_str().unwrap()).unwrap(),
+            )
+            .unwrap_or(serde_json::Value::Null);
+            let block_height = params["block_id"].as_u64().unwrap();
+            let result = handler(
+                params["account_id"].as_str().unwrap(),
+                params["method_name"].as_str().unwrap(),
+                &args,
+                block_height,
+            );
+            let response = match result {
+                Ok(result) => serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "result": {
+                        "result": serde_json::to_vec(&result).unwrap(),
+                        "logs": [],
+                        "block_height": block_height,
+                        "block_hash": CryptoHash::default(),
+                    },
+                }),
+                Err(error) => serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "error": error,
+                }),
+            }
+            .to_string();
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.len(),
+                response
+            );
+        }
+    });
+    url
+}
+
+pub(crate) fn coin_event(
+    event_index: u64,
+    block_height: u64,
+    contract_id: &str,
+    account_id: &str,
+    delta: i64,
+) -> crate::models::coin_events::CoinEvent {
+    crate::models::coin_events::CoinEvent {
+        event_index: bigdecimal::BigDecimal::from(event_index),
+        standard: "FT_NEP141".to_string(),
+        receipt_id: format!("receipt {}", event_index),
+        block_height: bigdecimal::BigDecimal::from(block_height),
+        block_timestamp: bigdecimal::BigDecimal::from(block_header(block_height).timestamp),
+        contract_account_id: contract_id.to_string(),
+        affected_account_id: account_id.to_string(),
+        involved_account_id: None,
+        delta_amount: bigdecimal::BigDecimal::from(delta),
+        cause: if delta > 0 { "MINT" } else { "BURN" }.to_string(),
+        status: "SUCCESS".to_string(),
+        event_memo: None,
+        raw_event: None,
+    }
+}
+
+/// The block recorded to `fixtures/blocks/<name>.json`, see README on how to capture new ones
+pub(crate) fn block_fixture(name: &str) -> near_indexer_primitives::StreamerMessage {
+    let path = format!("{}/fixtures/blocks/{name}.json", env!("CARGO_MANIFEST_DIR"));
+    let json = std::fs::read_to_string(&path).unwrap_or_else(|err| panic!("{path}: {err}"));
+    serde_json::from_str(&json).unwrap_or_else(|err| panic!("{path}: {err}"))
+}